@@ -1,9 +1,13 @@
 use std::{
     cmp::Ordering,
-    collections::{BinaryHeap, HashMap},
+    collections::{BinaryHeap, HashMap, HashSet},
 };
 
-use crate::osm_parser::OpenStreetMap;
+use crate::{
+    graph::{Direction, Graph},
+    osm_parser::{OpenStreetMap, METRES_PER_MILE},
+    params::{Params, SimpleParams},
+};
 
 pub struct HeapNode {
     pub id: u32,
@@ -12,13 +16,13 @@ pub struct HeapNode {
 
 impl Ord for HeapNode {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        other.f_score.partial_cmp(&self.f_score).unwrap()
     }
 }
 
 impl PartialOrd for HeapNode {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        other.f_score.partial_cmp(&self.f_score)
+        Some(self.cmp(other))
     }
 }
 
@@ -30,7 +34,7 @@ impl PartialEq for HeapNode {
 
 impl Eq for HeapNode {}
 
-fn construct_path<'a>(init: u32, map: &HashMap<u32, u32>, osm: &'a OpenStreetMap) -> Path<'a> {
+fn construct_path<'a, G: Graph>(init: u32, map: &HashMap<u32, u32>, osm: &'a G) -> Path<'a, G> {
     let mut ids = Vec::new();
     let mut on = &init;
     ids.push(*on);
@@ -45,95 +49,167 @@ fn construct_path<'a>(init: u32, map: &HashMap<u32, u32>, osm: &'a OpenStreetMap
     }
 }
 
-pub struct Path<'a> {
+pub struct Path<'a, G: Graph = OpenStreetMap> {
     pub ids: Vec<u32>,
-    pub parent_map: &'a OpenStreetMap,
+    pub parent_map: &'a G,
 }
 
-impl<'a> Path<'a> {
-    pub fn length_miles(&self) -> f64 {
+impl<'a, G: Graph> Path<'a, G> {
+    /// the length along the ground
+    pub fn length_metres(&self) -> f64 {
         let map = self.parent_map;
-        let locations = self.ids.iter().map(|&id| map.get(id).location);
-        let mut prev_loc = None;
-        let mut total = 0.0;
-        for loc in locations {
-            let dx = match prev_loc {
-                Some(prev) => loc.dist2(prev).sqrt() as f64,
-                None => 0.0,
-            };
-            prev_loc = Some(loc);
-            total += dx;
-        }
-        total * 68.703
+        self.ids
+            .windows(2)
+            .map(|pair| map.location(pair[0]).dist_metres(map.location(pair[1])))
+            .sum()
+    }
+
+    pub fn length_miles(&self) -> f64 {
+        self.length_metres() / METRES_PER_MILE
+    }
+
+    /// the OSM ids of the nodes along the path
+    #[allow(dead_code)]
+    pub fn osm_ids(&self) -> Vec<i64> {
+        self.ids
+            .iter()
+            .map(|&id| self.parent_map.osm_id(id))
+            .collect()
     }
 }
 
+/// The shortest path in metres, with `SimpleParams`.
 #[allow(dead_code)]
-pub fn path(map: &OpenStreetMap, init_node: u32, goal_node: u32) -> Option<Path> {
-    // also is an explored
-    let mut g_scores = HashMap::new();
-    let mut queue = BinaryHeap::new();
+pub fn path<G: Graph>(map: &G, init_node: u32, goal_node: u32) -> Option<Path<'_, G>> {
+    path_with(map, init_node, goal_node, &SimpleParams)
+}
 
-    let mut track = HashMap::new();
+/// The cheapest path with the costs and heuristic of `params`.
+pub fn path_with<'a, G: Graph>(
+    map: &'a G,
+    init_node: u32,
+    goal_node: u32,
+    params: &impl Params<G::Node>,
+) -> Option<Path<'a, G>> {
+    path_between(map, &[(init_node, 0.0)], &[(goal_node, 0.0)], params).map(|(path, _)| path)
+}
 
-    // init
-    g_scores.insert(init_node, 0f64);
+/// A* between sets of nodes. A route may start at any of `sources`, having
+/// already cost the amount given with it, and end at any of `targets`, still
+/// costing the amount given with it. This is how a route starts or ends part
+/// way along an edge. Returns the cheapest path and its total cost.
+///
+/// A route arriving at a target over a turn restriction ends at a copy of
+/// it, so the copies of a target with nothing left to pay are targets too. A
+/// target part way along an edge is only reached through the nodes with that
+/// edge, which have to be listed.
+pub fn path_between<'a, G: Graph>(
+    map: &'a G,
+    sources: &[(u32, f64)],
+    targets: &[(u32, f64)],
+    params: &impl Params<G::Node>,
+) -> Option<(Path<'a, G>, f64)> {
+    let targets: Vec<(u32, f64)> = targets
+        .iter()
+        .flat_map(|&(target, cost)| {
+            let copies = map.copies(target).filter(move |_| cost == 0.0);
+            std::iter::once((target, cost)).chain(copies.map(move |id| (id, cost)))
+        })
+        .collect();
+    let remaining: HashMap<u32, f64> = targets.iter().cloned().collect();
+
+    // the cheapest way to any target is never cheaper than this
+    let h_score = |id: u32| {
+        targets
+            .iter()
+            .map(|&(target, cost)| {
+                params.heuristic_between(id, map.get(id), target, map.get(target)) + cost
+            })
+            .fold(f64::MAX, f64::min)
+    };
 
-    let goal_loc = map.get(goal_node).location;
+    let mut g_scores = HashMap::new();
+    let mut track = HashMap::new();
+    let mut closed = HashSet::new();
+    let mut queue = BinaryHeap::new();
+
+    for &(id, cost) in sources {
+        if g_scores.get(&id).is_none_or(|&prev| cost < prev) {
+            g_scores.insert(id, cost);
+            queue.push(HeapNode {
+                id,
+                f_score: cost + h_score(id),
+            });
+        }
+    }
 
-    queue.push(HeapNode {
-        id: init_node,
-        f_score: f64::MAX,
-    });
+    let mut best: Option<(u32, f64)> = None;
 
     while let Some(origin) = queue.pop() {
-        // let origin_node = origin.node;
-        if origin.id == goal_node {
-            return Some(construct_path(origin.id, &track, map));
+        if best.is_some_and(|(_, total)| origin.f_score >= total) {
+            break;
+        }
+        if !closed.insert(origin.id) {
+            continue;
         }
 
-        let origin_id = &origin.id;
-        let origin_g_score = g_scores[&origin_id];
-
-        let origin_loc = map.get(*origin_id).location;
-
-        map.next_to_id(origin.id).for_each(|neighbor| {
-            let neighbor_node = map.get(*neighbor);
-            let neighbor_loc = neighbor_node.location;
-            let tentative_g_score = origin_g_score + neighbor_loc.dist2(origin_loc);
-            match g_scores.get_mut(&neighbor) {
-                Some(prev_score) => {
-                    if tentative_g_score < *prev_score {
-                        *prev_score = tentative_g_score;
-                    } else {
-                        return;
-                    }
-                }
-                None => {
-                    g_scores.insert(*neighbor, tentative_g_score);
-                }
-            };
-
-            track.insert(*neighbor, *origin_id);
-
-            let h_score = goal_loc.dist2(neighbor_loc).sqrt();
-            let f_score = tentative_g_score + h_score;
+        let origin_g_score = g_scores[&origin.id];
 
+        if let Some(&cost) = remaining.get(&origin.id) {
+            let total = origin_g_score + cost;
+            if best.is_none_or(|(_, best_total)| total < best_total) {
+                best = Some((origin.id, total));
+            }
+        }
+
+        let origin_node = map.get(origin.id);
+        for edge in map.edges(origin.id, Direction::Forward) {
+            let neighbor = edge.node;
+            let neighbor_node = map.get(neighbor);
+            let tentative_g_score =
+                origin_g_score + params.neighbor_dist(origin_node, neighbor_node, edge);
+
+            if g_scores
+                .get(&neighbor)
+                .is_some_and(|&prev| tentative_g_score >= prev)
+            {
+                continue;
+            }
+
+            g_scores.insert(neighbor, tentative_g_score);
+            track.insert(neighbor, origin.id);
             queue.push(HeapNode {
-                id: *neighbor,
-                f_score,
-            })
-        })
+                id: neighbor,
+                f_score: tentative_g_score + h_score(neighbor),
+            });
+        }
     }
 
-    None
+    best.map(|(id, total)| (construct_path(id, &track, map), total))
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::BinaryHeap;
 
-    use crate::a_star::HeapNode;
+    use crate::{
+        a_star,
+        a_star::HeapNode,
+        osm_parser::{Location, OpenStreetMap, METRES_PER_MILE},
+    };
+
+    #[test]
+    fn shortest_in_metres() {
+        // 0 -> 1 -> 2 -> 3 along the equator, or round through 4, which is
+        // further east
+        let map = OpenStreetMap::from_edges(5, &[(0, 1), (1, 2), (2, 3), (0, 4), (4, 3)]);
+        let path = a_star::path(&map, 0, 3).unwrap();
+        assert_eq!(vec![0, 1, 2, 3], path.ids);
+
+        let expected = Location(0.0, 0.0).dist_metres(Location(3.0, 0.0));
+        assert!((path.length_metres() - expected).abs() < 1e-6);
+        assert!((path.length_miles() - expected / METRES_PER_MILE).abs() < 1e-9);
+    }
 
     #[test]
     fn queue_min() {
@@ -0,0 +1,403 @@
+use std::collections::HashSet;
+
+use crate::{
+    a_star,
+    a_star::Path,
+    dijkstra,
+    edge::Edge,
+    graph::{Direction, Graph},
+    params::Params,
+    profile::Profile,
+};
+
+/// A map with some of its nodes and edges taken out.
+struct Restricted<'a, G: Graph> {
+    map: &'a G,
+    nodes: HashSet<u32>,
+    edges: HashSet<(u32, u32)>,
+}
+
+impl<'a, G: Graph> Restricted<'a, G> {
+    fn allows(&self, from: u32, to: u32) -> bool {
+        !self.nodes.contains(&from)
+            && !self.nodes.contains(&to)
+            && !self.edges.contains(&(from, to))
+    }
+}
+
+impl<'a, G: Graph> Graph for Restricted<'a, G> {
+    type Node = G::Node;
+
+    fn node_count(&self) -> usize {
+        self.map.node_count()
+    }
+
+    fn get(&self, id: u32) -> &G::Node {
+        self.map.get(id)
+    }
+
+    fn edges(&self, id: u32, direction: Direction) -> impl Iterator<Item = &Edge> + '_ {
+        self.map
+            .edges(id, direction)
+            .filter(move |edge| match direction {
+                Direction::Forward => self.allows(id, edge.node),
+                Direction::Backward => self.allows(edge.node, id),
+            })
+    }
+
+    fn osm_id(&self, id: u32) -> i64 {
+        self.map.osm_id(id)
+    }
+
+    fn original(&self, id: u32) -> u32 {
+        self.map.original(id)
+    }
+
+    fn copies(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        self.map.copies(id)
+    }
+
+    fn profile(&self) -> Profile {
+        self.map.profile()
+    }
+}
+
+/// the cost of the cheapest edge from `from` to `to`
+fn edge_cost<G: Graph>(map: &G, from: u32, to: u32, params: &impl Params<G::Node>) -> f64 {
+    map.edges(from, Direction::Forward)
+        .filter(|edge| edge.node == to)
+        .map(|edge| params.neighbor_dist(map.get(from), map.get(to), edge))
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn cost<G: Graph>(map: &G, ids: &[u32], params: &impl Params<G::Node>) -> f64 {
+    ids.windows(2)
+        .map(|pair| edge_cost(map, pair[0], pair[1], params))
+        .sum()
+}
+
+/// The `k` cheapest paths from `init_node` to `goal_node` which do not visit
+/// a node twice, cheapest first, with their costs (Yen's algorithm).
+///
+/// Each path after the first leaves one before it at some node, the spur, and
+/// is the cheapest way on from there that leaves by an edge none of the paths
+/// so far with the same start take, without going back through the start.
+#[allow(dead_code)]
+pub fn k_shortest<'a, G: Graph>(
+    map: &'a G,
+    init_node: u32,
+    goal_node: u32,
+    k: usize,
+    params: &impl Params<G::Node>,
+) -> Vec<(Path<'a, G>, f64)> {
+    if k == 0 {
+        return Vec::new();
+    }
+
+    let mut found: Vec<(Vec<u32>, f64)> = Vec::new();
+    let mut candidates: Vec<(Vec<u32>, f64)> = Vec::new();
+
+    if let Some((path, cost)) =
+        a_star::path_between(map, &[(init_node, 0.0)], &[(goal_node, 0.0)], params)
+    {
+        found.push((path.ids, cost));
+    }
+
+    while !found.is_empty() && found.len() < k {
+        let last = found.last().unwrap().0.clone();
+
+        for i in 0..last.len() - 1 {
+            let (root, spur) = (&last[..=i], last[i]);
+
+            let mut restricted = Restricted {
+                map,
+                nodes: root[..i].iter().cloned().collect(),
+                edges: HashSet::new(),
+            };
+            for (ids, _) in &found {
+                if ids.len() > i + 1 && &ids[..=i] == root {
+                    restricted.edges.insert((ids[i], ids[i + 1]));
+                }
+            }
+
+            let Some((spur_path, spur_cost)) =
+                a_star::path_between(&restricted, &[(spur, 0.0)], &[(goal_node, 0.0)], params)
+            else {
+                continue;
+            };
+
+            let mut ids = root.to_vec();
+            ids.extend(&spur_path.ids[1..]);
+            if !candidates.iter().any(|(other, _)| *other == ids) {
+                let total = cost(map, root, params) + spur_cost;
+                candidates.push((ids, total));
+            }
+        }
+
+        let cheapest = candidates
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(i, _)| i);
+        match cheapest {
+            Some(i) => found.push(candidates.swap_remove(i)),
+            None => break,
+        }
+    }
+
+    found
+        .into_iter()
+        .map(|(ids, cost)| {
+            let path = Path {
+                ids,
+                parent_map: map,
+            };
+            (path, cost)
+        })
+        .collect()
+}
+
+/// What makes an alternative route worth showing.
+#[derive(Debug, Copy, Clone)]
+pub struct AlternativeLimits {
+    /// the most routes to return, the shortest one included
+    pub count: usize,
+    /// how much longer than the shortest route an alternative may be, e.g.
+    /// 1.25 for a quarter longer
+    pub max_stretch: f64,
+    /// the most of an alternative which may be shared with any route picked
+    /// before it, as a fraction of its cost
+    pub max_overlap: f64,
+}
+
+impl Default for AlternativeLimits {
+    fn default() -> Self {
+        AlternativeLimits {
+            count: 3,
+            max_stretch: 1.25,
+            max_overlap: 0.6,
+        }
+    }
+}
+
+/// The shortest route from `init_node` to `goal_node` followed by up to
+/// `limits.count - 1` alternatives, with their costs.
+///
+/// This is the plateau method, which takes two searches rather than one per
+/// route: a shortest path tree out of the start and one into the goal, each
+/// only as far as the longest route allowed. A
+/// stretch of road on a shortest path in both trees, a plateau, is part of a
+/// sensible route, the one that takes the shortest way to the plateau and the
+/// shortest way from it to the goal. The longest plateaus make the most
+/// natural alternatives.
+#[allow(dead_code)]
+pub fn alternatives<'a, G: Graph>(
+    map: &'a G,
+    init_node: u32,
+    goal_node: u32,
+    params: &impl Params<G::Node>,
+    limits: &AlternativeLimits,
+) -> Vec<(Path<'a, G>, f64)> {
+    let Some((_, shortest)) =
+        a_star::path_between(map, &[(init_node, 0.0)], &[(goal_node, 0.0)], params)
+    else {
+        return Vec::new();
+    };
+    // nothing further than this from either end can be on an alternative
+    let max_cost = shortest * limits.max_stretch.max(1.0);
+    let forward = dijkstra::tree(map, init_node, Direction::Forward, params, Some(max_cost));
+    let backward = dijkstra::tree(map, goal_node, Direction::Backward, params, Some(max_cost));
+    let shortest = forward.dist_to(goal_node).unwrap();
+
+    // an edge on a plateau is in both trees
+    let plateau_next = |from: u32| {
+        backward.prev[from as usize].filter(|&to| forward.prev[to as usize] == Some(from))
+    };
+    let plateau_prev = |to: u32| {
+        forward.prev[to as usize].filter(|&from| backward.prev[from as usize] == Some(to))
+    };
+
+    // each plateau is its first and last node, its length and the cost of the
+    // route through it
+    let mut plateaus = Vec::new();
+    for start in forward.reached_nodes() {
+        if plateau_prev(start).is_some() || plateau_next(start).is_none() {
+            continue;
+        }
+        let mut end = start;
+        while let Some(next) = plateau_next(end) {
+            end = next;
+        }
+        let cost = forward.dist[end as usize] + backward.dist[end as usize];
+        if cost <= max_cost {
+            let length = forward.dist[end as usize] - forward.dist[start as usize];
+            plateaus.push((start, end, length, cost));
+        }
+    }
+    plateaus.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+
+    let mut routes: Vec<(Vec<u32>, f64)> =
+        vec![(forward.path_to(goal_node).unwrap().ids, shortest)];
+    let mut used: HashSet<(u32, u32)> = HashSet::new();
+    used.extend(routes[0].0.windows(2).map(|pair| (pair[0], pair[1])));
+
+    for (start, end, _, cost) in plateaus {
+        if routes.len() >= limits.count {
+            break;
+        }
+
+        let mut ids = forward.path_to(start).unwrap().ids;
+        ids.pop();
+        ids.extend(
+            forward
+                .path_to(end)
+                .unwrap()
+                .ids
+                .into_iter()
+                .skip_while(|&id| id != start),
+        );
+        ids.extend(&backward.path_to(end).unwrap().ids[1..]);
+
+        let mut seen = HashSet::new();
+        if !ids.iter().all(|&id| seen.insert(id)) {
+            // the ways to and from the plateau cross
+            continue;
+        }
+        // the plateau on the shortest route leads to the shortest route
+        if ids == routes[0].0 {
+            continue;
+        }
+
+        let shared: f64 = ids
+            .windows(2)
+            .filter(|pair| used.contains(&(pair[0], pair[1])))
+            .map(|pair| edge_cost(map, pair[0], pair[1], params))
+            .sum();
+        if shared > limits.max_overlap * cost {
+            continue;
+        }
+
+        used.extend(ids.windows(2).map(|pair| (pair[0], pair[1])));
+        routes.push((ids, cost));
+    }
+
+    routes
+        .into_iter()
+        .map(|(ids, cost)| {
+            let path = Path {
+                ids,
+                parent_map: map,
+            };
+            (path, cost)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::{
+        a_star,
+        alternatives::{alternatives, cost, k_shortest, AlternativeLimits},
+        graph::Graph,
+        osm_parser::OpenStreetMap,
+        params::SimpleParams,
+    };
+
+    /// every path from `on` to `goal` which does not visit a node twice
+    fn all_paths(
+        map: &OpenStreetMap,
+        on: u32,
+        goal: u32,
+        path: &mut Vec<u32>,
+        into: &mut Vec<f64>,
+    ) {
+        path.push(on);
+        if on == goal {
+            into.push(cost(map, path, &SimpleParams));
+        } else {
+            let next: Vec<_> = map.next_to_id(on).collect();
+            for next in next {
+                if !path.contains(&next) {
+                    all_paths(map, next, goal, path, into);
+                }
+            }
+        }
+        path.pop();
+    }
+
+    fn valid(map: &OpenStreetMap, ids: &[u32], init: u32, goal: u32) -> bool {
+        let mut seen = HashSet::new();
+        ids.first() == Some(&init)
+            && ids.last() == Some(&goal)
+            && ids.iter().all(|&id| seen.insert(id))
+            && ids
+                .windows(2)
+                .all(|pair| map.next_to_id(pair[0]).any(|next| next == pair[1]))
+    }
+
+    #[test]
+    fn yen_matches_brute_force() {
+        let map = OpenStreetMap::random_roads(14, 19);
+
+        for (init, goal) in [(0, 13), (3, 9), (7, 2)] {
+            let mut expected = Vec::new();
+            all_paths(&map, init, goal, &mut Vec::new(), &mut expected);
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            let found = k_shortest(&map, init, goal, 6, &SimpleParams);
+            assert_eq!(expected.len().min(6), found.len());
+
+            let mut distinct = HashSet::new();
+            for ((path, cost), expected) in found.iter().zip(&expected) {
+                assert!((cost - expected).abs() < 1e-6);
+                assert!(valid(&map, &path.ids, init, goal));
+                assert!(distinct.insert(path.ids.clone()));
+            }
+        }
+
+        assert!(k_shortest(&map, 0, 13, 0, &SimpleParams).is_empty());
+    }
+
+    #[test]
+    fn plateau_alternatives() {
+        let map = OpenStreetMap::random_roads(120, 20);
+        let limits = AlternativeLimits {
+            count: 3,
+            max_stretch: 1.4,
+            max_overlap: 0.7,
+        };
+
+        let mut with_alternatives = 0;
+        for (init, goal) in [(0, 119), (10, 80), (33, 57), (5, 95)] {
+            let routes = alternatives(&map, init, goal, &SimpleParams, &limits);
+            let Some(shortest) = a_star::path(&map, init, goal) else {
+                assert!(routes.is_empty());
+                continue;
+            };
+
+            assert!((routes[0].1 - shortest.length_metres()).abs() < 0.01);
+            assert!(routes.len() <= 3);
+            if routes.len() > 1 {
+                with_alternatives += 1;
+            }
+
+            for (path, route_cost) in &routes {
+                assert!(valid(&map, &path.ids, init, goal));
+                assert!((cost(&map, &path.ids, &SimpleParams) - route_cost).abs() < 0.01);
+                assert!(*route_cost <= routes[0].1 * limits.max_stretch + 1e-6);
+            }
+        }
+        assert!(with_alternatives > 0);
+
+        // with any overlap allowed the shortest route is still only there once
+        let limits = AlternativeLimits {
+            max_overlap: 1.0,
+            ..limits
+        };
+        let routes = alternatives(&map, 0, 119, &SimpleParams, &limits);
+        let distinct: HashSet<_> = routes.iter().map(|(path, _)| path.ids.clone()).collect();
+        assert_eq!(routes.len(), distinct.len());
+    }
+}
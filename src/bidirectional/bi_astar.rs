@@ -1,127 +1,245 @@
 use std::{
-    collections::{BinaryHeap, HashMap},
+    collections::{BinaryHeap, HashMap, HashSet},
     sync::mpsc::Sender,
 };
 
 use crate::{
     a_star::{HeapNode, Path},
-    bidirectional::{middleman::Middleman, path_constructor::PathConstructor},
-    osm_parser,
-    osm_parser::{Node, OpenStreetMap},
+    bidirectional::{
+        middleman::{Label, Middleman, Progress},
+        path_constructor::PathConstructor,
+    },
+    graph::{Direction, Graph},
     params::Params,
 };
 
-pub fn a_star_bi<'a>(
-    map: &'a osm_parser::OpenStreetMap,
+/// Bidirectional A*: one search from `init_node` and one backwards from
+/// `goal_node`, each on its own thread.
+///
+/// Each side stops once the smallest key in its queue is at least μ, the
+/// cheapest path through a node both sides have reached. Any cheaper path
+/// would still have a node in that queue with a smaller key, as long as the
+/// heuristic is consistent, so the path found is a shortest one.
+///
+/// A route arriving at the goal over a turn restriction ends at a copy of it,
+/// so the backward half starts at the goal and all its copies.
+pub fn a_star_bi<'a, G: Graph>(
+    map: &'a G,
     init_node: u32,
     goal_node: u32,
-    params: &impl Params<Node>,
-) -> Option<Path<'a>> {
+    params: &impl Params<G::Node>,
+) -> Option<Path<'a, G>> {
     let middleman = Middleman::new();
-
-    let sender1 = middleman.node_sender.clone();
-    let sender2 = middleman.node_sender.clone();
+    let (sender1, sender2) = (middleman.sender(), middleman.sender());
+    let progress = middleman.progress();
+    let goals: Vec<u32> = map.with_copies(goal_node).collect();
 
     let mut forward = None;
     let mut backward = None;
 
     rayon::scope(|scope| {
         scope.spawn(|_| {
-            forward = Some(bi_path_helper(map, init_node, goal_node, sender1, params));
+            forward = Some(bi_path_helper(
+                map,
+                &[init_node],
+                &goals,
+                Direction::Forward,
+                sender1,
+                &progress,
+                params,
+            ));
         });
 
         scope.spawn(|_| {
-            backward = Some(bi_path_helper(map, goal_node, init_node, sender2, params));
+            backward = Some(bi_path_helper(
+                map,
+                &goals,
+                &[init_node],
+                Direction::Backward,
+                sender2,
+                &progress,
+                params,
+            ));
         });
     });
 
     let (forward, backward) = (forward.unwrap(), backward.unwrap());
 
-    if let Some(split) = middleman.get_split() {
-        let ids = PathConstructor::build_path(&forward, &backward, split);
+    let split = middleman.get_split()?;
+    let ids = PathConstructor::build_path(&forward, &backward, split);
 
-        return Some(Path {
-            ids,
-            parent_map: map,
-        });
-    }
-
-    None
+    Some(Path {
+        ids,
+        parent_map: map,
+    })
 }
 
-fn bi_path_helper(
-    map: &OpenStreetMap,
-    init_node_id: u32,
-    goal_node_id: u32,
-    node_sender: Sender<u32>,
-    params: &impl Params<Node>,
+/// One half of the bidirectional search, from any of `inits` towards any of
+/// `goals`. The backward half starts at the goal and walks edges in reverse,
+/// so the parents it tracks point along the real direction of travel.
+fn bi_path_helper<G: Graph>(
+    map: &G,
+    inits: &[u32],
+    goals: &[u32],
+    direction: Direction,
+    node_sender: Sender<Label>,
+    progress: &Progress,
+    params: &impl Params<G::Node>,
 ) -> HashMap<u32, u32> {
-    // also is an explored
     let mut g_scores = HashMap::new();
+    let mut closed = HashSet::new();
     let mut queue = BinaryHeap::new();
 
     let mut track = HashMap::new();
 
-    // init
-    g_scores.insert(init_node_id, 0f64);
-
-    let goal_node = map.get(goal_node_id);
-
-    queue.push(HeapNode {
-        id: init_node_id,
-        f_score: f64::MAX,
-    });
+    // a lower bound of what is left to travel: to the goal going forward, from
+    // the start (which this half calls its goal) going backward
+    let h_score = |id: u32, node: &G::Node| {
+        goals
+            .iter()
+            .map(|&goal_id| {
+                let goal = map.get(goal_id);
+                match direction {
+                    Direction::Forward => params.heuristic_between(id, node, goal_id, goal),
+                    Direction::Backward => params.heuristic_between(goal_id, goal, id, node),
+                }
+            })
+            .fold(f64::MAX, f64::min)
+    };
+
+    for &init_id in inits {
+        g_scores.insert(init_id, 0f64);
+        let _ = node_sender.send(Label {
+            direction,
+            id: init_id,
+            g_score: 0.0,
+        });
+        queue.push(HeapNode {
+            id: init_id,
+            f_score: h_score(init_id, map.get(init_id)),
+        });
+    }
 
     while let Some(origin) = queue.pop() {
-        // let origin_node = origin.node;
-        if origin.id == goal_node_id {
-            return track; // Some(construct_path(origin.id, &track, map));
+        // nothing left in the queue can lead to a path cheaper than μ
+        if origin.f_score >= progress.best() || progress.finished() {
+            break;
+        }
+        if !closed.insert(origin.id) {
+            continue;
         }
 
-        let origin_id = &origin.id;
+        let origin_id = origin.id;
         let origin_g_score = g_scores[&origin_id];
-
-        let origin_node = map.get(*origin_id);
-
-        for neighbor in map.next_to_id(origin.id) {
-            let neighbor_node = map.get(*neighbor);
-            let tentative_g_score =
-                origin_g_score + params.neighbor_dist(origin_node, neighbor_node);
-            match g_scores.get_mut(&neighbor) {
-                Some(prev_score) => {
-                    if tentative_g_score < *prev_score {
-                        *prev_score = tentative_g_score;
-                    } else {
-                        continue;
-                    }
-                }
-                None => {
-                    g_scores.insert(*neighbor, tentative_g_score);
-                }
+        let origin_node = map.get(origin_id);
+
+        for edge in map.edges(origin_id, direction) {
+            let neighbor = edge.node;
+            let neighbor_node = map.get(neighbor);
+            let edge_dist = match direction {
+                Direction::Forward => params.neighbor_dist(origin_node, neighbor_node, edge),
+                Direction::Backward => params.neighbor_dist(neighbor_node, origin_node, edge),
             };
+            let tentative_g_score = origin_g_score + edge_dist;
 
-            let h_score = params.heuristic(&neighbor_node, &goal_node);
-            let unique_add = track.insert(*neighbor, *origin_id).is_none();
-
-            if unique_add {
-                // if this is the first time we added to the map
-
-                let send_result = node_sender.send(*neighbor);
-
-                // this will be an error if the send channel has been closed (which means the
-                // middle man has found a collision), so we can stop
-                if send_result.is_err() {
-                    return track;
-                }
+            if g_scores
+                .get(&neighbor)
+                .is_some_and(|&prev| tentative_g_score >= prev)
+            {
+                continue;
             }
 
-            let f_score = tentative_g_score + h_score;
+            g_scores.insert(neighbor, tentative_g_score);
+            track.insert(neighbor, origin_id);
+
+            let _ = node_sender.send(Label {
+                direction,
+                id: neighbor,
+                g_score: tentative_g_score,
+            });
 
             queue.push(HeapNode {
-                id: *neighbor,
-                f_score,
+                id: neighbor,
+                f_score: tentative_g_score + h_score(neighbor, neighbor_node),
             })
         }
     }
+
+    // whichever side finishes first (an empty queue means every node it can
+    // reach has its final cost) is enough to stop the other
+    progress.finish();
     track
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{
+        a_star,
+        bidirectional::bi_astar::a_star_bi,
+        dijkstra,
+        edge::Edge,
+        graph::Direction,
+        osm_parser::{Node, OpenStreetMap},
+        params::{Params, SimpleParams},
+    };
+
+    /// plain Dijkstra
+    struct NoHeuristic;
+
+    impl Params<Node> for NoHeuristic {
+        fn heuristic(&self, _on: &Node, _goal: &Node) -> f64 {
+            0.0
+        }
+
+        fn neighbor_dist(&self, _on: &Node, _next: &Node, edge: &Edge) -> f64 {
+            edge.length as f64
+        }
+    }
+
+    #[test]
+    fn matches_dijkstra() {
+        let map = OpenStreetMap::random_roads(60, 13);
+        let rng = &mut StdRng::seed_from_u64(14);
+
+        for _ in 0..200 {
+            let init = rng.gen_range(0, map.node_count() as u32);
+            let goal = rng.gen_range(0, map.node_count() as u32);
+
+            let expected = a_star::path_between(&map, &[(init, 0.0)], &[(goal, 0.0)], &NoHeuristic);
+            let found = a_star_bi(&map, init, goal, &SimpleParams);
+            match (expected, found) {
+                (Some((_, cost)), Some(path)) => {
+                    assert_eq!(init, path.ids[0]);
+                    assert_eq!(goal, *path.ids.last().unwrap());
+                    assert!((path.length_metres() - cost).abs() < 0.01);
+                }
+                (None, None) => {}
+                (expected, found) => panic!(
+                    "{} to {}: expected {:?}, found {:?}",
+                    init,
+                    goal,
+                    expected.map(|(_, cost)| cost),
+                    found.map(|path| path.length_metres())
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn matches_dijkstra_tree() {
+        let map = OpenStreetMap::random_roads(60, 21);
+
+        for init in (0..60).step_by(11) {
+            let tree = dijkstra::tree(&map, init, Direction::Forward, &SimpleParams, None);
+            for goal in 0..60 {
+                let found = a_star_bi(&map, init, goal, &SimpleParams);
+                assert_eq!(tree.reached(goal), found.is_some());
+                if let Some(path) = found {
+                    assert!((path.length_metres() - tree.dist[goal as usize]).abs() < 0.01);
+                }
+            }
+        }
+    }
+}
@@ -1,46 +1,112 @@
 use std::{
-    collections::HashSet,
+    collections::HashMap,
     sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
         mpsc,
-        mpsc::{Receiver, SendError, Sender},
+        mpsc::{Receiver, Sender},
+        Arc,
     },
     thread,
 };
 
-use crate::bidirectional::SimpleNode;
+use crate::graph::Direction;
 
+/// A node reached by one of the searches, and the cost of getting there from
+/// where that search started.
+pub struct Label {
+    pub direction: Direction,
+    pub id: u32,
+    pub g_score: f64,
+}
+
+/// What the searches can see of each other while they run.
+#[derive(Clone)]
+pub struct Progress {
+    best: Arc<AtomicU64>,
+    done: Arc<AtomicBool>,
+}
+
+impl Progress {
+    /// μ as far as the middleman has got. It only ever goes down, so a search
+    /// that reads an old value just stops a little later.
+    pub fn best(&self) -> f64 {
+        f64::from_bits(self.best.load(Ordering::Relaxed))
+    }
+
+    /// Tells the other search to stop as well.
+    pub fn finish(&self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+
+    pub fn finished(&self) -> bool {
+        self.done.load(Ordering::Relaxed)
+    }
+}
+
+/// Collects the labels of both searches and keeps track of μ, the cheapest
+/// path found so far: the lowest sum of a forward and a backward label of the
+/// same node.
 pub struct Middleman {
-    pub node_sender: Sender<u32>,
-    pub vec_receiver: Receiver<u32>,
+    node_sender: Sender<Label>,
+    split_receiver: Receiver<Option<u32>>,
+    progress: Progress,
 }
 
 impl Middleman {
     pub fn new() -> Middleman {
-        let (send_node, receive_node) = mpsc::channel();
-        let (send_vec, receive_vec) = mpsc::channel();
+        let (send_node, receive_node) = mpsc::channel::<Label>();
+        let (send_split, receive_split) = mpsc::channel();
+        let best = Arc::new(AtomicU64::new(f64::INFINITY.to_bits()));
+        let shared_best = best.clone();
 
         thread::spawn(move || {
-            let mut traversed_set = HashSet::new();
+            let mut forward = HashMap::new();
+            let mut backward = HashMap::new();
+            let mut split = None;
+            let mut best = f64::INFINITY;
+
+            // runs until both searches have dropped their senders
+            for label in receive_node {
+                let (own, other) = match label.direction {
+                    Direction::Forward => (&mut forward, &backward),
+                    Direction::Backward => (&mut backward, &forward),
+                };
+                own.insert(label.id, label.g_score);
 
-            for elem in receive_node {
-                let was_empty = traversed_set.insert(elem);
-                if !was_empty {
-                    send_vec.send(elem);
-                    return;
+                if let Some(&other_g_score) = other.get(&label.id) {
+                    let cost = label.g_score + other_g_score;
+                    if cost < best {
+                        best = cost;
+                        split = Some(label.id);
+                        shared_best.store(best.to_bits(), Ordering::Relaxed);
+                    }
                 }
             }
+
+            let _ = send_split.send(split);
         });
 
         Middleman {
             node_sender: send_node,
-            vec_receiver: receive_vec,
+            split_receiver: receive_split,
+            progress: Progress {
+                best,
+                done: Arc::new(AtomicBool::new(false)),
+            },
         }
     }
 
-    pub fn get_split(&self) -> Option<u32> {
-        match self.vec_receiver.recv() {
-            Ok(split_point) => Some(split_point),
-            Err(_) => None,
-        }
+    pub fn sender(&self) -> Sender<Label> {
+        self.node_sender.clone()
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    /// The node the cheapest path goes through, once every sender is gone.
+    pub fn get_split(self) -> Option<u32> {
+        drop(self.node_sender);
+        self.split_receiver.recv().ok().flatten()
     }
 }
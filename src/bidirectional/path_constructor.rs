@@ -19,7 +19,10 @@ impl PathConstructor {
         let mut vec = Vec::new();
         path_trace(split, forward, &mut vec);
         vec.reverse();
-        path_trace(split, backward, &mut vec);
+        // the split is already in the first half
+        if let Some(&after) = backward.get(&split) {
+            path_trace(after, backward, &mut vec);
+        }
         vec
     }
 }
@@ -1,5 +1,6 @@
 use crate::{
     a_star::Path,
+    graph::Graph,
     osm_parser::{Location, OpenStreetMap},
 };
 
@@ -44,15 +45,15 @@ impl Boundable for OpenStreetMap {
     }
 }
 
-impl<'a> Boundable for Path<'a> {
+impl<'a, G: Graph> Boundable for Path<'a, G> {
     fn get_bounds(&self) -> Bounds {
         let mut minx = f64::MAX;
         let mut miny = f64::MAX;
         let mut maxx = f64::MIN;
         let mut maxy = f64::MIN;
 
-        for node in self.ids.iter().map(|&id| self.parent_map.get(id)) {
-            let Location(x, y) = node.location;
+        for location in self.ids.iter().map(|&id| self.parent_map.location(id)) {
+            let Location(x, y) = location;
             if x < minx {
                 minx = x;
             }
@@ -11,7 +11,7 @@ use std::{
 // 196 MB => 491MB = 2.5
 // => 429MB = 2.1 .. after 215 (with f32)
 // what to do... graph compression
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct CompactVec<T> {
     len: u8,
     // 1
@@ -53,7 +53,7 @@ impl<T> CompactVec<T> {
         self.len
     }
 
-    pub fn iterator(&self) -> CompactVecIterator<T> {
+    pub fn iterator(&self) -> CompactVecIterator<'_, T> {
         CompactVecIterator {
             compact_vec: self,
             idx: 0,
@@ -65,12 +65,6 @@ impl<T> CompactVec<T> {
         self.insert(self.len - 1, elem);
     }
 
-    pub fn push2(&mut self, elem1: T, elem2: T) {
-        self.add_len(2);
-        self.insert(self.len - 2, elem1);
-        self.insert(self.len - 1, elem2);
-    }
-
     pub fn empty() -> CompactVec<T> {
         CompactVec {
             len: 0,
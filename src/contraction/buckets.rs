@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use crate::{
+    contraction::hierarchy::ContractionHierarchy,
+    graph::{Direction, Graph},
+    matrix::CostMatrix,
+};
+
+impl ContractionHierarchy {
+    /// The cost from every source to every target, by bucket many to many.
+    ///
+    /// An upward search backwards from each target leaves its cost in a
+    /// bucket at every node it reaches. An upward search from each source then
+    /// only has to look in the buckets of the nodes it reaches, since every
+    /// shortest path has a top node both searches get to. That is one search
+    /// per source and target rather than one per pair. Both sets of searches
+    /// are spread over all cores.
+    ///
+    /// `map` is the map the hierarchy was built from, for the turn restriction
+    /// copies of the targets, where routes may end too.
+    #[allow(dead_code)]
+    pub fn matrix<G: Graph>(&self, map: &G, sources: &[u32], targets: &[u32]) -> CostMatrix {
+        let backward: Vec<_> = targets
+            .par_iter()
+            .map(|&target| self.upward(map.with_copies(target), Direction::Backward))
+            .collect();
+
+        let mut buckets: HashMap<u32, Vec<(usize, f64)>> = HashMap::new();
+        for (target, reached) in backward.into_iter().enumerate() {
+            for (node, cost) in reached {
+                buckets.entry(node).or_default().push((target, cost));
+            }
+        }
+
+        let rows = sources
+            .par_iter()
+            .map(|&source| {
+                let mut row = vec![f64::INFINITY; targets.len()];
+                for (node, cost) in self.upward(std::iter::once(source), Direction::Forward) {
+                    for &(target, rest) in buckets.get(&node).into_iter().flatten() {
+                        row[target] = row[target].min(cost + rest);
+                    }
+                }
+                row
+            })
+            .collect();
+
+        CostMatrix::from_rows(sources, targets, rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        contraction::hierarchy::ContractionHierarchy, matrix, osm_parser::OpenStreetMap,
+        params::SimpleParams,
+    };
+
+    #[test]
+    fn matches_one_to_many() {
+        let map = OpenStreetMap::random_roads(80, 19);
+        let hierarchy = ContractionHierarchy::new(&map, &SimpleParams);
+        let sources: Vec<u32> = (0..80).step_by(6).collect();
+        let targets: Vec<u32> = (3..80).step_by(5).collect();
+
+        let expected = matrix::many_to_many(&map, &sources, &targets, &SimpleParams);
+        let found = hierarchy.matrix(&map, &sources, &targets);
+
+        for (expected, found) in expected.costs.iter().zip(&found.costs) {
+            assert!(
+                (expected - found).abs() < 0.01 || (expected.is_infinite() && found.is_infinite())
+            );
+        }
+    }
+}
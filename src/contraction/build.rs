@@ -0,0 +1,272 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use crate::{
+    a_star::HeapNode,
+    contraction::hierarchy::{Arc, ContractionHierarchy, ORIGINAL},
+    graph::{Direction, Graph},
+    map_file::Source,
+    params::Params,
+};
+
+/// A witness search gives up after settling this many nodes. Giving up early
+/// only costs a shortcut which was not needed.
+const WITNESS_SETTLE_LIMIT: usize = 500;
+
+/// What is left of the map while it is being contracted. Each arc is kept as
+/// its cost and the node it skips, only the cheapest one between two nodes.
+struct Remaining {
+    outgoing: Vec<HashMap<u32, (f64, u32)>>,
+    incoming: Vec<HashMap<u32, (f64, u32)>>,
+}
+
+impl Remaining {
+    fn add(&mut self, from: u32, to: u32, cost: f64, middle: u32) {
+        let cheaper = self.outgoing[from as usize]
+            .get(&to)
+            .is_none_or(|&(prev, _)| cost < prev);
+        if from != to && cheaper {
+            self.outgoing[from as usize].insert(to, (cost, middle));
+            self.incoming[to as usize].insert(from, (cost, middle));
+        }
+    }
+
+    /// The cost of the cheapest paths from `from` which do not go through
+    /// `skip`, as far as `max_cost`.
+    fn witnesses(&self, from: u32, skip: u32, max_cost: f64) -> HashMap<u32, f64> {
+        let mut dist = HashMap::new();
+        let mut closed = HashSet::new();
+        let mut queue = BinaryHeap::new();
+
+        dist.insert(from, 0.0);
+        queue.push(HeapNode {
+            id: from,
+            f_score: 0.0,
+        });
+
+        while let Some(origin) = queue.pop() {
+            if origin.f_score > max_cost || closed.len() >= WITNESS_SETTLE_LIMIT {
+                break;
+            }
+            if !closed.insert(origin.id) {
+                continue;
+            }
+
+            for (&next, &(cost, _)) in &self.outgoing[origin.id as usize] {
+                let tentative = origin.f_score + cost;
+                if next == skip || dist.get(&next).is_some_and(|&prev| tentative >= prev) {
+                    continue;
+                }
+                dist.insert(next, tentative);
+                queue.push(HeapNode {
+                    id: next,
+                    f_score: tentative,
+                });
+            }
+        }
+
+        dist
+    }
+
+    /// The shortcuts contracting `node` needs: one for each pair of neighbours
+    /// whose only shortest path goes through it.
+    fn shortcuts(&self, node: u32) -> Vec<(u32, u32, f64)> {
+        let outgoing = &self.outgoing[node as usize];
+        let mut shortcuts = Vec::new();
+
+        for (&from, &(in_cost, _)) in &self.incoming[node as usize] {
+            let max_cost = outgoing
+                .iter()
+                .filter(|(&to, _)| to != from)
+                .map(|(_, &(out_cost, _))| in_cost + out_cost)
+                .fold(0.0, f64::max);
+            let witnesses = self.witnesses(from, node, max_cost);
+
+            for (&to, &(out_cost, _)) in outgoing {
+                let via = in_cost + out_cost;
+                let witnessed = witnesses.get(&to).is_some_and(|&dist| dist <= via);
+                if to != from && !witnessed {
+                    shortcuts.push((from, to, via));
+                }
+            }
+        }
+
+        shortcuts
+    }
+
+    /// How much contracting `node` would grow the graph, plus how many of its
+    /// neighbours are already gone, which spreads the contraction out evenly.
+    fn priority(&self, node: u32, contracted_neighbors: u32) -> i64 {
+        let removed = self.outgoing[node as usize].len() + self.incoming[node as usize].len();
+        self.shortcuts(node).len() as i64 - removed as i64 + contracted_neighbors as i64
+    }
+}
+
+impl ContractionHierarchy {
+    /// Contracts every node of `map`, with the costs of `params`.
+    #[allow(dead_code)]
+    pub fn new<G: Graph>(map: &G, params: &impl Params<G::Node>) -> ContractionHierarchy {
+        let node_count = map.node_count();
+        let mut remaining = Remaining {
+            outgoing: vec![HashMap::new(); node_count],
+            incoming: vec![HashMap::new(); node_count],
+        };
+        for from in 0..node_count as u32 {
+            for edge in map.edges(from, Direction::Forward) {
+                let cost = params.neighbor_dist(map.get(from), map.get(edge.node), edge);
+                remaining.add(from, edge.node, cost, ORIGINAL);
+            }
+        }
+
+        let mut contracted_neighbors = vec![0; node_count];
+        let mut priorities: Vec<i64> = (0..node_count as u32)
+            .map(|node| remaining.priority(node, 0))
+            .collect();
+        let mut queue: BinaryHeap<_> = priorities
+            .iter()
+            .enumerate()
+            .map(|(node, &priority)| Reverse((priority, node as u32)))
+            .collect();
+
+        let mut rank = vec![u32::MAX; node_count];
+        let mut up = vec![Vec::new(); node_count];
+        let mut down = vec![Vec::new(); node_count];
+        let mut next_rank = 0;
+
+        while let Some(Reverse((priority, node))) = queue.pop() {
+            // a node's priority changes when its neighbours are contracted,
+            // which leaves old entries behind
+            if rank[node as usize] != u32::MAX || priority != priorities[node as usize] {
+                continue;
+            }
+
+            let shortcuts = remaining.shortcuts(node);
+            let outgoing = std::mem::take(&mut remaining.outgoing[node as usize]);
+            let incoming = std::mem::take(&mut remaining.incoming[node as usize]);
+
+            rank[node as usize] = next_rank;
+            next_rank += 1;
+
+            for (&to, &(cost, middle)) in &outgoing {
+                remaining.incoming[to as usize].remove(&node);
+                up[node as usize].push(Arc {
+                    node: to,
+                    cost,
+                    middle,
+                });
+            }
+            for (&from, &(cost, middle)) in &incoming {
+                remaining.outgoing[from as usize].remove(&node);
+                down[node as usize].push(Arc {
+                    node: from,
+                    cost,
+                    middle,
+                });
+            }
+
+            for (from, to, cost) in shortcuts {
+                remaining.add(from, to, cost, node);
+            }
+
+            let neighbors: HashSet<u32> = outgoing.keys().chain(incoming.keys()).cloned().collect();
+            for neighbor in neighbors {
+                contracted_neighbors[neighbor as usize] += 1;
+                let priority =
+                    remaining.priority(neighbor, contracted_neighbors[neighbor as usize]);
+                priorities[neighbor as usize] = priority;
+                queue.push(Reverse((priority, neighbor)));
+            }
+        }
+
+        ContractionHierarchy::from_arcs(Source::of(map, params), rank, up, down)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        contraction::hierarchy::ContractionHierarchy,
+        dijkstra,
+        graph::{Direction, Graph},
+        map_file::{MapFileError, Source},
+        osm_parser::OpenStreetMap,
+        params::{SimpleParams, TravelTimeParams},
+    };
+
+    #[test]
+    fn matches_dijkstra() {
+        let map = OpenStreetMap::random_roads(80, 15);
+        let hierarchy = ContractionHierarchy::new(&map, &SimpleParams);
+
+        for source in (0..80).step_by(7) {
+            let tree = dijkstra::tree(&map, source, Direction::Forward, &SimpleParams, None);
+            for goal in 0..80 {
+                let found = hierarchy.path(&map, source, goal);
+                assert_eq!(tree.reached(goal), found.is_some());
+
+                if let Some((path, cost)) = found {
+                    assert!((tree.dist[goal as usize] - cost).abs() < 0.01);
+
+                    // shortcuts are unpacked into edges of the map
+                    assert_eq!((source, goal), (path.ids[0], *path.ids.last().unwrap()));
+                    for pair in path.ids.windows(2) {
+                        assert!(map.next_to_id(pair[0]).any(|next| next == pair[1]));
+                    }
+                    assert!((path.length_metres() - cost).abs() < 0.01);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn write_read() {
+        let map = OpenStreetMap::random_roads(40, 16);
+        let hierarchy = ContractionHierarchy::new(&map, &SimpleParams);
+        let mut bytes = Vec::new();
+        hierarchy.write(&mut bytes).unwrap();
+
+        let source = Source::of(&map, &SimpleParams);
+        let read = ContractionHierarchy::read(&bytes[..], &source).unwrap();
+        assert_eq!(hierarchy.shortcut_count(), read.shortcut_count());
+        for id in 0..40 {
+            assert_eq!(hierarchy.rank(id), read.rank(id));
+        }
+        let (path, _) = hierarchy.path(&map, 3, 30).unwrap();
+        assert_eq!(path.ids, read.path(&map, 3, 30).unwrap().0.ids);
+
+        // a hierarchy for another map of the same size, or other costs, is
+        // refused
+        let other = OpenStreetMap::random_roads(40, 17);
+        assert!(matches!(
+            ContractionHierarchy::read(&bytes[..], &Source::of(&other, &SimpleParams)),
+            Err(MapFileError::Mismatch)
+        ));
+        assert!(matches!(
+            ContractionHierarchy::read(&bytes[..], &Source::of(&map, &TravelTimeParams::new(&map))),
+            Err(MapFileError::Mismatch)
+        ));
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(ContractionHierarchy::read(&bytes[..], &source).is_err());
+    }
+
+    #[test]
+    fn save_next_to_map() {
+        let map = OpenStreetMap::from_edges(3, &[(0, 1), (1, 2), (2, 0)]);
+        let name = std::env::temp_dir().join(format!("ai_osm_ch_{}.save", std::process::id()));
+        let name = name.to_str().unwrap();
+
+        ContractionHierarchy::new(&map, &SimpleParams)
+            .save(name)
+            .unwrap();
+        let read = ContractionHierarchy::open(name, &Source::of(&map, &SimpleParams)).unwrap();
+        fs::remove_file(ContractionHierarchy::file(name)).unwrap();
+
+        assert_eq!(vec![1, 2, 0], read.path(&map, 1, 0).unwrap().0.ids);
+    }
+}
@@ -0,0 +1,434 @@
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet},
+    fs::File,
+    io,
+    io::{BufReader, BufWriter, Read, Write},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    a_star::{HeapNode, Path},
+    graph::{Direction, Graph},
+    map_file::{ChecksumReader, ChecksumWriter, MapFileError, Source},
+};
+
+/// The first bytes of a file written by `ContractionHierarchy::write`.
+pub const MAGIC: [u8; 4] = *b"RCHG";
+
+/// Version 2 records the map and costs the hierarchy was built from.
+pub const VERSION: u16 = 2;
+
+/// The `middle` of an arc which is an edge of the map rather than a shortcut.
+pub const ORIGINAL: u32 = u32::MAX;
+
+/// An edge of the hierarchy. It always leads to a node contracted after the
+/// one it is stored at.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Arc {
+    pub node: u32,
+    pub cost: f64,
+    /// the node a shortcut skips, or `ORIGINAL`
+    pub middle: u32,
+}
+
+/// The arcs of every node in one array, like `CsrGraph` stores edges.
+struct Arcs {
+    offsets: Vec<u32>,
+    arcs: Vec<Arc>,
+}
+
+impl Arcs {
+    fn new(lists: Vec<Vec<Arc>>) -> Arcs {
+        let mut offsets = Vec::with_capacity(lists.len() + 1);
+        let mut arcs = Vec::new();
+
+        offsets.push(0);
+        for list in lists {
+            arcs.extend(list);
+            offsets.push(arcs.len() as u32);
+        }
+
+        Arcs { offsets, arcs }
+    }
+
+    fn of(&self, id: u32) -> &[Arc] {
+        let from = self.offsets[id as usize] as usize;
+        let to = self.offsets[id as usize + 1] as usize;
+        &self.arcs[from..to]
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_u32::<BigEndian>(self.arcs.len() as u32)?;
+        for &offset in &self.offsets {
+            writer.write_u32::<BigEndian>(offset)?;
+        }
+        for arc in &self.arcs {
+            writer.write_u32::<BigEndian>(arc.node)?;
+            writer.write_f64::<BigEndian>(arc.cost)?;
+            writer.write_u32::<BigEndian>(arc.middle)?;
+        }
+        Ok(())
+    }
+
+    fn read(reader: &mut impl Read, node_count: u32) -> Result<Arcs, MapFileError> {
+        let arc_count = reader.read_u32::<BigEndian>()?;
+
+        let mut offsets = Vec::with_capacity(node_count as usize + 1);
+        for _ in 0..=node_count {
+            let offset = reader.read_u32::<BigEndian>()?;
+            if offset > arc_count || offsets.last().is_some_and(|&last| offset < last) {
+                return Err(MapFileError::Corrupt);
+            }
+            offsets.push(offset);
+        }
+        if offsets[0] != 0 || offsets[node_count as usize] != arc_count {
+            return Err(MapFileError::Corrupt);
+        }
+
+        let mut arcs = Vec::with_capacity(arc_count as usize);
+        for _ in 0..arc_count {
+            let arc = Arc {
+                node: reader.read_u32::<BigEndian>()?,
+                cost: reader.read_f64::<BigEndian>()?,
+                middle: reader.read_u32::<BigEndian>()?,
+            };
+            if arc.node >= node_count || (arc.middle != ORIGINAL && arc.middle >= node_count) {
+                return Err(MapFileError::Corrupt);
+            }
+            arcs.push(arc);
+        }
+
+        Ok(Arcs { offsets, arcs })
+    }
+}
+
+/// A map preprocessed by contracting its nodes one at a time, cheapest first,
+/// and adding a shortcut wherever that removed the only shortest path between
+/// two of its neighbours. Every shortest path then climbs to the node on it
+/// contracted last and comes back down, so a query only ever has to search
+/// upwards from both ends, which touches a tiny part of the map.
+///
+/// The costs are those of the `Params` it was built with.
+pub struct ContractionHierarchy {
+    /// the map and costs it was built from
+    source: Source,
+    /// the order each node was contracted in
+    rank: Vec<u32>,
+    /// the arcs leaving each node
+    up: Arcs,
+    /// the arcs coming into each node, with `Arc::node` where they start
+    down: Arcs,
+}
+
+/// One direction of a query. `parent` has the node each one was reached from
+/// and the `middle` of that arc.
+struct Search {
+    direction: Direction,
+    dist: HashMap<u32, f64>,
+    parent: HashMap<u32, (u32, u32)>,
+    closed: HashSet<u32>,
+    queue: BinaryHeap<HeapNode>,
+}
+
+impl Search {
+    fn new(direction: Direction, from: impl IntoIterator<Item = u32>) -> Search {
+        let from: Vec<u32> = from.into_iter().collect();
+        let queue = from
+            .iter()
+            .map(|&id| HeapNode { id, f_score: 0.0 })
+            .collect();
+        Search {
+            direction,
+            dist: from.iter().map(|&id| (id, 0.0)).collect(),
+            parent: HashMap::new(),
+            closed: HashSet::new(),
+            queue,
+        }
+    }
+
+    fn min_key(&self) -> f64 {
+        self.queue.peek().map_or(f64::INFINITY, |node| node.f_score)
+    }
+}
+
+impl ContractionHierarchy {
+    pub(super) fn from_arcs(
+        source: Source,
+        rank: Vec<u32>,
+        up: Vec<Vec<Arc>>,
+        down: Vec<Vec<Arc>>,
+    ) -> Self {
+        ContractionHierarchy {
+            source,
+            rank,
+            up: Arcs::new(up),
+            down: Arcs::new(down),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn node_count(&self) -> usize {
+        self.rank.len()
+    }
+
+    /// the number of arcs which are shortcuts
+    #[allow(dead_code)]
+    pub fn shortcut_count(&self) -> usize {
+        self.up
+            .arcs
+            .iter()
+            .chain(&self.down.arcs)
+            .filter(|arc| arc.middle != ORIGINAL)
+            .count()
+    }
+
+    #[allow(dead_code)]
+    pub fn rank(&self, id: u32) -> u32 {
+        self.rank[id as usize]
+    }
+
+    fn arcs(&self, id: u32, direction: Direction) -> &[Arc] {
+        match direction {
+            Direction::Forward => self.up.of(id),
+            Direction::Backward => self.down.of(id),
+        }
+    }
+
+    /// The cost of getting from `from` to every node above it, or with
+    /// `Backward`, from every node above it to `from`. These are upper bounds,
+    /// but exact for the top node of any shortest path.
+    pub(super) fn upward(
+        &self,
+        from: impl IntoIterator<Item = u32>,
+        direction: Direction,
+    ) -> HashMap<u32, f64> {
+        let mut search = Search::new(direction, from);
+        while let Some(origin) = search.queue.pop() {
+            if !search.closed.insert(origin.id) {
+                continue;
+            }
+            for arc in self.arcs(origin.id, direction) {
+                let tentative = origin.f_score + arc.cost;
+                if search
+                    .dist
+                    .get(&arc.node)
+                    .is_some_and(|&prev| tentative >= prev)
+                {
+                    continue;
+                }
+                search.dist.insert(arc.node, tentative);
+                search.queue.push(HeapNode {
+                    id: arc.node,
+                    f_score: tentative,
+                });
+            }
+        }
+        search.dist
+    }
+
+    /// The cheapest path from `init_node` to `goal_node` and its cost. `map`
+    /// has to be the map the hierarchy was built from, or the path is `None`
+    /// where a shortcut cannot be unpacked.
+    #[allow(dead_code)]
+    pub fn path<'a, G: Graph>(
+        &self,
+        map: &'a G,
+        init_node: u32,
+        goal_node: u32,
+    ) -> Option<(Path<'a, G>, f64)> {
+        let mut searches = [
+            Search::new(Direction::Forward, std::iter::once(init_node)),
+            // a route arriving over a turn restriction ends at a copy
+            Search::new(Direction::Backward, map.with_copies(goal_node)),
+        ];
+        let mut best: Option<(u32, f64)> = None;
+
+        loop {
+            // step whichever side is behind, until neither can improve on best
+            let side = if searches[0].min_key() <= searches[1].min_key() {
+                0
+            } else {
+                1
+            };
+            let limit = best.map_or(f64::INFINITY, |(_, cost)| cost);
+            if searches[side].min_key() >= limit {
+                break;
+            }
+
+            let (search, other) = match side {
+                0 => {
+                    let (first, second) = searches.split_at_mut(1);
+                    (&mut first[0], &second[0])
+                }
+                _ => {
+                    let (first, second) = searches.split_at_mut(1);
+                    (&mut second[0], &first[0])
+                }
+            };
+
+            let origin = search.queue.pop().unwrap();
+            if !search.closed.insert(origin.id) {
+                continue;
+            }
+
+            if let Some(&other_dist) = other.dist.get(&origin.id) {
+                let cost = origin.f_score + other_dist;
+                if best.is_none_or(|(_, best_cost)| cost < best_cost) {
+                    best = Some((origin.id, cost));
+                }
+            }
+
+            for arc in self.arcs(origin.id, search.direction) {
+                let tentative = origin.f_score + arc.cost;
+                if search
+                    .dist
+                    .get(&arc.node)
+                    .is_some_and(|&prev| tentative >= prev)
+                {
+                    continue;
+                }
+
+                search.dist.insert(arc.node, tentative);
+                search.parent.insert(arc.node, (origin.id, arc.middle));
+                search.queue.push(HeapNode {
+                    id: arc.node,
+                    f_score: tentative,
+                });
+            }
+        }
+
+        let (meeting, cost) = best?;
+
+        // the arcs up from the start, then down to the goal
+        let mut arcs = Vec::new();
+        let mut on = meeting;
+        while let Some(&(prev, middle)) = searches[0].parent.get(&on) {
+            arcs.push((prev, on, middle));
+            on = prev;
+        }
+        arcs.reverse();
+        let mut on = meeting;
+        while let Some(&(next, middle)) = searches[1].parent.get(&on) {
+            arcs.push((on, next, middle));
+            on = next;
+        }
+
+        let mut ids = vec![init_node];
+        for (from, to, middle) in arcs {
+            self.unpack(from, to, middle, &mut ids)?;
+        }
+
+        Some((
+            Path {
+                ids,
+                parent_map: map,
+            },
+            cost,
+        ))
+    }
+
+    /// Appends the nodes after `from` on the arc from `from` to `to`, replacing
+    /// shortcuts with the edges they stand for. `None` if a shortcut is not
+    /// made of two arcs, which only a corrupt file can cause.
+    fn unpack(&self, from: u32, to: u32, middle: u32, into: &mut Vec<u32>) -> Option<()> {
+        let mut stack = vec![(from, to, middle)];
+        while let Some((from, to, middle)) = stack.pop() {
+            if middle == ORIGINAL {
+                into.push(to);
+                continue;
+            }
+
+            // the middle was contracted before both ends, so both halves are
+            // stored at it
+            let first = self.cheapest(self.down.of(middle), from)?;
+            let second = self.cheapest(self.up.of(middle), to)?;
+            stack.push((middle, to, second.middle));
+            stack.push((from, middle, first.middle));
+        }
+        Some(())
+    }
+
+    fn cheapest(&self, arcs: &[Arc], node: u32) -> Option<Arc> {
+        arcs.iter()
+            .filter(|arc| arc.node == node)
+            .min_by(|a, b| a.cost.total_cmp(&b.cost))
+            .copied()
+    }
+
+    /// the file the hierarchy of the map file `name` is saved in
+    pub fn file(name: &str) -> String {
+        format!("{}.ch", name)
+    }
+
+    /// Saves the hierarchy next to the map file `name`.
+    #[allow(dead_code)]
+    pub fn save(&self, name: &str) -> io::Result<()> {
+        let file = File::create(ContractionHierarchy::file(name))?;
+        self.write(BufWriter::new(file))
+    }
+
+    /// Reads the hierarchy saved next to the map file `name`, which has to
+    /// have been built from `source`.
+    #[allow(dead_code)]
+    pub fn open(name: &str, source: &Source) -> Result<Self, MapFileError> {
+        let file = File::open(ContractionHierarchy::file(name))?;
+        ContractionHierarchy::read(BufReader::new(file), source)
+    }
+
+    pub fn write(&self, writer: impl Write) -> io::Result<()> {
+        let mut writer = ChecksumWriter::new(writer);
+
+        writer.write_all(&MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
+        self.source.write(&mut writer)?;
+        for &rank in &self.rank {
+            writer.write_u32::<BigEndian>(rank)?;
+        }
+        self.up.write(&mut writer)?;
+        self.down.write(&mut writer)?;
+
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Reads a hierarchy written by `write`. One built from another map or
+    /// with other costs than `source` is a `MapFileError::Mismatch`.
+    pub fn read(reader: impl Read, source: &Source) -> Result<Self, MapFileError> {
+        let mut reader = ChecksumReader::new(reader);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(MapFileError::BadMagic);
+        }
+
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(MapFileError::UnsupportedVersion(version));
+        }
+
+        let source = Source::read(&mut reader, source)?;
+        let count = source.node_count;
+
+        let mut rank = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let value = reader.read_u32::<BigEndian>()?;
+            if value >= count {
+                return Err(MapFileError::Corrupt);
+            }
+            rank.push(value);
+        }
+
+        let up = Arcs::read(&mut reader, count)?;
+        let down = Arcs::read(&mut reader, count)?;
+        reader.finish(true)?;
+
+        Ok(ContractionHierarchy {
+            source,
+            rank,
+            up,
+            down,
+        })
+    }
+}
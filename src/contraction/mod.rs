@@ -0,0 +1,3 @@
+mod buckets;
+mod build;
+pub mod hierarchy;
@@ -0,0 +1,228 @@
+use std::collections::BinaryHeap;
+
+use crate::{
+    a_star::{HeapNode, Path},
+    graph::{Direction, Graph},
+    params::Params,
+};
+
+/// The cheapest paths from one node to every node it can reach, or, searched
+/// `Backward`, from every node that can reach it.
+#[allow(dead_code)]
+pub struct ShortestPathTree<'a, G: Graph> {
+    map: &'a G,
+    /// the nodes the search started from, usually just one
+    pub sources: Vec<u32>,
+    pub direction: Direction,
+    /// the cost of the cheapest path between the sources and each node,
+    /// `f64::INFINITY` if there is none (or it costs more than the cap)
+    pub dist: Vec<f64>,
+    /// the node before each one on its path from the sources, which for a
+    /// `Backward` tree is the node after it on its way to the sources
+    pub prev: Vec<Option<u32>>,
+}
+
+impl<'a, G: Graph> ShortestPathTree<'a, G> {
+    pub fn reached(&self, id: u32) -> bool {
+        self.dist[id as usize].is_finite()
+    }
+
+    /// Where a route to `id` ends. Going `Forward`, a route arriving over a
+    /// turn restriction ends at a copy of `id`, so this is the cheapest of
+    /// `id` and its copies.
+    fn end(&self, id: u32) -> u32 {
+        match self.direction {
+            Direction::Forward => self
+                .map
+                .with_copies(id)
+                .min_by(|&a, &b| self.dist[a as usize].total_cmp(&self.dist[b as usize]))
+                .unwrap_or(id),
+            Direction::Backward => id,
+        }
+    }
+
+    /// the cost between the sources and `id`, if the search got there
+    #[allow(dead_code)]
+    pub fn dist_to(&self, id: u32) -> Option<f64> {
+        Some(self.dist[self.end(id) as usize]).filter(|dist| dist.is_finite())
+    }
+
+    /// every node the search got to, in no particular order
+    #[allow(dead_code)]
+    pub fn reached_nodes(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.dist.len() as u32).filter(move |&id| self.reached(id))
+    }
+
+    /// The cheapest path between the sources and `id`, in the direction of
+    /// travel: from a source for a `Forward` tree, to one for a `Backward` one.
+    #[allow(dead_code)]
+    pub fn path_to(&self, id: u32) -> Option<Path<'a, G>> {
+        let id = self.end(id);
+        if !self.reached(id) {
+            return None;
+        }
+
+        let mut ids = vec![id];
+        let mut on = id;
+        while let Some(prev) = self.prev[on as usize] {
+            ids.push(prev);
+            on = prev;
+        }
+        if self.direction == Direction::Forward {
+            ids.reverse();
+        }
+
+        Some(Path {
+            ids,
+            parent_map: self.map,
+        })
+    }
+}
+
+/// Dijkstra's algorithm from `source` until every reachable node is settled,
+/// or, with `max_dist`, until everything left costs more than that.
+#[allow(dead_code)]
+pub fn tree<'a, G: Graph>(
+    map: &'a G,
+    source: u32,
+    direction: Direction,
+    params: &impl Params<G::Node>,
+    max_dist: Option<f64>,
+) -> ShortestPathTree<'a, G> {
+    tree_from(map, &[(source, 0.0)], direction, params, max_dist)
+}
+
+/// `tree` from several nodes at once, each starting at its own cost, like the
+/// `departures` of a snapped point. Searched `Backward`, routes may end at the
+/// copies of the sources too.
+pub fn tree_from<'a, G: Graph>(
+    map: &'a G,
+    sources: &[(u32, f64)],
+    direction: Direction,
+    params: &impl Params<G::Node>,
+    max_dist: Option<f64>,
+) -> ShortestPathTree<'a, G> {
+    let max_dist = max_dist.unwrap_or(f64::INFINITY);
+    let mut dist = vec![f64::INFINITY; map.node_count()];
+    let mut prev = vec![None; map.node_count()];
+    let mut settled = vec![false; map.node_count()];
+    let mut queue = BinaryHeap::new();
+
+    let starts = sources.iter().flat_map(|&(source, cost)| {
+        let copies = map
+            .copies(source)
+            .filter(move |_| direction == Direction::Backward);
+        std::iter::once(source)
+            .chain(copies)
+            .map(move |id| (id, cost))
+    });
+    for (source, cost) in starts {
+        if cost <= max_dist && cost < dist[source as usize] {
+            dist[source as usize] = cost;
+            queue.push(HeapNode {
+                id: source,
+                f_score: cost,
+            });
+        }
+    }
+
+    while let Some(origin) = queue.pop() {
+        if settled[origin.id as usize] {
+            continue;
+        }
+        settled[origin.id as usize] = true;
+
+        let origin_node = map.get(origin.id);
+        for edge in map.edges(origin.id, direction) {
+            let neighbor = edge.node;
+            let neighbor_node = map.get(neighbor);
+            let edge_dist = match direction {
+                Direction::Forward => params.neighbor_dist(origin_node, neighbor_node, edge),
+                Direction::Backward => params.neighbor_dist(neighbor_node, origin_node, edge),
+            };
+            let tentative = origin.f_score + edge_dist;
+
+            if tentative > max_dist || tentative >= dist[neighbor as usize] {
+                continue;
+            }
+
+            dist[neighbor as usize] = tentative;
+            prev[neighbor as usize] = Some(origin.id);
+            queue.push(HeapNode {
+                id: neighbor,
+                f_score: tentative,
+            });
+        }
+    }
+
+    ShortestPathTree {
+        map,
+        sources: sources.iter().map(|&(source, _)| source).collect(),
+        direction,
+        dist,
+        prev,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        a_star, dijkstra,
+        graph::Direction,
+        osm_parser::{Location, OpenStreetMap},
+        params::SimpleParams,
+    };
+
+    #[test]
+    fn matches_a_star() {
+        let map = OpenStreetMap::random_roads(60, 14);
+        let forward = dijkstra::tree(&map, 7, Direction::Forward, &SimpleParams, None);
+        let backward = dijkstra::tree(&map, 7, Direction::Backward, &SimpleParams, None);
+
+        for id in 0..map.node_count() as u32 {
+            let there = a_star::path(&map, 7, id);
+            assert_eq!(there.is_some(), forward.reached(id));
+            if let Some(there) = there {
+                let dist = forward.dist_to(id).unwrap();
+                assert!((there.length_metres() - dist).abs() < 0.01);
+
+                let path = forward.path_to(id).unwrap();
+                assert_eq!((7, id), (path.ids[0], *path.ids.last().unwrap()));
+                assert!((path.length_metres() - dist).abs() < 0.01);
+            }
+
+            let back = a_star::path(&map, id, 7);
+            assert_eq!(back.is_some(), backward.reached(id));
+            if let Some(back) = back {
+                let dist = backward.dist_to(id).unwrap();
+                assert!((back.length_metres() - dist).abs() < 0.01);
+
+                let path = backward.path_to(id).unwrap();
+                assert_eq!((id, 7), (path.ids[0], *path.ids.last().unwrap()));
+                assert!((path.length_metres() - dist).abs() < 0.01);
+            }
+        }
+    }
+
+    #[test]
+    fn capped() {
+        // 0 -> 1 -> 2 -> 3, a degree apart
+        let map = OpenStreetMap::from_edges(4, &[(0, 1), (1, 2), (2, 3)]);
+        let degree = Location(0.0, 0.0).dist_metres(Location(1.0, 0.0));
+
+        let tree = dijkstra::tree(
+            &map,
+            0,
+            Direction::Forward,
+            &SimpleParams,
+            Some(2.5 * degree),
+        );
+        assert_eq!(vec![0, 1, 2], tree.reached_nodes().collect::<Vec<_>>());
+        assert_eq!(Some(1), tree.prev[2]);
+        assert!(tree.path_to(3).is_none());
+
+        let tree = dijkstra::tree(&map, 2, Direction::Backward, &SimpleParams, None);
+        assert_eq!(vec![0, 1, 2], tree.reached_nodes().collect::<Vec<_>>());
+        assert_eq!(vec![0, 1, 2], tree.path_to(0).unwrap().ids);
+    }
+}
@@ -0,0 +1,170 @@
+/// A directed edge between two nodes that are next to each other on a way.
+///
+/// The attributes are those of the way the edge came from, so they do not have
+/// to be looked up in the PBF again when searching.
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct Edge {
+    /// the node at the other end of the edge. For an incoming edge this is the
+    /// node the edge starts at.
+    pub node: u32,
+    /// length in metres
+    pub length: f32,
+    /// the `Highway` as a u8. A mapped file is used as it is, so this can be
+    /// any value; `highway()` reads it.
+    pub highway: u8,
+    /// the speed limit in km/h, or 0 if the way has no (numeric) `maxspeed` tag
+    pub max_speed: u8,
+    /// the id of the OSM way this edge is part of
+    pub way_id: i64,
+}
+
+impl Edge {
+    /// A copy of this edge which points at `node` instead. Used to flip an edge
+    /// into an incoming edge and to renumber nodes.
+    pub fn with_node(&self, node: u32) -> Edge {
+        Edge { node, ..*self }
+    }
+
+    /// the `highway` class, `Other` if it is not one this version knows
+    pub fn highway(&self) -> Highway {
+        Highway::from_u8(self.highway)
+    }
+}
+
+/// The `highway` class of a way.
+///
+/// https://wiki.openstreetmap.org/wiki/Key:highway
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Highway {
+    Motorway,
+    MotorwayLink,
+    Trunk,
+    TrunkLink,
+    Primary,
+    PrimaryLink,
+    Secondary,
+    SecondaryLink,
+    Tertiary,
+    TertiaryLink,
+    Unclassified,
+    Residential,
+    LivingStreet,
+    Service,
+    Track,
+    Road,
+    Pedestrian,
+    Footway,
+    Cycleway,
+    Path,
+    Steps,
+    Other,
+}
+
+impl Highway {
+    pub const ALL: [Highway; 22] = [
+        Highway::Motorway,
+        Highway::MotorwayLink,
+        Highway::Trunk,
+        Highway::TrunkLink,
+        Highway::Primary,
+        Highway::PrimaryLink,
+        Highway::Secondary,
+        Highway::SecondaryLink,
+        Highway::Tertiary,
+        Highway::TertiaryLink,
+        Highway::Unclassified,
+        Highway::Residential,
+        Highway::LivingStreet,
+        Highway::Service,
+        Highway::Track,
+        Highway::Road,
+        Highway::Pedestrian,
+        Highway::Footway,
+        Highway::Cycleway,
+        Highway::Path,
+        Highway::Steps,
+        Highway::Other,
+    ];
+
+    pub fn from_tag(value: &str) -> Highway {
+        match value {
+            "motorway" => Highway::Motorway,
+            "motorway_link" => Highway::MotorwayLink,
+            "trunk" => Highway::Trunk,
+            "trunk_link" => Highway::TrunkLink,
+            "primary" => Highway::Primary,
+            "primary_link" => Highway::PrimaryLink,
+            "secondary" => Highway::Secondary,
+            "secondary_link" => Highway::SecondaryLink,
+            "tertiary" => Highway::Tertiary,
+            "tertiary_link" => Highway::TertiaryLink,
+            "unclassified" => Highway::Unclassified,
+            "residential" => Highway::Residential,
+            "living_street" => Highway::LivingStreet,
+            "service" => Highway::Service,
+            "track" => Highway::Track,
+            "road" => Highway::Road,
+            "pedestrian" => Highway::Pedestrian,
+            "footway" | "sidewalk" | "crossing" => Highway::Footway,
+            "cycleway" => Highway::Cycleway,
+            "path" | "bridleway" => Highway::Path,
+            "steps" => Highway::Steps,
+            _ => Highway::Other,
+        }
+    }
+
+    /// The inverse of `as u8`. Unknown values become `Other`.
+    pub fn from_u8(value: u8) -> Highway {
+        Highway::ALL
+            .get(value as usize)
+            .cloned()
+            .unwrap_or(Highway::Other)
+    }
+}
+
+/// Parses a `maxspeed` value into km/h. Values which are not a number (`none`,
+/// `signals`, `RU:urban`, ...) give `None`.
+///
+/// https://wiki.openstreetmap.org/wiki/Key:maxspeed
+pub fn parse_max_speed(value: &str) -> Option<u8> {
+    // "50;30" means the limit varies, use the first one
+    let value = value.split(';').next()?.trim();
+
+    let (number, factor) = match value.strip_suffix("mph") {
+        Some(number) => (number, 1.609_344),
+        None => (value.strip_suffix("km/h").unwrap_or(value), 1.0),
+    };
+
+    let speed: f64 = number.trim().parse().ok()?;
+    if speed <= 0.0 {
+        return None;
+    }
+
+    Some((speed * factor).round().min(u8::MAX as f64) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::edge::{parse_max_speed, Highway};
+
+    #[test]
+    fn max_speed() {
+        assert_eq!(Some(50), parse_max_speed("50"));
+        assert_eq!(Some(50), parse_max_speed("50 km/h"));
+        assert_eq!(Some(48), parse_max_speed("30 mph"));
+        assert_eq!(Some(89), parse_max_speed("55mph"));
+        assert_eq!(Some(50), parse_max_speed("50;30"));
+        assert_eq!(None, parse_max_speed("none"));
+        assert_eq!(None, parse_max_speed("US:urban"));
+    }
+
+    #[test]
+    fn highway_round_trip() {
+        for &highway in Highway::ALL.iter() {
+            assert_eq!(highway, Highway::from_u8(highway as u8));
+        }
+        assert_eq!(Highway::Other, Highway::from_u8(200));
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt::Write;
+
+use crate::{a_star::Path, graph::Graph, osm_parser::Location};
+
+/// Degrees to 7 places are about a centimetre, as precise as OSM itself.
+fn coordinates(location: Location) -> (String, String) {
+    (
+        format!("{:.7}", location.x()),
+        format!("{:.7}", location.y()),
+    )
+}
+
+/// `text` with the characters which mean something in XML escaped
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The path as a GeoJSON Feature with a LineString geometry, and its length,
+/// node count and the OSM ids of its nodes as properties.
+#[allow(dead_code)]
+pub fn geojson<G: Graph>(path: &Path<G>) -> String {
+    let map = path.parent_map;
+    let points: Vec<String> = path
+        .ids
+        .iter()
+        .map(|&id| {
+            let (lon, lat) = coordinates(map.location(id));
+            format!("[{},{}]", lon, lat)
+        })
+        .collect();
+    let osm_ids: Vec<String> = path.osm_ids().iter().map(i64::to_string).collect();
+
+    format!(
+        concat!(
+            r#"{{"type":"Feature","#,
+            r#""geometry":{{"type":"LineString","coordinates":[{}]}},"#,
+            r#""properties":{{"length_metres":{:.1},"length_miles":{:.3},"#,
+            r#""node_count":{},"osm_ids":[{}]}}}}"#
+        ),
+        points.join(","),
+        path.length_metres(),
+        path.length_miles(),
+        path.ids.len(),
+        osm_ids.join(","),
+    )
+}
+
+/// The path as a GPX track called `name`, with a track point for each node.
+#[allow(dead_code)]
+pub fn gpx<G: Graph>(path: &Path<G>, name: &str) -> String {
+    let map = path.parent_map;
+    let mut gpx = String::new();
+    gpx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gpx.push_str(
+        "<gpx version=\"1.1\" creator=\"ai_osm\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    );
+    writeln!(
+        gpx,
+        "  <trk>\n    <name>{}</name>\n    <trkseg>",
+        escape(name)
+    )
+    .unwrap();
+    for &id in &path.ids {
+        let (lon, lat) = coordinates(map.location(id));
+        writeln!(gpx, "      <trkpt lat=\"{}\" lon=\"{}\"/>", lat, lon).unwrap();
+    }
+    gpx.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    gpx
+}
+
+/// The path as a KML document with one LineString placemark called `name`.
+#[allow(dead_code)]
+pub fn kml<G: Graph>(path: &Path<G>, name: &str) -> String {
+    let map = path.parent_map;
+    let points: Vec<String> = path
+        .ids
+        .iter()
+        .map(|&id| {
+            let (lon, lat) = coordinates(map.location(id));
+            format!("{},{}", lon, lat)
+        })
+        .collect();
+
+    let mut kml = String::new();
+    kml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    kml.push_str("<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n  <Document>\n");
+    writeln!(kml, "    <Placemark>\n      <name>{}</name>", escape(name)).unwrap();
+    writeln!(
+        kml,
+        "      <description>{:.1} m, {} nodes</description>",
+        path.length_metres(),
+        path.ids.len()
+    )
+    .unwrap();
+    writeln!(
+        kml,
+        "      <LineString>\n        <tessellate>1</tessellate>\n        \
+         <coordinates>{}</coordinates>\n      </LineString>",
+        points.join(" ")
+    )
+    .unwrap();
+    kml.push_str("    </Placemark>\n  </Document>\n</kml>\n");
+    kml
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        a_star,
+        export::{geojson, gpx, kml},
+        map_match::read_gpx,
+        osm_parser::OpenStreetMap,
+    };
+
+    #[test]
+    fn formats() {
+        // along the equator, a degree apart
+        let map = OpenStreetMap::from_edges(3, &[(0, 1), (1, 2)]);
+        let path = a_star::path(&map, 0, 2).unwrap();
+
+        let json = geojson(&path);
+        assert!(json.starts_with(r#"{"type":"Feature","geometry":{"type":"LineString""#));
+        assert!(json.contains(
+            r#""coordinates":[[0.0000000,0.0000000],[1.0000000,0.0000000],[2.0000000,0.0000000]]"#
+        ));
+        assert!(json.contains(r#""node_count":3,"osm_ids":[100,101,102]}}"#));
+        assert!(json.contains(&format!(r#""length_metres":{:.1}"#, path.length_metres())));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+
+        // our own reader gets the nodes back
+        let track = gpx(&path, "to <work> & back");
+        assert!(track.contains("<name>to &lt;work&gt; &amp; back</name>"));
+        let fixes = read_gpx(track.as_bytes()).unwrap();
+        assert_eq!(3, fixes.len());
+        assert_eq!((1.0, 0.0), (fixes[1].x(), fixes[1].y()));
+
+        let document = kml(&path, "route");
+        assert!(document.contains("<coordinates>0.0000000,0.0000000 1.0000000,0.0000000 2.0000000,0.0000000</coordinates>"));
+        assert!(document.contains("3 nodes"));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{
+    edge::Edge,
+    osm_parser::{Location, Node, OpenStreetMap},
+    profile::Profile,
+};
+
+/// Which way edges are followed. Searching backwards from a goal walks edges
+/// against their direction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// Anything with a position on the map. `Params` are written against this so
+/// they work for every `Graph::Node`.
+pub trait Located {
+    fn location(&self) -> Location;
+}
+
+impl Located for Node {
+    fn location(&self) -> Location {
+        self.location
+    }
+}
+
+impl Located for Location {
+    fn location(&self) -> Location {
+        *self
+    }
+}
+
+/// A directed road graph the searches can run on. Nodes are numbered
+/// `0..node_count()`.
+pub trait Graph: Sync {
+    type Node: Located;
+
+    #[allow(dead_code)]
+    fn node_count(&self) -> usize;
+
+    fn get(&self, id: u32) -> &Self::Node;
+
+    /// the edges leaving `id` (`Forward`) or entering it (`Backward`). For
+    /// incoming edges `Edge::node` is the node the edge starts at.
+    fn edges(&self, id: u32, direction: Direction) -> impl Iterator<Item = &Edge> + '_;
+
+    fn osm_id(&self, id: u32) -> i64;
+
+    #[allow(dead_code)]
+    fn profile(&self) -> Profile;
+
+    fn location(&self, id: u32) -> Location {
+        self.get(id).location()
+    }
+
+    /// The node `id` is a copy of, made for turn restrictions, or `id`. A
+    /// route to a node may end at any of its copies.
+    fn original(&self, id: u32) -> u32 {
+        id
+    }
+
+    /// the copies of `id` made for turn restrictions
+    fn copies(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        let _ = id;
+        std::iter::empty()
+    }
+
+    /// `id` and its copies: everywhere a route to `id` can end
+    fn with_copies(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        std::iter::once(id).chain(self.copies(id))
+    }
+
+    fn next_to_id(&self, from_id: u32) -> impl Iterator<Item = u32> + '_ {
+        self.edges(from_id, Direction::Forward)
+            .map(|edge| edge.node)
+    }
+
+    fn prev_to_id(&self, to_id: u32) -> impl Iterator<Item = u32> + '_ {
+        self.edges(to_id, Direction::Backward).map(|edge| edge.node)
+    }
+}
+
+impl Graph for OpenStreetMap {
+    type Node = Node;
+
+    fn node_count(&self) -> usize {
+        OpenStreetMap::node_count(self)
+    }
+
+    fn get(&self, id: u32) -> &Node {
+        OpenStreetMap::get(self, id)
+    }
+
+    fn edges(&self, id: u32, direction: Direction) -> impl Iterator<Item = &Edge> + '_ {
+        OpenStreetMap::edges(self, id, direction)
+    }
+
+    fn osm_id(&self, id: u32) -> i64 {
+        OpenStreetMap::osm_id(self, id)
+    }
+
+    fn original(&self, id: u32) -> u32 {
+        self.restriction_copies().original(id)
+    }
+
+    fn copies(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        self.restriction_copies().of(id)
+    }
+
+    fn profile(&self) -> Profile {
+        OpenStreetMap::profile(self)
+    }
+}
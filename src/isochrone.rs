@@ -0,0 +1,479 @@
+use crate::{
+    dijkstra,
+    dijkstra::ShortestPathTree,
+    graph::{Direction, Graph},
+    osm_parser::Location,
+    params::Params,
+};
+
+/// Where the budget runs out along an edge from a node in the reachable area
+/// to one outside it.
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub struct Cut {
+    pub from: u32,
+    pub to: u32,
+    /// how far along the edge the budget runs out, from 0 at `from` to 1 at
+    /// `to`
+    pub fraction: f64,
+    pub location: Location,
+}
+
+/// Everything reachable from a node within a budget: a distance, or a time
+/// with `TravelTimeParams`.
+#[allow(dead_code)]
+pub struct Isochrone<'a, G: Graph> {
+    pub tree: ShortestPathTree<'a, G>,
+    pub budget: f64,
+    /// the edges the budget runs out on
+    pub boundary: Vec<Cut>,
+}
+
+impl<'a, G: Graph> Isochrone<'a, G> {
+    /// Searches out from `start` until everything left costs more than
+    /// `budget`.
+    #[allow(dead_code)]
+    pub fn new(
+        map: &'a G,
+        start: u32,
+        budget: f64,
+        params: &impl Params<G::Node>,
+    ) -> Isochrone<'a, G> {
+        let tree = dijkstra::tree(map, start, Direction::Forward, params, Some(budget));
+
+        let mut boundary = Vec::new();
+        for from in tree.reached_nodes() {
+            let left = budget - tree.dist[from as usize];
+            let from_node = map.get(from);
+            for edge in map.edges(from, Direction::Forward) {
+                let to = edge.node;
+                let cost = params.neighbor_dist(from_node, map.get(to), edge);
+                if tree.reached(to) || cost <= left {
+                    continue;
+                }
+
+                let fraction = left / cost;
+                let (a, b) = (map.location(from), map.location(to));
+                boundary.push(Cut {
+                    from,
+                    to,
+                    fraction,
+                    location: Location(
+                        a.x() + fraction * (b.x() - a.x()),
+                        a.y() + fraction * (b.y() - a.y()),
+                    ),
+                });
+            }
+        }
+
+        Isochrone {
+            tree,
+            budget,
+            boundary,
+        }
+    }
+
+    /// every node within the budget
+    #[allow(dead_code)]
+    pub fn nodes(&self) -> impl Iterator<Item = u32> + '_ {
+        self.tree.reached_nodes()
+    }
+
+    /// A polygon around the reachable nodes and the ends of the cut edges.
+    /// See `concave_hull` for `max_edge`.
+    #[allow(dead_code)]
+    pub fn polygon(&self, map: &G, max_edge: f64) -> Vec<Location> {
+        let points: Vec<_> = self
+            .nodes()
+            .map(|id| map.location(id))
+            .chain(self.boundary.iter().map(|cut| cut.location))
+            .collect();
+        concave_hull(&points, max_edge)
+    }
+}
+
+/// Flat coordinates in metres around a reference point, which is plenty
+/// accurate over the size of an isochrone.
+struct Plane {
+    origin: Location,
+    metres_per_degree: f64,
+    scale: f64,
+}
+
+impl Plane {
+    fn new(origin: Location) -> Plane {
+        Plane {
+            origin,
+            metres_per_degree: Location(0.0, 0.0).dist_metres(Location(0.0, 1.0)),
+            scale: origin.y().to_radians().cos(),
+        }
+    }
+
+    fn project(&self, location: Location) -> (f64, f64) {
+        (
+            (location.x() - self.origin.x()) * self.scale * self.metres_per_degree,
+            (location.y() - self.origin.y()) * self.metres_per_degree,
+        )
+    }
+}
+
+fn cross(o: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+fn dist(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+/// the indices of the convex hull of `points`, anticlockwise (Andrew's
+/// monotone chain)
+fn convex_hull(points: &[(f64, f64)]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..points.len()).collect();
+    order.sort_by(|&a, &b| points[a].partial_cmp(&points[b]).unwrap());
+    order.dedup_by(|a, b| points[*a] == points[*b]);
+    if order.len() < 3 {
+        return order;
+    }
+
+    let mut hull: Vec<usize> = Vec::with_capacity(order.len() * 2);
+    for pass in 0..2 {
+        let start = hull.len();
+        for &i in &order {
+            while hull.len() >= start + 2
+                && cross(
+                    points[hull[hull.len() - 2]],
+                    points[hull[hull.len() - 1]],
+                    points[i],
+                ) <= 0.0
+            {
+                hull.pop();
+            }
+            hull.push(i);
+        }
+        // the last point is the first of the other half
+        hull.pop();
+        if pass == 0 {
+            order.reverse();
+        }
+    }
+    hull
+}
+
+/// whether segments `a`-`b` and `c`-`d` cross, not counting shared ends
+fn crosses(a: (f64, f64), b: (f64, f64), c: (f64, f64), d: (f64, f64)) -> bool {
+    if a == c || a == d || b == c || b == d {
+        return false;
+    }
+    let (d1, d2) = (cross(c, d, a), cross(c, d, b));
+    let (d3, d4) = (cross(a, b, c), cross(a, b, d));
+    (d1 > 0.0) != (d2 > 0.0) && (d3 > 0.0) != (d4 > 0.0)
+}
+
+/// whether `p` is strictly inside triangle `a`, `b`, `c` (anticlockwise)
+fn in_triangle(p: (f64, f64), a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> bool {
+    cross(a, b, p) > 0.0 && cross(b, c, p) > 0.0 && cross(c, a, p) > 0.0
+}
+
+/// the distance from `p` to the segment `a`-`b`
+fn segment_dist(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len2 = dx * dx + dy * dy;
+    let t = if len2 == 0.0 {
+        0.0
+    } else {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len2).clamp(0.0, 1.0)
+    };
+    dist(p, (a.0 + t * dx, a.1 + t * dy))
+}
+
+/// How many of the points closest to an edge are tried when digging into it.
+const DIG_CANDIDATES: usize = 16;
+
+/// Points bucketed into square cells, about one per cell, so the points near
+/// an edge or in a triangle are found without looking at all of them.
+struct PointGrid {
+    min: (f64, f64),
+    max: (f64, f64),
+    cell: f64,
+    width: usize,
+    height: usize,
+    cells: Vec<Vec<usize>>,
+}
+
+impl PointGrid {
+    fn new(flat: &[(f64, f64)], ids: &[usize]) -> PointGrid {
+        let (mut min, mut max) = ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN));
+        for &id in ids {
+            let (x, y) = flat[id];
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+        let area = (max.0 - min.0).max(1.0) * (max.1 - min.1).max(1.0);
+        let cell = (area / ids.len().max(1) as f64).sqrt();
+        let width = ((max.0 - min.0) / cell) as usize + 1;
+        let height = ((max.1 - min.1) / cell) as usize + 1;
+
+        let mut grid = PointGrid {
+            min,
+            max,
+            cell,
+            width,
+            height,
+            cells: vec![Vec::new(); width * height],
+        };
+        for &id in ids {
+            let (x, y) = grid.cell_of(flat[id]);
+            grid.cells[y * width + x].push(id);
+        }
+        grid
+    }
+
+    fn cell_of(&self, (x, y): (f64, f64)) -> (usize, usize) {
+        let clamp = |v: f64, len: usize| (v.max(0.0) as usize).min(len - 1);
+        (
+            clamp((x - self.min.0) / self.cell, self.width),
+            clamp((y - self.min.1) / self.cell, self.height),
+        )
+    }
+
+    /// the points in the cells overlapping the box from `low` to `high`
+    fn in_box(&self, low: (f64, f64), high: (f64, f64)) -> impl Iterator<Item = usize> + '_ {
+        let (x0, y0) = self.cell_of(low);
+        let (x1, y1) = self.cell_of(high);
+        (y0..=y1)
+            .flat_map(move |y| (x0..=x1).map(move |x| y * self.width + x))
+            .flat_map(move |cell| self.cells[cell].iter().cloned())
+    }
+
+    /// whether the box from `low` to `high` holds every point
+    fn covers(&self, low: (f64, f64), high: (f64, f64)) -> bool {
+        low.0 <= self.min.0 && low.1 <= self.min.1 && high.0 >= self.max.0 && high.1 >= self.max.1
+    }
+}
+
+fn bounds(points: &[(f64, f64)]) -> ((f64, f64), (f64, f64)) {
+    points.iter().fold(
+        ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN)),
+        |(low, high), &(x, y)| ((low.0.min(x), low.1.min(y)), (high.0.max(x), high.1.max(y))),
+    )
+}
+
+/// A concave hull of `points`, anticlockwise.
+///
+/// Starts from the convex hull and digs into it: any edge longer than
+/// `max_edge` metres is split at the point inside closest to it, as long as
+/// that leaves every point inside and the outline does not cross itself. A
+/// smaller `max_edge` follows the points more tightly.
+///
+/// The points near an edge, and those that would be left out, come from a
+/// grid, so the work grows with the points near the outline rather than with
+/// all of them for every edge.
+pub fn concave_hull(points: &[Location], max_edge: f64) -> Vec<Location> {
+    let Some(&first) = points.first() else {
+        return Vec::new();
+    };
+    let plane = Plane::new(first);
+    let flat: Vec<_> = points.iter().map(|&point| plane.project(point)).collect();
+
+    let hull = convex_hull(&flat);
+    if hull.len() < 3 {
+        return hull.into_iter().map(|i| points[i]).collect();
+    }
+
+    // a point twice would be dug in twice
+    let mut unique: Vec<usize> = (0..flat.len()).collect();
+    unique.sort_by(|&a, &b| flat[a].partial_cmp(&flat[b]).unwrap());
+    unique.dedup_by(|a, b| flat[*a] == flat[*b]);
+    let grid = PointGrid::new(&flat, &unique);
+
+    // the outline is a ring, each point on it pointing to the next
+    let mut next = vec![usize::MAX; flat.len()];
+    for (i, &a) in hull.iter().enumerate() {
+        next[a] = hull[(i + 1) % hull.len()];
+    }
+    let on_hull = |next: &[usize], p: usize| next[p] != usize::MAX;
+
+    // the edges still to dig into; digging one adds the two that replace it
+    let mut edges: Vec<(usize, usize)> = hull.iter().map(|&a| (a, next[a])).collect();
+    while let Some((a, b)) = edges.pop() {
+        let (pa, pb) = (flat[a], flat[b]);
+        if dist(pa, pb) <= max_edge {
+            continue;
+        }
+
+        // the points on the inside closest to the edge, looking further out
+        // until there are enough or there is nowhere left to look
+        let mut reach = grid.cell;
+        let candidates = loop {
+            let (low, high) = bounds(&[pa, pb]);
+            let (low, high) = (
+                (low.0 - reach, low.1 - reach),
+                (high.0 + reach, high.1 + reach),
+            );
+            let mut candidates: Vec<_> = grid
+                .in_box(low, high)
+                .filter(|&p| !on_hull(&next, p) && flat[p] != pa && flat[p] != pb)
+                .filter(|&p| cross(pa, pb, flat[p]) >= 0.0)
+                .map(|p| (p, segment_dist(flat[p], pa, pb)))
+                .collect();
+            let everywhere = grid.covers(low, high);
+            candidates.retain(|&(_, d)| d <= reach || everywhere);
+            if candidates.len() >= DIG_CANDIDATES || everywhere {
+                candidates.sort_by(|x, y| x.1.partial_cmp(&y.1).unwrap());
+                break candidates;
+            }
+            reach *= 2.0;
+        };
+
+        let accepted = candidates
+            .into_iter()
+            .take(DIG_CANDIDATES)
+            .map(|(p, _)| p)
+            .find(|&p| {
+                let pp = flat[p];
+                // cutting off the triangle a, p, b must not leave a point out
+                let (low, high) = bounds(&[pa, pb, pp]);
+                let empty = grid
+                    .in_box(low, high)
+                    .all(|q| !in_triangle(flat[q], pa, pb, pp));
+                if !empty {
+                    return false;
+                }
+                let mut on = b;
+                loop {
+                    let (c, d) = (flat[on], flat[next[on]]);
+                    if crosses(pa, pp, c, d) || crosses(pp, pb, c, d) {
+                        return false;
+                    }
+                    on = next[on];
+                    if on == b {
+                        return true;
+                    }
+                }
+            });
+
+        if let Some(p) = accepted {
+            next[a] = p;
+            next[p] = b;
+            edges.push((p, b));
+            edges.push((a, p));
+        }
+    }
+
+    let mut outline = vec![hull[0]];
+    while next[*outline.last().unwrap()] != hull[0] {
+        outline.push(next[*outline.last().unwrap()]);
+    }
+    outline.into_iter().map(|i| points[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        isochrone::{concave_hull, Isochrone},
+        osm_parser::{Location, OpenStreetMap},
+        params::SimpleParams,
+    };
+
+    /// whether `p` is inside `polygon` or within `tolerance` degrees of its
+    /// edge
+    fn contains(polygon: &[Location], p: Location, tolerance: f64) -> bool {
+        let mut inside = false;
+        for i in 0..polygon.len() {
+            let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+            let (dx, dy) = (b.x() - a.x(), b.y() - a.y());
+            let t = (((p.x() - a.x()) * dx + (p.y() - a.y()) * dy) / (dx * dx + dy * dy))
+                .clamp(0.0, 1.0);
+            let (fx, fy) = (a.x() + t * dx, a.y() + t * dy);
+            if (fx - p.x()).hypot(fy - p.y()) <= tolerance {
+                return true;
+            }
+            if (a.y() > p.y()) != (b.y() > p.y())
+                && p.x() < a.x() + (p.y() - a.y()) / (b.y() - a.y()) * dx
+            {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    #[test]
+    fn diamond_on_a_grid() {
+        // 11 by 11 blocks of 0.01 degrees on the equator
+        let map = OpenStreetMap::grid(11, 11, 0.01);
+        let step = Location(0.0, 0.0).dist_metres(Location(0.01, 0.0));
+        let isochrone = Isochrone::new(&map, 60, 3.5 * step, &SimpleParams);
+
+        // every node at most three blocks away
+        assert_eq!(25, isochrone.nodes().count());
+        for id in isochrone.nodes() {
+            let (x, y) = ((id % 11) as i32 - 5, (id / 11) as i32 - 5);
+            assert!(x.abs() + y.abs() <= 3);
+        }
+
+        // every road out of the outer ring is cut half way
+        assert_eq!(28, isochrone.boundary.len());
+        for cut in &isochrone.boundary {
+            assert!((cut.fraction - 0.5).abs() < 1e-3);
+        }
+
+        let polygon = isochrone.polygon(&map, 0.5 * step);
+        for id in isochrone.nodes() {
+            assert!(contains(&polygon, map.get(id).location, 1e-9));
+        }
+        for cut in &isochrone.boundary {
+            assert!(contains(&polygon, cut.location, 1e-9));
+        }
+        // the corners of the grid are well outside
+        assert!(!contains(&polygon, Location(0.01, 0.01), 1e-9));
+    }
+
+    #[test]
+    fn hull_follows_a_notch() {
+        // a U: two walls two points thick joined along the bottom
+        let mut points = Vec::new();
+        for i in 0..=10 {
+            let along = i as f64 * 0.001;
+            for across in [0.0, 0.001] {
+                points.push(Location(across, along));
+                points.push(Location(0.01 - across, along));
+                points.push(Location(along, across));
+            }
+        }
+        let step = Location(0.0, 0.0).dist_metres(Location(0.001, 0.0));
+
+        let hull = concave_hull(&points, 2.0 * step);
+        for &point in &points {
+            assert!(contains(&hull, point, 1e-9));
+        }
+        // the inside of the U is left out, unlike by the convex hull
+        assert!(!contains(&hull, Location(0.005, 0.008), 1e-9));
+        assert_eq!(4, concave_hull(&points, 100.0 * step).len());
+    }
+
+    #[test]
+    fn hull_of_many_points() {
+        use std::time::{Duration, Instant};
+
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // a disc about two kilometres across, scattered with points
+        let rng = &mut StdRng::seed_from_u64(18);
+        let points: Vec<_> = (0..20_000)
+            .map(|_| {
+                let (angle, r): (f64, f64) = (rng.gen_range(0.0, 6.3), rng.gen_range(0.0, 1.0));
+                let r = 0.01 * r.sqrt();
+                Location(r * angle.cos(), r * angle.sin())
+            })
+            .collect();
+
+        let started = Instant::now();
+        let hull = concave_hull(&points, 30.0);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        assert!(hull.len() > 100);
+        for &point in &points {
+            assert!(contains(&hull, point, 1e-9));
+        }
+    }
+}
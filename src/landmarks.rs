@@ -0,0 +1,435 @@
+use std::{
+    fs::File,
+    io,
+    io::{BufReader, BufWriter, Read, Write},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::Rng;
+
+use crate::{
+    dijkstra,
+    edge::Edge,
+    graph::{Direction, Graph},
+    map_file::{ChecksumReader, ChecksumWriter, MapFileError, Source},
+    params::Params,
+};
+
+/// The first bytes of a file written by `Landmarks::write`.
+pub const MAGIC: [u8; 4] = *b"RLMK";
+
+/// Version 2 records the map and costs the landmarks were picked with, and
+/// stores costs as `f32`.
+pub const VERSION: u16 = 2;
+
+/// How `Landmarks::new` picks landmarks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Selection {
+    /// each landmark is the node farthest from the ones picked before
+    Farthest,
+    /// Each landmark is at the end of the branch of a shortest path tree the
+    /// landmarks picked before bound worst (Goldberg and Werneck's "avoid").
+    /// Landmarks end up behind the places routes actually go through.
+    Avoid,
+}
+
+/// A few nodes with the cost between them and every node of the map, in both
+/// directions. By the triangle inequality, the cost from `v` to `t` is at
+/// least `d(l, t) - d(l, v)` and `d(v, l) - d(t, l)` for every landmark `l`,
+/// which is a much tighter bound than a straight line where roads go round
+/// lakes.
+///
+/// The costs are kept as `f32`, by node, the costs of every landmark for a
+/// node next to each other.
+pub struct Landmarks {
+    /// the map and costs they were picked with
+    source: Source,
+    nodes: Vec<u32>,
+    /// the cost from each landmark to every node, `f32::INFINITY` where there
+    /// is no path
+    from: Vec<f32>,
+    /// the cost from every node to each landmark
+    to: Vec<f32>,
+}
+
+impl Landmarks {
+    /// Picks `count` landmarks and works out their tables, with the costs of
+    /// `params`. Fewer are picked if the map runs out of useful ones.
+    #[allow(dead_code)]
+    pub fn new<G: Graph>(
+        map: &G,
+        params: &impl Params<G::Node>,
+        count: usize,
+        selection: Selection,
+    ) -> Landmarks {
+        let mut landmarks = Landmarks {
+            source: Source::of(map, params),
+            nodes: Vec::new(),
+            from: Vec::new(),
+            to: Vec::new(),
+        };
+
+        while landmarks.nodes.len() < count && map.node_count() > 0 {
+            let next = match selection {
+                Selection::Farthest => None,
+                Selection::Avoid => landmarks.avoid(map, params),
+            };
+            match next.or_else(|| landmarks.farthest(map, params)) {
+                Some(node) => landmarks.add(map, params, node),
+                None => break,
+            }
+        }
+
+        landmarks
+    }
+
+    fn add<G: Graph>(&mut self, map: &G, params: &impl Params<G::Node>, node: u32) {
+        let count = self.nodes.len();
+        let add = |table: &[f32], direction| -> Vec<f32> {
+            let dist = dijkstra::tree(map, node, direction, params, None).dist;
+            dist.iter()
+                .enumerate()
+                .flat_map(|(id, &dist)| {
+                    let row = &table[id * count..(id + 1) * count];
+                    row.iter().copied().chain(std::iter::once(dist as f32))
+                })
+                .collect()
+        };
+        self.from = add(&self.from, Direction::Forward);
+        self.to = add(&self.to, Direction::Backward);
+        self.nodes.push(node);
+    }
+
+    /// the costs of every landmark for the node `id`
+    fn row<'a>(&self, table: &'a [f32], id: u32) -> &'a [f32] {
+        let count = self.nodes.len();
+        &table[id as usize * count..(id as usize + 1) * count]
+    }
+
+    /// The node farthest from every landmark so far, or for the first one, the
+    /// node farthest from a random node.
+    fn farthest<G: Graph>(&self, map: &G, params: &impl Params<G::Node>) -> Option<u32> {
+        let dist: Vec<f64> = if self.nodes.is_empty() {
+            let start = rand::thread_rng().gen_range(0, map.node_count() as u32);
+            dijkstra::tree(map, start, Direction::Forward, params, None).dist
+        } else {
+            (0..map.node_count() as u32)
+                .map(|id| {
+                    self.row(&self.from, id)
+                        .iter()
+                        .zip(self.row(&self.to, id))
+                        .map(|(&from, &to)| from.min(to) as f64)
+                        .fold(f64::INFINITY, f64::min)
+                })
+                .collect()
+        };
+
+        // nodes no landmark is connected to are left alone, they are
+        // probably not part of the road network
+        (0..map.node_count() as u32)
+            .filter(|&id| dist[id as usize].is_finite() && dist[id as usize] > 0.0)
+            .max_by(|&a, &b| dist[a as usize].partial_cmp(&dist[b as usize]).unwrap())
+    }
+
+    /// The leaf at the end of the heaviest branch of a shortest path tree from
+    /// a random node, where a node weighs as much as the landmarks so far
+    /// underestimate the cost of getting to it. Branches which already have a
+    /// landmark in them weigh nothing.
+    fn avoid<G: Graph>(&self, map: &G, params: &impl Params<G::Node>) -> Option<u32> {
+        let node_count = map.node_count();
+        let root = rand::thread_rng().gen_range(0, node_count as u32);
+        let tree = dijkstra::tree(map, root, Direction::Forward, params, None);
+
+        let mut size: Vec<f64> = (0..node_count as u32)
+            .map(|id| match tree.dist[id as usize] {
+                dist if dist.is_finite() => dist - self.lower_bound(root, id),
+                _ => 0.0,
+            })
+            .collect();
+        let mut covered = vec![false; node_count];
+        for &node in &self.nodes {
+            covered[node as usize] = true;
+        }
+
+        // children before their parents
+        let mut order: Vec<u32> = tree.reached_nodes().collect();
+        order.sort_by(|&a, &b| {
+            tree.dist[b as usize]
+                .partial_cmp(&tree.dist[a as usize])
+                .unwrap()
+        });
+
+        let mut children = vec![Vec::new(); node_count];
+        for &id in &order {
+            if let Some(parent) = tree.prev[id as usize] {
+                children[parent as usize].push(id);
+                if covered[id as usize] {
+                    covered[parent as usize] = true;
+                } else {
+                    size[parent as usize] += size[id as usize];
+                }
+            }
+        }
+
+        let weight = |id: u32| match covered[id as usize] {
+            true => 0.0,
+            false => size[id as usize],
+        };
+
+        let mut on = root;
+        while let Some(&heaviest) = children[on as usize]
+            .iter()
+            .max_by(|&&a, &&b| weight(a).partial_cmp(&weight(b)).unwrap())
+        {
+            if weight(heaviest) <= 0.0 {
+                break;
+            }
+            on = heaviest;
+        }
+
+        Some(on).filter(|&on| on != root && !self.nodes.contains(&on))
+    }
+
+    /// the landmarks, in the order they were picked
+    #[allow(dead_code)]
+    pub fn nodes(&self) -> &[u32] {
+        &self.nodes
+    }
+
+    /// A lower bound of the cost from `on` to `goal`.
+    pub fn lower_bound(&self, on: u32, goal: u32) -> f64 {
+        // less what rounding both costs to f32 could have added
+        let bound = |far: f32, near: f32| match far.is_finite() && near.is_finite() {
+            true => {
+                let (far, near) = (far as f64, near as f64);
+                far - near - (far + near) * f32::EPSILON as f64
+            }
+            false => 0.0,
+        };
+
+        let from = self
+            .row(&self.from, on)
+            .iter()
+            .zip(self.row(&self.from, goal));
+        let to = self.row(&self.to, on).iter().zip(self.row(&self.to, goal));
+        from.zip(to)
+            .map(|((&from_on, &from_goal), (&to_on, &to_goal))| {
+                bound(from_goal, from_on).max(bound(to_on, to_goal))
+            })
+            .fold(0.0, f64::max)
+    }
+
+    /// the file the landmarks of the map file `name` are saved in
+    pub fn file(name: &str) -> String {
+        format!("{}.landmarks", name)
+    }
+
+    /// Saves the landmarks next to the map file `name`.
+    #[allow(dead_code)]
+    pub fn save(&self, name: &str) -> io::Result<()> {
+        let file = File::create(Landmarks::file(name))?;
+        self.write(BufWriter::new(file))
+    }
+
+    /// Reads the landmarks saved next to the map file `name`, which have to
+    /// have been picked with `source`.
+    #[allow(dead_code)]
+    pub fn open(name: &str, source: &Source) -> Result<Landmarks, MapFileError> {
+        let file = File::open(Landmarks::file(name))?;
+        Landmarks::read(BufReader::new(file), source)
+    }
+
+    pub fn write(&self, writer: impl Write) -> io::Result<()> {
+        let mut writer = ChecksumWriter::new(writer);
+
+        writer.write_all(&MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
+        self.source.write(&mut writer)?;
+        writer.write_u32::<BigEndian>(self.nodes.len() as u32)?;
+
+        for &node in &self.nodes {
+            writer.write_u32::<BigEndian>(node)?;
+        }
+        for &dist in self.from.iter().chain(&self.to) {
+            writer.write_f32::<BigEndian>(dist)?;
+        }
+
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Reads landmarks written by `write`. Landmarks picked on another map or
+    /// with other costs than `source` are a `MapFileError::Mismatch`.
+    pub fn read(reader: impl Read, source: &Source) -> Result<Landmarks, MapFileError> {
+        let mut reader = ChecksumReader::new(reader);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(MapFileError::BadMagic);
+        }
+
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(MapFileError::UnsupportedVersion(version));
+        }
+
+        let source = Source::read(&mut reader, source)?;
+        let count = reader.read_u32::<BigEndian>()?;
+
+        let mut nodes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let node = reader.read_u32::<BigEndian>()?;
+            if node >= source.node_count {
+                return Err(MapFileError::Corrupt);
+            }
+            nodes.push(node);
+        }
+
+        let size = count as usize * source.node_count as usize;
+        let mut tables = [Vec::new(), Vec::new()];
+        for table in tables.iter_mut() {
+            table.reserve_exact(size);
+            for _ in 0..size {
+                let dist = reader.read_f32::<BigEndian>()?;
+                if dist.is_nan() || dist < 0.0 {
+                    return Err(MapFileError::Corrupt);
+                }
+                table.push(dist);
+            }
+        }
+
+        reader.finish(true)?;
+        let [from, to] = tables;
+        Ok(Landmarks {
+            source,
+            nodes,
+            from,
+            to,
+        })
+    }
+}
+
+/// `params` with a heuristic sharpened by landmarks, which have to have been
+/// picked with the same costs. Works with both `a_star` and `a_star_bi`.
+pub struct AltParams<P> {
+    landmarks: Landmarks,
+    params: P,
+}
+
+impl<P> AltParams<P> {
+    #[allow(dead_code)]
+    pub fn new(landmarks: Landmarks, params: P) -> AltParams<P> {
+        AltParams { landmarks, params }
+    }
+
+    #[allow(dead_code)]
+    pub fn landmarks(&self) -> &Landmarks {
+        &self.landmarks
+    }
+}
+
+impl<T, P: Params<T>> Params<T> for AltParams<P> {
+    fn heuristic(&self, on: &T, goal: &T) -> f64 {
+        self.params.heuristic(on, goal)
+    }
+
+    fn heuristic_between(&self, on_id: u32, on: &T, goal_id: u32, goal: &T) -> f64 {
+        let own = self.params.heuristic_between(on_id, on, goal_id, goal);
+        own.max(self.landmarks.lower_bound(on_id, goal_id))
+    }
+
+    fn neighbor_dist(&self, on: &T, next: &T, edge: &Edge) -> f64 {
+        self.params.neighbor_dist(on, next, edge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        a_star,
+        bidirectional::bi_astar::a_star_bi,
+        dijkstra,
+        graph::Direction,
+        landmarks::{AltParams, Landmarks, Selection},
+        map_file::{MapFileError, Source},
+        osm_parser::OpenStreetMap,
+        params::{SimpleParams, TravelTimeParams},
+    };
+
+    #[test]
+    fn bounds_are_admissible() {
+        let map = OpenStreetMap::random_roads(80, 16);
+
+        for selection in [Selection::Farthest, Selection::Avoid] {
+            let landmarks = Landmarks::new(&map, &SimpleParams, 4, selection);
+            let mut nodes = landmarks.nodes().to_vec();
+            nodes.sort_unstable();
+            nodes.dedup();
+            assert_eq!(4, nodes.len());
+
+            for source in (0..80).step_by(9) {
+                let tree = dijkstra::tree(&map, source, Direction::Forward, &SimpleParams, None);
+                for goal in tree.reached_nodes() {
+                    let bound = landmarks.lower_bound(source, goal);
+                    assert!(bound <= tree.dist[goal as usize] + 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn searches_stay_optimal() {
+        let map = OpenStreetMap::random_roads(80, 17);
+        let landmarks = Landmarks::new(&map, &SimpleParams, 4, Selection::Avoid);
+        let params = AltParams::new(landmarks, SimpleParams);
+
+        for source in (0..80).step_by(7) {
+            let tree = dijkstra::tree(&map, source, Direction::Forward, &SimpleParams, None);
+            for goal in (0..80).step_by(3) {
+                let one_way = a_star::path_with(&map, source, goal, &params);
+                let both_ways = a_star_bi(&map, source, goal, &params);
+                assert_eq!(tree.reached(goal), one_way.is_some());
+                assert_eq!(tree.reached(goal), both_ways.is_some());
+
+                if let (Some(one_way), Some(both_ways)) = (one_way, both_ways) {
+                    let dist = tree.dist[goal as usize];
+                    assert!((one_way.length_metres() - dist).abs() < 0.01);
+                    assert!((both_ways.length_metres() - dist).abs() < 0.01);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn write_read() {
+        let map = OpenStreetMap::random_roads(40, 18);
+        let landmarks = Landmarks::new(&map, &SimpleParams, 3, Selection::Farthest);
+        let mut bytes = Vec::new();
+        landmarks.write(&mut bytes).unwrap();
+
+        let source = Source::of(&map, &SimpleParams);
+        let read = Landmarks::read(&bytes[..], &source).unwrap();
+        assert_eq!(landmarks.nodes(), read.nodes());
+        for (on, goal) in [(0, 39), (12, 5), (20, 21)] {
+            assert_eq!(landmarks.lower_bound(on, goal), read.lower_bound(on, goal));
+        }
+
+        // landmarks for another map of the same size, or other costs, are
+        // refused
+        let other = OpenStreetMap::random_roads(40, 19);
+        assert!(matches!(
+            Landmarks::read(&bytes[..], &Source::of(&other, &SimpleParams)),
+            Err(MapFileError::Mismatch)
+        ));
+        assert!(matches!(
+            Landmarks::read(&bytes[..], &Source::of(&map, &TravelTimeParams::new(&map))),
+            Err(MapFileError::Mismatch)
+        ));
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(Landmarks::read(&bytes[..], &source).is_err());
+    }
+}
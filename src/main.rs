@@ -1,14 +1,13 @@
 #![feature(ptr_internals)]
 #![feature(allocator_api)]
+#![allow(internal_features)]
 
 use std::time::SystemTime;
 
 use palette::{Hsl, Srgb};
 use plotters::{
     drawing::IntoDrawingArea,
-    prelude::{
-        BitMapBackend, ChartBuilder, Color, IntoFont, LineSeries, RGBColor, BLACK, RED, WHITE,
-    },
+    prelude::{BitMapBackend, ChartBuilder, Color, IntoFont, LineSeries, RGBColor, WHITE},
 };
 use statrs::statistics::Statistics;
 
@@ -21,14 +20,35 @@ use crate::{
 };
 
 mod a_star;
+mod alternatives;
 mod bidirectional;
 mod bounds;
 mod compact_array;
+mod contraction;
+mod csr;
+mod dijkstra;
+mod edge;
+mod export;
+mod graph;
+mod isochrone;
+mod landmarks;
+mod map_file;
+mod map_match;
+mod mapped;
+mod matrix;
 mod osm_parser;
 mod params;
+mod polyline;
+mod profile;
+mod quadtree;
+mod snap;
+mod tsp;
+mod turn_restriction;
+mod via;
+mod vrp;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // let map = OpenStreetMap::parse("minnesota-latest.osm.pbf")?;
+    // let map = OpenStreetMap::parse("minnesota-latest.osm.pbf", Profile::Car)?;
     // let map = map.trim(); // to prevent unsolvable paths
     // map.save("map.save")?;
     let map = OpenStreetMap::read_custom_file("map.save")?;
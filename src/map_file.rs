@@ -0,0 +1,405 @@
+use std::{
+    error::Error,
+    fmt,
+    fmt::{Display, Formatter},
+    io,
+    io::{Read, Write},
+};
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+
+use crate::{
+    bounds::Bounds,
+    graph::{Direction, Graph},
+    osm_parser::Location,
+    params::Params,
+    profile::Profile,
+};
+
+/// The first bytes of every map file written by `OpenStreetMap::save`.
+pub const MAGIC: [u8; 4] = *b"ROSM";
+
+/// The version `OpenStreetMap::save` writes. Version 0 is the layout from
+/// before there was a header: a big endian node count, then for each node its
+/// x and y, its neighbour count and the ids of its neighbours, with every road
+/// stored both ways.
+pub const VERSION: u16 = 1;
+
+/// Each node record has its OSM id.
+pub const FLAG_IDS: u8 = 1;
+/// Each edge record has its length, highway class, speed limit and way id.
+/// Without them an edge is only the id of the node it points at.
+pub const FLAG_TAGS: u8 = 1 << 1;
+/// Edges are directed (one-way streets are respected).
+pub const FLAG_DIRECTED: u8 = 1 << 2;
+
+const KNOWN_FLAGS: u8 = FLAG_IDS | FLAG_TAGS | FLAG_DIRECTED;
+
+#[derive(Debug)]
+pub enum MapFileError {
+    Io(io::Error),
+    /// the file ended before everything was read
+    Truncated,
+    /// the file is not a map file
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// the file was written with features this version does not know about
+    UnknownFlags(u8),
+    UnknownProfile(u8),
+    /// there is data after the end of the map
+    TrailingData,
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// the file has the right size but its contents do not make sense, e.g.
+    /// an edge to a node which does not exist
+    Corrupt,
+    /// the file was built from another map, or with other `Params`
+    Mismatch,
+}
+
+impl Display for MapFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MapFileError::Io(err) => write!(f, "could not read map file: {}", err),
+            MapFileError::Truncated => write!(f, "map file is truncated"),
+            MapFileError::BadMagic => write!(f, "not a map file"),
+            MapFileError::UnsupportedVersion(version) => {
+                write!(f, "unsupported map file version {}", version)
+            }
+            MapFileError::UnknownFlags(flags) => write!(f, "unknown map file flags {:#b}", flags),
+            MapFileError::UnknownProfile(profile) => write!(f, "unknown profile {}", profile),
+            MapFileError::TrailingData => write!(f, "unexpected data after the end of the map"),
+            MapFileError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "map file checksum is {:#010x} but should be {:#010x}",
+                actual, expected
+            ),
+            MapFileError::Corrupt => write!(f, "map file is corrupt"),
+            MapFileError::Mismatch => {
+                write!(f, "file was built for another map or with other params")
+            }
+        }
+    }
+}
+
+impl Error for MapFileError {}
+
+impl From<io::Error> for MapFileError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => MapFileError::Truncated,
+            _ => MapFileError::Io(err),
+        }
+    }
+}
+
+/// Everything in a map file before the node records.
+#[derive(Debug)]
+pub struct MapFileHeader {
+    pub version: u16,
+    pub flags: u8,
+    pub profile: Profile,
+    /// the bounds of the nodes in the file. Version 0 files do not record them.
+    pub bounds: Option<Bounds>,
+    pub node_count: u32,
+}
+
+impl MapFileHeader {
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_u16::<BigEndian>(self.version)?;
+        writer.write_u8(self.flags)?;
+        writer.write_u8(self.profile as u8)?;
+
+        let bounds = self
+            .bounds
+            .as_ref()
+            .expect("a header needs bounds to be written");
+        for value in [
+            bounds.from.x(),
+            bounds.from.y(),
+            bounds.to.x(),
+            bounds.to.y(),
+        ] {
+            writer.write_f64::<BigEndian>(value)?;
+        }
+
+        writer.write_u32::<BigEndian>(self.node_count)
+    }
+
+    /// Reads the header of a file which starts with `MAGIC`.
+    pub fn read(reader: &mut impl Read) -> Result<MapFileHeader, MapFileError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(MapFileError::BadMagic);
+        }
+
+        let version = reader.read_u16::<BigEndian>()?;
+        if version == 0 || version > VERSION {
+            return Err(MapFileError::UnsupportedVersion(version));
+        }
+
+        let flags = reader.read_u8()?;
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(MapFileError::UnknownFlags(flags));
+        }
+
+        let profile = reader.read_u8()?;
+        let profile = Profile::from_u8(profile).ok_or(MapFileError::UnknownProfile(profile))?;
+
+        let mut bounds = [0.0; 4];
+        for value in bounds.iter_mut() {
+            *value = reader.read_f64::<BigEndian>()?;
+        }
+        let [min_x, min_y, max_x, max_y] = bounds;
+
+        Ok(MapFileHeader {
+            version,
+            flags,
+            profile,
+            bounds: Some(Bounds {
+                from: Location(min_x, min_y),
+                to: Location(max_x, max_y),
+            }),
+            node_count: reader.read_u32::<BigEndian>()?,
+        })
+    }
+}
+
+/// A layout from before the header.
+struct Legacy {
+    version: u16,
+    flags: u8,
+    /// whether the file starts with the profile byte, before the node count
+    profile: bool,
+}
+
+/// The layouts a file without a header can have, in the order they are tried.
+const LEGACY: [Legacy; 4] = [
+    Legacy {
+        version: 0,
+        flags: 0,
+        profile: false,
+    },
+    // edges with their tags, before profiles
+    Legacy {
+        version: 0,
+        flags: FLAG_TAGS | FLAG_DIRECTED,
+        profile: false,
+    },
+    // a profile byte and edges with their tags, before OSM ids were kept
+    Legacy {
+        version: 0,
+        flags: FLAG_TAGS | FLAG_DIRECTED,
+        profile: true,
+    },
+    // the last before the header, with the OSM id of each node
+    Legacy {
+        version: 0,
+        flags: FLAG_IDS | FLAG_TAGS | FLAG_DIRECTED,
+        profile: true,
+    },
+];
+
+impl Legacy {
+    /// Where the node records start, if `bytes` is a whole file in this layout:
+    /// every record fits, every edge goes to a node in the file and nothing is
+    /// left over.
+    fn start(&self, bytes: &[u8]) -> Option<usize> {
+        if self.profile {
+            Profile::from_u8(*bytes.first()?)?;
+        }
+        let start = 4 + self.profile as usize;
+        let node_count = BigEndian::read_u32(bytes.get(start - 4..start)?);
+
+        let node_size = if self.flags & FLAG_IDS != 0 { 24 } else { 16 };
+        let edge_size = if self.flags & FLAG_TAGS != 0 { 18 } else { 4 };
+        let mut at = start;
+        for _ in 0..node_count {
+            at += node_size;
+            let edge_count = *bytes.get(at)? as usize;
+            at += 1;
+            for _ in 0..edge_count {
+                let node = BigEndian::read_u32(bytes.get(at..at + 4)?);
+                if node >= node_count {
+                    return None;
+                }
+                at += edge_size;
+            }
+        }
+
+        (at == bytes.len()).then_some(start)
+    }
+}
+
+impl MapFileHeader {
+    /// Works out the layout of `bytes`, a whole file without a header, and
+    /// where its node records start. The layouts are told apart by which one
+    /// reads to exactly the end of the file.
+    pub fn read_legacy(bytes: &[u8]) -> Result<(MapFileHeader, usize), MapFileError> {
+        LEGACY
+            .iter()
+            .find_map(|legacy| {
+                let start = legacy.start(bytes)?;
+                let header = MapFileHeader {
+                    version: legacy.version,
+                    flags: legacy.flags,
+                    profile: match legacy.profile {
+                        true => Profile::from_u8(bytes[0]).unwrap(),
+                        false => Profile::Car,
+                    },
+                    bounds: None,
+                    node_count: BigEndian::read_u32(&bytes[start - 4..start]),
+                };
+                Some((header, start))
+            })
+            .ok_or(MapFileError::BadMagic)
+    }
+}
+
+/// What a file built from the costs of a map, like a contraction hierarchy or
+/// landmarks, was built from. It is only any use with the same map and
+/// `Params`, and wrong rather than merely slow with others, so it records this
+/// and refuses to load for anything else.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub node_count: u32,
+    pub profile: Profile,
+    /// the CRC-32 of every edge and what it costs
+    pub checksum: u32,
+}
+
+impl Source {
+    pub fn of<G: Graph>(map: &G, params: &impl Params<G::Node>) -> Source {
+        let mut hasher = Hasher::new();
+        for id in 0..map.node_count() as u32 {
+            for edge in map.edges(id, Direction::Forward) {
+                let cost = params.neighbor_dist(map.get(id), map.get(edge.node), edge);
+                hasher.update(&id.to_be_bytes());
+                hasher.update(&edge.node.to_be_bytes());
+                hasher.update(&cost.to_bits().to_be_bytes());
+            }
+        }
+
+        Source {
+            node_count: map.node_count() as u32,
+            profile: map.profile(),
+            checksum: hasher.finalize(),
+        }
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_u32::<BigEndian>(self.node_count)?;
+        writer.write_u8(self.profile as u8)?;
+        writer.write_u32::<BigEndian>(self.checksum)
+    }
+
+    /// Reads what a file was built from, which has to be `expected`.
+    pub fn read(reader: &mut impl Read, expected: &Source) -> Result<Source, MapFileError> {
+        let node_count = reader.read_u32::<BigEndian>()?;
+        let profile = reader.read_u8()?;
+        let source = Source {
+            node_count,
+            profile: Profile::from_u8(profile).ok_or(MapFileError::UnknownProfile(profile))?,
+            checksum: reader.read_u32::<BigEndian>()?,
+        };
+        match source == *expected {
+            true => Ok(source),
+            false => Err(MapFileError::Mismatch),
+        }
+    }
+}
+
+/// Computes the CRC-32 of everything written through it.
+pub struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+
+    /// the checksum of everything written so far
+    pub fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+
+    /// Appends the checksum of everything written so far.
+    pub fn finish(mut self) -> io::Result<W> {
+        let checksum = self.hasher.clone().finalize();
+        self.inner.write_u32::<BigEndian>(checksum)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Computes the CRC-32 of everything read through it.
+pub struct ChecksumReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R) -> ChecksumReader<R> {
+        ChecksumReader {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+
+    /// the checksum of everything read so far
+    pub fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+
+    /// Checks that the file ends correctly: with a matching checksum, or right
+    /// away for files which have none (version 0 maps).
+    pub fn finish(mut self, has_checksum: bool) -> Result<(), MapFileError> {
+        if has_checksum {
+            let actual = self.hasher.clone().finalize();
+            let expected = self.inner.read_u32::<BigEndian>()?;
+            if actual != expected {
+                return Err(MapFileError::ChecksumMismatch { expected, actual });
+            }
+        }
+
+        let mut rest = [0];
+        match self.inner.read(&mut rest)? {
+            0 => Ok(()),
+            _ => Err(MapFileError::TrailingData),
+        }
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
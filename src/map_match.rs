@@ -0,0 +1,481 @@
+use std::{
+    error::Error,
+    fmt,
+    fmt::{Display, Formatter},
+    fs::File,
+    io,
+    io::Read,
+};
+
+use crate::{
+    a_star::Path,
+    dijkstra,
+    graph::{Direction, Graph},
+    osm_parser::Location,
+    params::SimpleParams,
+    snap,
+    snap::{SegmentIndex, Snap},
+};
+
+/// How closely a trace is expected to follow the roads.
+#[derive(Debug, Copy, Clone)]
+pub struct MatchOptions {
+    /// how far from a fix in metres to look for roads it could be on
+    pub radius: f64,
+    /// the most roads to consider for each fix, closest first
+    pub candidates: usize,
+    /// the standard deviation of GPS error in metres
+    pub sigma: f64,
+    /// how many metres the route between two fixes is expected to be longer
+    /// than the straight line between them, on average
+    pub beta: f64,
+    /// how many metres longer than that straight line, give or take the
+    /// radius at each end, a route between two fixes can be. Longer ones are
+    /// not looked for.
+    pub max_detour: f64,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        MatchOptions {
+            radius: 50.0,
+            candidates: 8,
+            sigma: 5.0,
+            beta: 5.0,
+            max_detour: 200.0,
+        }
+    }
+}
+
+/// A trace matched onto the map.
+#[allow(dead_code)]
+pub struct Matched<'a, G: Graph> {
+    /// the nodes driven through, one path for each part of the trace in which
+    /// the roads are connected, starting and ending with the ends of the
+    /// first and last roads matched in it
+    pub paths: Vec<Path<'a, G>>,
+    /// where each fix was matched to, `None` for a fix with no road within
+    /// the radius
+    pub snaps: Vec<Option<Snap>>,
+}
+
+/// The log probability of a fix `dist` metres from the road being on it, up
+/// to a constant.
+fn emission(dist: f64, options: &MatchOptions) -> f64 {
+    -0.5 * (dist / options.sigma).powi(2)
+}
+
+/// The log probability of driving `route` metres between fixes `straight`
+/// metres apart, up to a constant.
+fn transition(route: f64, straight: f64, options: &MatchOptions) -> f64 {
+    -(route - straight).abs() / options.beta
+}
+
+/// The cheapest route from `start` to each of `goals` within `max_dist`, and
+/// the nodes on it, from one search.
+fn routes<G: Graph>(
+    map: &G,
+    start: &Snap,
+    goals: &[Snap],
+    max_dist: f64,
+) -> Vec<Option<(f64, Vec<u32>)>> {
+    let departures = start.departures(map, &SimpleParams);
+    let tree = dijkstra::tree_from(
+        map,
+        &departures,
+        Direction::Forward,
+        &SimpleParams,
+        Some(max_dist),
+    );
+
+    goals
+        .iter()
+        .map(|goal| {
+            let through = goal
+                .arrivals(map, &SimpleParams)
+                .into_iter()
+                .filter_map(|(node, left)| tree.dist_to(node).map(|dist| (node, dist + left)))
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            let direct = snap::direct(map, start, goal, &SimpleParams);
+
+            let (node, cost) = match (through, direct) {
+                (Some((node, cost)), Some(direct)) if cost <= direct => (node, cost),
+                (_, Some(direct)) => return Some((direct, Vec::new())),
+                (through, None) => through?,
+            };
+            Some((cost, tree.path_to(node)?.ids))
+        })
+        .collect()
+}
+
+/// Matches the GPS fixes of a trace onto the roads it most likely drove, with
+/// a hidden Markov model (Newson and Krumm).
+///
+/// The hidden states are the points on roads within `options.radius` of each
+/// fix. Being on a road is more likely the closer it is to the fix, and going
+/// from one to the next more likely the closer the route between them is to
+/// the straight line between the fixes. One search from each candidate finds
+/// the routes to all of the next ones, as far as `options.max_detour` allows.
+/// Viterbi decoding picks the most likely sequence.
+///
+/// Where no road near one fix is connected to a road near the next, the trace
+/// is split and each part matched on its own. `None` if no fix is near a
+/// road.
+#[allow(dead_code)]
+pub fn match_trace<'a, G: Graph>(
+    map: &'a G,
+    index: &SegmentIndex<'a, G>,
+    fixes: &[Location],
+    options: &MatchOptions,
+) -> Option<Matched<'a, G>> {
+    let candidates: Vec<Vec<Snap>> = fixes
+        .iter()
+        .map(|&fix| {
+            let mut within = index.snap_within(fix, options.radius);
+            within.truncate(options.candidates);
+            within
+        })
+        .collect();
+    let steps: Vec<usize> = (0..fixes.len())
+        .filter(|&fix| !candidates[fix].is_empty())
+        .collect();
+    let (&first, rest) = steps.split_first()?;
+
+    let mut paths = Vec::new();
+    let mut snaps = vec![None; fixes.len()];
+    let emissions = |fix: usize| -> Vec<f64> {
+        candidates[fix]
+            .iter()
+            .map(|snap| emission(snap.dist, options))
+            .collect()
+    };
+
+    let mut part = vec![first];
+    let mut scores = emissions(first);
+    // for each step of the part after the first, each candidate's best
+    // predecessor and the nodes on the way from it
+    let mut back: Vec<Vec<(usize, Vec<u32>)>> = Vec::new();
+
+    for &fix in rest {
+        let previous = *part.last().unwrap();
+        let straight = fixes[previous].dist_metres(fixes[fix]);
+        let max_dist = straight + 2.0 * options.radius + options.max_detour;
+
+        let mut next_scores = vec![f64::NEG_INFINITY; candidates[fix].len()];
+        let mut pointers = vec![(0, Vec::new()); candidates[fix].len()];
+        for (from, from_snap) in candidates[previous].iter().enumerate() {
+            let found = routes(map, from_snap, &candidates[fix], max_dist);
+            for (to, route) in found.into_iter().enumerate() {
+                let Some((cost, ids)) = route else {
+                    continue;
+                };
+                let score = scores[from] + transition(cost, straight, options);
+                if score > next_scores[to] {
+                    next_scores[to] = score;
+                    pointers[to] = (from, ids);
+                }
+            }
+        }
+
+        if next_scores.iter().all(|score| score.is_infinite()) {
+            // start again from this fix
+            paths.push(decode(map, &candidates, &part, &scores, &back, &mut snaps));
+            part = vec![fix];
+            scores = emissions(fix);
+            back.clear();
+            continue;
+        }
+
+        for (score, snap) in next_scores.iter_mut().zip(&candidates[fix]) {
+            *score += emission(snap.dist, options);
+        }
+        part.push(fix);
+        scores = next_scores;
+        back.push(pointers);
+    }
+    paths.push(decode(map, &candidates, &part, &scores, &back, &mut snaps));
+
+    Some(Matched { paths, snaps })
+}
+
+/// The path of one part of a trace, following the pointers in `back` from its
+/// most likely last candidate, and where each of its fixes was matched to.
+fn decode<'a, G: Graph>(
+    map: &'a G,
+    candidates: &[Vec<Snap>],
+    part: &[usize],
+    scores: &[f64],
+    back: &[Vec<(usize, Vec<u32>)>],
+    snaps: &mut [Option<Snap>],
+) -> Path<'a, G> {
+    let mut chosen = (0..scores.len())
+        .max_by(|&a, &b| scores[a].total_cmp(&scores[b]))
+        .unwrap();
+    let mut picks = vec![chosen];
+    let mut routes = Vec::with_capacity(back.len());
+    for pointers in back.iter().rev() {
+        let (from, ids) = &pointers[chosen];
+        routes.push(ids);
+        chosen = *from;
+        picks.push(chosen);
+    }
+    picks.reverse();
+    routes.reverse();
+
+    for (&fix, &pick) in part.iter().zip(&picks) {
+        snaps[fix] = Some(candidates[fix][pick]);
+    }
+
+    let (start, end) = (
+        candidates[part[0]][picks[0]],
+        candidates[*part.last().unwrap()][*picks.last().unwrap()],
+    );
+    let mut ids: Vec<u32> = Vec::new();
+    for &id in routes.into_iter().flatten() {
+        if ids.last() != Some(&id) {
+            ids.push(id);
+        }
+    }
+    // the far end of the roads the part starts and ends on
+    match ids.first() {
+        None => ids = vec![start.from, start.to],
+        Some(&id) => ids.insert(0, if id == start.to { start.from } else { start.to }),
+    }
+    if end.from != start.from || end.to != start.to || ids.len() > 2 {
+        let last = *ids.last().unwrap();
+        ids.push(if last == end.from { end.to } else { end.from });
+    }
+
+    Path {
+        ids,
+        parent_map: map,
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    Csv(csv::Error),
+    /// a CSV file without a latitude or longitude column
+    MissingColumn(&'static str),
+    /// a coordinate which is not a number
+    BadCoordinate(String),
+    /// neither `.gpx` nor `.csv`
+    UnknownFormat,
+}
+
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Io(err) => write!(f, "could not read trace: {}", err),
+            TraceError::Csv(err) => write!(f, "could not read trace: {}", err),
+            TraceError::MissingColumn(column) => write!(f, "trace has no {} column", column),
+            TraceError::BadCoordinate(value) => write!(f, "bad coordinate {:?}", value),
+            TraceError::UnknownFormat => write!(f, "trace is neither GPX nor CSV"),
+        }
+    }
+}
+
+impl Error for TraceError {}
+
+impl From<io::Error> for TraceError {
+    fn from(err: io::Error) -> Self {
+        TraceError::Io(err)
+    }
+}
+
+impl From<csv::Error> for TraceError {
+    fn from(err: csv::Error) -> Self {
+        TraceError::Csv(err)
+    }
+}
+
+fn coordinate(value: &str) -> Result<f64, TraceError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| TraceError::BadCoordinate(value.to_string()))
+}
+
+/// The fixes of a CSV file with a header row naming a `lat` or `latitude` and
+/// a `lon`, `lng` or `longitude` column, in any case. Other columns are
+/// ignored.
+#[allow(dead_code)]
+pub fn read_csv(reader: impl Read) -> Result<Vec<Location>, TraceError> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let column = |names: &[&str], name| {
+        headers
+            .iter()
+            .position(|header| names.contains(&header.trim().to_lowercase().as_str()))
+            .ok_or(TraceError::MissingColumn(name))
+    };
+    let lat = column(&["lat", "latitude"], "latitude")?;
+    let lon = column(&["lon", "lng", "long", "longitude"], "longitude")?;
+
+    reader
+        .records()
+        .map(|record| {
+            let record = record?;
+            let get = |i| coordinate(record.get(i).unwrap_or(""));
+            Ok(Location(get(lon)?, get(lat)?))
+        })
+        .collect()
+}
+
+/// the value of `name="..."` or `name='...'` in the attributes of a tag
+fn attribute<'t>(tag: &'t str, name: &str) -> Option<&'t str> {
+    let mut rest = tag;
+    while let Some(at) = rest.find(name) {
+        let before = rest[..at].chars().last();
+        let after = rest[at + name.len()..].trim_start();
+        rest = &rest[at + name.len()..];
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        let Some(value) = after.strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+    None
+}
+
+/// The track points of a GPX file, every track and segment in order. This
+/// only looks at the `lat` and `lon` of each `trkpt`, so needs no XML parser.
+#[allow(dead_code)]
+pub fn read_gpx(mut reader: impl Read) -> Result<Vec<Location>, TraceError> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+
+    let mut fixes = Vec::new();
+    let mut rest = text.as_str();
+    while let Some(at) = rest.find("<trkpt") {
+        rest = &rest[at + "<trkpt".len()..];
+        let tag = &rest[..rest.find('>').unwrap_or(rest.len())];
+        let lat =
+            attribute(tag, "lat").ok_or_else(|| TraceError::BadCoordinate(tag.to_string()))?;
+        let lon =
+            attribute(tag, "lon").ok_or_else(|| TraceError::BadCoordinate(tag.to_string()))?;
+        fixes.push(Location(coordinate(lon)?, coordinate(lat)?));
+    }
+    Ok(fixes)
+}
+
+/// `read_gpx` or `read_csv` depending on the extension of `name`.
+#[allow(dead_code)]
+pub fn read_trace(name: &str) -> Result<Vec<Location>, TraceError> {
+    let lower = name.to_lowercase();
+    if lower.ends_with(".gpx") {
+        read_gpx(File::open(name)?)
+    } else if lower.ends_with(".csv") {
+        read_csv(File::open(name)?)
+    } else {
+        Err(TraceError::UnknownFormat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        map_match::{match_trace, read_csv, read_gpx, MatchOptions, TraceError},
+        osm_parser::{Location, OpenStreetMap},
+        snap::SegmentIndex,
+    };
+
+    #[test]
+    fn follows_a_noisy_trace() {
+        // 6 by 6 blocks about 111m long
+        let map = OpenStreetMap::grid(6, 6, 0.001);
+        let index = SegmentIndex::new(&map);
+
+        // along the bottom to the fourth column, then up it, with fixes every
+        // 0.0004 degrees wobbling up to 15m off the road
+        let mut fixes = Vec::new();
+        for step in 0..=7 {
+            let wobble = if step % 2 == 0 { 0.00012 } else { -0.00008 };
+            fixes.push(Location(0.0002 + step as f64 * 0.0004, wobble));
+        }
+        for step in 1..=7 {
+            let wobble = if step % 2 == 0 { 0.00013 } else { -0.0001 };
+            fixes.push(Location(0.003 + wobble, step as f64 * 0.0004));
+        }
+        // a fix nowhere near a road
+        fixes.insert(5, Location(0.02, 0.02));
+
+        let matched = match_trace(&map, &index, &fixes, &MatchOptions::default()).unwrap();
+        assert_eq!(vec![0, 1, 2, 3, 9, 15, 21], matched.paths[0].ids);
+        assert!(matched.snaps[5].is_none());
+        for snap in matched.snaps.iter().flatten() {
+            assert!(snap.dist < 16.0);
+        }
+
+        // no fix near any road
+        let lost = [Location(1.0, 1.0), Location(1.001, 1.0)];
+        assert!(match_trace(&map, &index, &lost, &MatchOptions::default()).is_none());
+    }
+
+    #[test]
+    fn stays_on_one_road() {
+        let map = OpenStreetMap::grid(6, 6, 0.001);
+        let index = SegmentIndex::new(&map);
+        let fixes = [Location(0.0003, 0.0001), Location(0.0007, -0.0001)];
+
+        let matched = match_trace(&map, &index, &fixes, &MatchOptions::default()).unwrap();
+        assert_eq!(vec![0, 1], matched.paths[0].ids);
+    }
+
+    #[test]
+    fn splits_where_roads_do_not_connect() {
+        // two roads about 220m apart with no way between them
+        let locations = [
+            Location(0.0, 0.0),
+            Location(0.001, 0.0),
+            Location(0.003, 0.0),
+            Location(0.004, 0.0),
+        ];
+        let map = OpenStreetMap::from_locations(&locations, &[(0, 1), (1, 0), (2, 3), (3, 2)]);
+        let index = SegmentIndex::new(&map);
+        let fixes = [
+            Location(0.0002, 0.0001),
+            Location(0.0008, -0.0001),
+            Location(0.0032, 0.0001),
+            Location(0.0038, 0.0),
+        ];
+
+        let matched = match_trace(&map, &index, &fixes, &MatchOptions::default()).unwrap();
+        let paths: Vec<_> = matched.paths.iter().map(|path| path.ids.clone()).collect();
+        assert_eq!(vec![vec![0, 1], vec![2, 3]], paths);
+        assert!(matched.snaps.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn reads_traces() {
+        let csv = "time,Latitude,Longitude\n1,45.0,-93.5\n2,45.001,-93.499\n";
+        let fixes = read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(2, fixes.len());
+        assert_eq!((-93.499, 45.001), (fixes[1].x(), fixes[1].y()));
+
+        let missing = read_csv("time,lat\n1,45.0\n".as_bytes());
+        assert!(matches!(
+            missing,
+            Err(TraceError::MissingColumn("longitude"))
+        ));
+
+        let gpx = r#"<?xml version="1.0"?>
+            <gpx version="1.1"><trk><trkseg>
+              <trkpt lat="45.0" lon="-93.5"><ele>250</ele></trkpt>
+              <trkpt lon='-93.499' lat='45.001'/>
+            </trkseg></trk></gpx>"#;
+        let fixes = read_gpx(gpx.as_bytes()).unwrap();
+        assert_eq!(2, fixes.len());
+        assert_eq!((-93.5, 45.0), (fixes[0].x(), fixes[0].y()));
+        assert_eq!((-93.499, 45.001), (fixes[1].x(), fixes[1].y()));
+
+        let bad = read_gpx(r#"<trkpt lat="north" lon="1"/>"#.as_bytes());
+        assert!(matches!(bad, Err(TraceError::BadCoordinate(_))));
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    fs::File,
+    io,
+    io::{BufWriter, Write},
+    mem,
+    ops::Range,
+    sync::OnceLock,
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use memmap2::Mmap;
+
+use crate::{
+    edge::Edge,
+    graph::{Direction, Graph},
+    map_file::MapFileError,
+    osm_parser::{ClosestResult, Location, OpenStreetMap},
+    profile::Profile,
+    quadtree::QuadTree,
+    turn_restriction::Copies,
+};
+
+/// The first bytes of every file written by `OpenStreetMap::save_mapped`.
+pub const MAGIC: [u8; 4] = *b"RCSR";
+
+pub const VERSION: u16 = 2;
+
+/// The header is padded so the first section starts 8 byte aligned.
+const HEADER_LEN: usize = 64;
+
+/// Where each array of a mapped file starts. They follow the header in this
+/// order, each padded to a multiple of 8 bytes.
+///
+/// * `offsets`: `node_count + 1` u32s. The outgoing edges of node `i` are
+///   `edges[offsets[i]..offsets[i + 1]]`.
+/// * `edges`: `edge_count` 18 byte `Edge`s.
+/// * `rev_offsets` and `rev_edges`: the same for incoming edges.
+/// * `locations`: `node_count` pairs of f64.
+/// * `osm_ids`: `node_count` i64s.
+/// * `originals`: `copy_count` [copy, original] pairs of u32 for the nodes
+///   copied for turn restrictions, sorted by copy.
+/// * `copies`: the same pairs as [original, copy], sorted by original.
+///
+/// Everything is little endian, so it can be used straight from the mapping.
+#[derive(Debug)]
+struct Sections {
+    offsets: Range<usize>,
+    edges: Range<usize>,
+    rev_offsets: Range<usize>,
+    rev_edges: Range<usize>,
+    locations: Range<usize>,
+    osm_ids: Range<usize>,
+    originals: Range<usize>,
+    copies: Range<usize>,
+}
+
+impl Sections {
+    fn new(node_count: usize, edge_count: usize, copy_count: usize) -> Sections {
+        let mut at = HEADER_LEN;
+        let mut section = |len: usize| {
+            let range = at..at + len;
+            at = padded(range.end);
+            range
+        };
+
+        Sections {
+            offsets: section((node_count + 1) * mem::size_of::<u32>()),
+            edges: section(edge_count * mem::size_of::<Edge>()),
+            rev_offsets: section((node_count + 1) * mem::size_of::<u32>()),
+            rev_edges: section(edge_count * mem::size_of::<Edge>()),
+            locations: section(node_count * mem::size_of::<Location>()),
+            osm_ids: section(node_count * mem::size_of::<i64>()),
+            originals: section(copy_count * mem::size_of::<[u32; 2]>()),
+            copies: section(copy_count * mem::size_of::<[u32; 2]>()),
+        }
+    }
+
+    /// the length of the whole file
+    fn end(&self) -> usize {
+        padded(self.copies.end)
+    }
+}
+
+fn padded(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+/// Reinterprets `bytes` as a slice of `T`.
+///
+/// # Safety
+///
+/// Every bit pattern of the right size must be a valid `T`, or the bytes must
+/// already have been checked to be one.
+unsafe fn cast<T>(bytes: &[u8]) -> &[T] {
+    let (prefix, slice, suffix) = bytes.align_to::<T>();
+    assert!(prefix.is_empty() && suffix.is_empty());
+    slice
+}
+
+/// A map read straight from a memory mapped file, in compressed sparse row
+/// form. Opening it only checks the header and the file size; nothing is
+/// copied, so a large map is ready at once and its pages are shared between
+/// processes.
+///
+/// The rest of the file is not trusted. Edges are checked as they are read,
+/// and a corrupt file gives a wrong map rather than a panic.
+pub struct MappedMap {
+    mmap: Mmap,
+    sections: Sections,
+    node_count: usize,
+    profile: Profile,
+    /// the spatial index, built the first time it is needed
+    index: OnceLock<QuadTree>,
+}
+
+impl MappedMap {
+    #[allow(dead_code)]
+    pub fn open(name: &str) -> Result<MappedMap, MapFileError> {
+        if cfg!(target_endian = "big") {
+            return Err(MapFileError::Io(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "mapped files can only be read on little endian machines",
+            )));
+        }
+
+        let file = File::open(name)?;
+        // SAFETY: the mapping is read only. Like any mapped file it must not
+        // be changed by another process while it is open.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let header = mmap.get(..HEADER_LEN).ok_or(MapFileError::Truncated)?;
+        if header[..4] != MAGIC {
+            return Err(MapFileError::BadMagic);
+        }
+
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(MapFileError::UnsupportedVersion(version));
+        }
+
+        let profile = Profile::from_u8(header[6]).ok_or(MapFileError::UnknownProfile(header[6]))?;
+
+        let read_u32 = |at: usize| {
+            u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+        };
+        let node_count = read_u32(8) as usize;
+        let edge_count = read_u32(12) as usize;
+        let copy_count = read_u32(16) as usize;
+
+        let sections = Sections::new(node_count, edge_count, copy_count);
+        match mmap.len() {
+            len if len < sections.end() => return Err(MapFileError::Truncated),
+            len if len > sections.end() => return Err(MapFileError::TrailingData),
+            _ => {}
+        }
+
+        let map = MappedMap {
+            mmap,
+            sections,
+            node_count,
+            profile,
+            index: OnceLock::new(),
+        };
+
+        for direction in [Direction::Forward, Direction::Backward] {
+            let (offsets, _) = map.csr(direction);
+            if offsets[0] != 0 || offsets[node_count] as usize != edge_count {
+                return Err(MapFileError::Corrupt);
+            }
+        }
+
+        Ok(map)
+    }
+
+    fn csr(&self, direction: Direction) -> (&[u32], &[Edge]) {
+        let (offsets, edges) = match direction {
+            Direction::Forward => (&self.sections.offsets, &self.sections.edges),
+            Direction::Backward => (&self.sections.rev_offsets, &self.sections.rev_edges),
+        };
+
+        // SAFETY: any bit pattern is a valid u32, or an `Edge`, which is
+        // made of a u32, f32, u8s and an i64
+        unsafe {
+            (
+                cast(&self.mmap[offsets.clone()]),
+                cast(&self.mmap[edges.clone()]),
+            )
+        }
+    }
+
+    fn locations(&self) -> &[Location] {
+        // SAFETY: any bit pattern is a valid pair of f64
+        unsafe { cast(&self.mmap[self.sections.locations.clone()]) }
+    }
+
+    fn osm_ids(&self) -> &[i64] {
+        // SAFETY: any bit pattern is a valid i64
+        unsafe { cast(&self.mmap[self.sections.osm_ids.clone()]) }
+    }
+
+    /// which nodes are copies made for turn restrictions
+    pub fn restriction_copies(&self) -> Copies<&[[u32; 2]]> {
+        // SAFETY: any bit pattern is a valid pair of u32
+        unsafe {
+            Copies::from_sorted(
+                cast(&self.mmap[self.sections.originals.clone()]),
+                cast(&self.mmap[self.sections.copies.clone()]),
+            )
+        }
+    }
+
+    /// The index over the nodes with outgoing edges, other than turn
+    /// restriction copies. It is built on the first spatial query, so opening
+    /// stays cheap for searches that never need one.
+    fn index(&self) -> &QuadTree {
+        self.index.get_or_init(|| {
+            let copies = self.restriction_copies();
+            let snappable = (0..self.node_count as u32)
+                .filter(|&id| {
+                    self.edges(id, Direction::Forward).next().is_some() && !copies.is_copy(id)
+                })
+                .collect();
+            QuadTree::new(self.locations().to_vec(), snappable)
+        })
+    }
+
+    /// the closest node to `location`, like `OpenStreetMap::closest`
+    #[allow(dead_code)]
+    pub fn closest(&self, location: Location) -> Option<ClosestResult> {
+        self.index()
+            .closest(location)
+            .map(|(id, dist)| ClosestResult { dist, id })
+    }
+
+    /// the `k` closest nodes to `location`, closest first
+    #[allow(dead_code)]
+    pub fn k_closest(&self, location: Location, k: usize) -> Vec<ClosestResult> {
+        self.index()
+            .k_closest(location, k)
+            .into_iter()
+            .map(|(id, dist)| ClosestResult { dist, id })
+            .collect()
+    }
+
+    /// every node within `radius` metres of `location`, closest first
+    #[allow(dead_code)]
+    pub fn within(&self, location: Location, radius: f64) -> Vec<ClosestResult> {
+        self.index()
+            .within(location, radius)
+            .into_iter()
+            .map(|(id, dist)| ClosestResult { dist, id })
+            .collect()
+    }
+}
+
+impl Graph for MappedMap {
+    type Node = Location;
+
+    fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    fn get(&self, id: u32) -> &Location {
+        &self.locations()[id as usize]
+    }
+
+    /// The offsets are clamped to the edges, and edges to nodes past the end
+    /// are skipped, so a corrupt file cannot send a search out of bounds.
+    fn edges(&self, id: u32, direction: Direction) -> impl Iterator<Item = &Edge> + '_ {
+        let (offsets, edges) = self.csr(direction);
+        let from = (offsets[id as usize] as usize).min(edges.len());
+        let to = (offsets[id as usize + 1] as usize).clamp(from, edges.len());
+        let node_count = self.node_count;
+        edges[from..to]
+            .iter()
+            .filter(move |edge| (edge.node as usize) < node_count)
+    }
+
+    fn osm_id(&self, id: u32) -> i64 {
+        self.osm_ids()[id as usize]
+    }
+
+    fn original(&self, id: u32) -> u32 {
+        let original = self.restriction_copies().original(id);
+        if (original as usize) < self.node_count {
+            original
+        } else {
+            id
+        }
+    }
+
+    fn copies(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        let copies: Vec<u32> = self
+            .restriction_copies()
+            .of(id)
+            .filter(|&copy| (copy as usize) < self.node_count)
+            .collect();
+        copies.into_iter()
+    }
+
+    fn profile(&self) -> Profile {
+        self.profile
+    }
+}
+
+impl OpenStreetMap {
+    /// Writes the map so `MappedMap::open` can use it without reading it in.
+    /// See `Sections` for the layout.
+    #[allow(dead_code)]
+    pub fn save_mapped(&self, name: &str) -> Result<(), io::Error> {
+        let node_count = self.node_count();
+        let edge_count: usize = self
+            .iterator()
+            .map(|node| node.connected.len() as usize)
+            .sum();
+        let copies = self.restriction_copies();
+        let copy_count = copies.originals().len();
+        let sections = Sections::new(node_count, edge_count, copy_count);
+
+        let mut writer = Offset {
+            inner: BufWriter::new(File::create(name)?),
+            at: 0,
+        };
+
+        writer.write_all(&MAGIC)?;
+        writer.write_u16::<LittleEndian>(VERSION)?;
+        writer.write_u8(self.profile() as u8)?;
+        writer.write_u8(0)?;
+        writer.write_u32::<LittleEndian>(node_count as u32)?;
+        writer.write_u32::<LittleEndian>(edge_count as u32)?;
+        writer.write_u32::<LittleEndian>(copy_count as u32)?;
+
+        for direction in [Direction::Forward, Direction::Backward] {
+            let (offsets, edges) = match direction {
+                Direction::Forward => (&sections.offsets, &sections.edges),
+                Direction::Backward => (&sections.rev_offsets, &sections.rev_edges),
+            };
+
+            writer.pad_to(offsets.start)?;
+            let mut offset = 0;
+            writer.write_u32::<LittleEndian>(offset)?;
+            for id in 0..node_count as u32 {
+                offset += self.edges(id, direction).count() as u32;
+                writer.write_u32::<LittleEndian>(offset)?;
+            }
+
+            writer.pad_to(edges.start)?;
+            for id in 0..node_count as u32 {
+                for edge in self.edges(id, direction) {
+                    writer.write_u32::<LittleEndian>(edge.node)?;
+                    writer.write_f32::<LittleEndian>(edge.length)?;
+                    writer.write_u8(edge.highway)?;
+                    writer.write_u8(edge.max_speed)?;
+                    writer.write_i64::<LittleEndian>(edge.way_id)?;
+                }
+            }
+        }
+
+        writer.pad_to(sections.locations.start)?;
+        for node in self.iterator() {
+            let Location(x, y) = node.location;
+            writer.write_f64::<LittleEndian>(x)?;
+            writer.write_f64::<LittleEndian>(y)?;
+        }
+
+        writer.pad_to(sections.osm_ids.start)?;
+        for id in 0..node_count as u32 {
+            writer.write_i64::<LittleEndian>(self.osm_id(id))?;
+        }
+
+        for (section, pairs) in [
+            (&sections.originals, copies.originals()),
+            (&sections.copies, copies.copies()),
+        ] {
+            writer.pad_to(section.start)?;
+            for &[a, b] in pairs {
+                writer.write_u32::<LittleEndian>(a)?;
+                writer.write_u32::<LittleEndian>(b)?;
+            }
+        }
+
+        writer.pad_to(sections.end())?;
+        writer.flush()
+    }
+}
+
+/// Keeps track of how much has been written, so sections can be aligned.
+struct Offset<W> {
+    inner: W,
+    at: usize,
+}
+
+impl<W: Write> Offset<W> {
+    fn pad_to(&mut self, at: usize) -> io::Result<()> {
+        let padding = at - self.at;
+        self.write_all(&vec![0; padding])
+    }
+}
+
+impl<W: Write> Write for Offset<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.at += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, fs::OpenOptions, io::Write, path::PathBuf};
+
+    use crate::{
+        a_star,
+        edge::{Edge, Highway},
+        graph::{Direction, Graph},
+        map_file::MapFileError,
+        mapped::MappedMap,
+        osm_parser::{ClosestResult, Location, OpenStreetMap},
+    };
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ai_osm_{}_{}.csr", name, std::process::id()))
+    }
+
+    #[test]
+    fn matches_map() {
+        let map = OpenStreetMap::from_edges(4, &[(0, 1), (1, 2), (2, 0), (2, 3), (3, 2)]);
+        let path = temp_file("mapped");
+        map.save_mapped(path.to_str().unwrap()).unwrap();
+
+        let mapped = MappedMap::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(map.node_count(), mapped.node_count());
+        assert_eq!(map.profile(), mapped.profile());
+
+        for id in 0..4 {
+            assert_eq!(map.osm_id(id), mapped.osm_id(id));
+            assert_eq!(Graph::location(&map, id).f64(), mapped.location(id).f64());
+
+            for direction in [Direction::Forward, Direction::Backward] {
+                let expected: Vec<_> = map
+                    .edges(id, direction)
+                    .map(|edge| (edge.node, edge.length, edge.way_id))
+                    .collect();
+                let actual: Vec<_> = Graph::edges(&mapped, id, direction)
+                    .map(|edge| (edge.node, edge.length, edge.way_id))
+                    .collect();
+                assert_eq!(expected, actual);
+            }
+        }
+
+        let expected = a_star::path(&map, 0, 3).unwrap().ids;
+        let actual = a_star::path(&mapped, 0, 3).unwrap().ids;
+        assert_eq!(expected, actual);
+
+        let ids = |results: Vec<ClosestResult>| -> Vec<u32> {
+            results.into_iter().map(|result| result.id).collect()
+        };
+        let location = Location(1.2, 0.1);
+        assert_eq!(
+            map.closest(location).map(|result| result.id),
+            mapped.closest(location).map(|result| result.id)
+        );
+        assert_eq!(
+            ids(map.k_closest(location, 3)),
+            ids(mapped.k_closest(location, 3))
+        );
+        let radius = location.dist_metres(Location(3.0, 0.0));
+        assert_eq!(
+            ids(map.within(location, radius)),
+            ids(mapped.within(location, radius))
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let map = OpenStreetMap::from_edges(3, &[(0, 1), (1, 2)]);
+        let path = temp_file("mapped_bad");
+        let name = path.to_str().unwrap();
+
+        map.save_mapped(name).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(name)
+            .unwrap()
+            .write_all(&[0; 8])
+            .unwrap();
+        assert!(matches!(
+            MappedMap::open(name),
+            Err(MapFileError::TrailingData)
+        ));
+
+        map.save_mapped(name).unwrap();
+        let mut bytes = fs::read(name).unwrap();
+        // a highway class from a later version is not checked, and reads as
+        // `Other`
+        bytes[88] = 200;
+        fs::write(name, &bytes).unwrap();
+        let mapped = MappedMap::open(name).unwrap();
+        assert_eq!(
+            Some(Highway::Other),
+            mapped
+                .edges(0, Direction::Forward)
+                .next()
+                .map(Edge::highway)
+        );
+        drop(mapped);
+
+        // the first edge now points past the last node, which is only found
+        // when it is read, and then skipped
+        bytes[80..84].copy_from_slice(&7u32.to_le_bytes());
+        fs::write(name, &bytes).unwrap();
+        let mapped = MappedMap::open(name).unwrap();
+        assert_eq!(0, mapped.edges(0, Direction::Forward).count());
+        assert_eq!(1, mapped.edges(1, Direction::Forward).count());
+        drop(mapped);
+
+        // an offset past the edges is clamped to them, which leaves the next
+        // one out of order, and that node with no edges
+        bytes[68..72].copy_from_slice(&9u32.to_le_bytes());
+        fs::write(name, &bytes).unwrap();
+        let mapped = MappedMap::open(name).unwrap();
+        assert_eq!(1, mapped.edges(0, Direction::Forward).count());
+        assert_eq!(0, mapped.edges(1, Direction::Forward).count());
+        drop(mapped);
+
+        // the last offset has to match the edge count in the header
+        bytes[76..80].copy_from_slice(&3u32.to_le_bytes());
+        fs::write(name, &bytes).unwrap();
+        assert!(matches!(MappedMap::open(name), Err(MapFileError::Corrupt)));
+
+        bytes[0] = b'X';
+        fs::write(name, &bytes).unwrap();
+        assert!(matches!(MappedMap::open(name), Err(MapFileError::BadMagic)));
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use rayon::prelude::*;
+
+use crate::{
+    a_star::HeapNode,
+    graph::{Direction, Graph},
+    params::Params,
+};
+
+/// The cost from each of a list of sources to each of a list of targets.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct CostMatrix {
+    pub sources: Vec<u32>,
+    pub targets: Vec<u32>,
+    /// row by row, a row for each source. `f64::INFINITY` where a target
+    /// cannot be reached.
+    pub costs: Vec<f64>,
+}
+
+impl CostMatrix {
+    pub fn from_rows(sources: &[u32], targets: &[u32], rows: Vec<Vec<f64>>) -> CostMatrix {
+        CostMatrix {
+            sources: sources.to_vec(),
+            targets: targets.to_vec(),
+            costs: rows.into_iter().flatten().collect(),
+        }
+    }
+
+    /// the cost from `sources[source]` to `targets[target]`
+    #[allow(dead_code)]
+    pub fn get(&self, source: usize, target: usize) -> f64 {
+        self.costs[source * self.targets.len() + target]
+    }
+
+    #[allow(dead_code)]
+    pub fn row(&self, source: usize) -> &[f64] {
+        let width = self.targets.len();
+        &self.costs[source * width..(source + 1) * width]
+    }
+}
+
+/// The cost from `source` to each of `targets`. This is one Dijkstra search
+/// which stops once every target is settled, instead of a search per target.
+/// A target counts as settled once it or one of its turn restriction copies
+/// is.
+pub fn one_to_many<G: Graph>(
+    map: &G,
+    source: u32,
+    targets: &[u32],
+    params: &impl Params<G::Node>,
+) -> Vec<f64> {
+    let mut remaining: HashSet<u32> = targets.iter().cloned().collect();
+    let mut dist = HashMap::new();
+    let mut closed = HashSet::new();
+    let mut queue = BinaryHeap::new();
+
+    dist.insert(source, 0.0);
+    queue.push(HeapNode {
+        id: source,
+        f_score: 0.0,
+    });
+
+    while let Some(origin) = queue.pop() {
+        if remaining.is_empty() {
+            break;
+        }
+        if !closed.insert(origin.id) {
+            continue;
+        }
+        remaining.remove(&map.original(origin.id));
+
+        let origin_node = map.get(origin.id);
+        for edge in map.edges(origin.id, Direction::Forward) {
+            let neighbor = edge.node;
+            let tentative =
+                origin.f_score + params.neighbor_dist(origin_node, map.get(neighbor), edge);
+            if dist.get(&neighbor).is_some_and(|&prev| tentative >= prev) {
+                continue;
+            }
+
+            dist.insert(neighbor, tentative);
+            queue.push(HeapNode {
+                id: neighbor,
+                f_score: tentative,
+            });
+        }
+    }
+
+    targets
+        .iter()
+        .map(|&target| {
+            map.with_copies(target)
+                .filter_map(|id| dist.get(&id).copied())
+                .fold(f64::INFINITY, f64::min)
+        })
+        .collect()
+}
+
+/// The cost from every source to every target, with a one to many search from
+/// each source, spread over all cores.
+#[allow(dead_code)]
+pub fn many_to_many<G: Graph>(
+    map: &G,
+    sources: &[u32],
+    targets: &[u32],
+    params: &impl Params<G::Node>,
+) -> CostMatrix {
+    let rows = sources
+        .par_iter()
+        .map(|&source| one_to_many(map, source, targets, params))
+        .collect();
+    CostMatrix::from_rows(sources, targets, rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{a_star, matrix, osm_parser::OpenStreetMap, params::SimpleParams};
+
+    #[test]
+    fn matches_a_star() {
+        let map = OpenStreetMap::random_roads(60, 17);
+        let sources = [0, 5, 17, 40, 59];
+        let targets = [3, 5, 22, 41];
+
+        let costs = matrix::many_to_many(&map, &sources, &targets, &SimpleParams);
+        assert_eq!(20, costs.costs.len());
+
+        for (i, &source) in sources.iter().enumerate() {
+            for (j, &target) in targets.iter().enumerate() {
+                match a_star::path(&map, source, target) {
+                    Some(path) => assert!((path.length_metres() - costs.get(i, j)).abs() < 0.01),
+                    None => assert!(costs.get(i, j).is_infinite()),
+                }
+            }
+            assert_eq!(costs.get(i, 2), costs.row(i)[2]);
+        }
+        assert_eq!(0.0, costs.get(1, 1));
+    }
+}
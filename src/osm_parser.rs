@@ -3,8 +3,7 @@ use std::{
     fmt::{Debug, Formatter},
     fs::File,
     io,
-    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
-    ptr::read,
+    io::{BufReader, BufWriter, Cursor, Read},
     slice::Iter,
 };
 
@@ -13,8 +12,16 @@ use osmpbf::ElementReader;
 use rand::Rng;
 
 use crate::{
-    a_star::Path,
+    bounds::Boundable,
     compact_array::{CompactVec, CompactVecIterator},
+    edge::{parse_max_speed, Edge, Highway},
+    graph::{Direction, Graph},
+    map_file,
+    map_file::{ChecksumReader, ChecksumWriter, MapFileError, MapFileHeader},
+    profile::Profile,
+    quadtree::QuadTree,
+    turn_restriction,
+    turn_restriction::{Copies, TurnRestriction},
 };
 
 /// Nodes, ways, etc.
@@ -35,9 +42,13 @@ use crate::{
 /// <node id lat lon>
 /// <way
 /// <nd ref>
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct Node {
-    pub connected: CompactVec<u32>,
+    /// edges to the nodes reachable from this node (outgoing edges)
+    pub connected: CompactVec<Edge>,
+    /// edges from the nodes which can reach this node (incoming edges). This is
+    /// derived from `connected` and is never saved.
+    pub incoming: CompactVec<Edge>,
     pub location: Location,
 }
 
@@ -45,57 +56,163 @@ impl Debug for Node {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Node")
             .field("connected", &self.connected)
+            .field("incoming", &self.incoming)
             .field("location", &self.location)
             .finish()
     }
 }
 
-fn process_way(id_to_idx: &mut HashMap<i64, u32>, idx_to_node: &mut Vec<Node>, way: &osmpbf::Way) {
-    let valid = OpenStreetMap::valid_way(way);
+/// Which directions a way can be travelled in, relative to the order of its
+/// node refs.
+///
+/// https://wiki.openstreetmap.org/wiki/Key:oneway
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Oneway {
+    /// both directions
+    No,
+    /// only in the order of the refs
+    Forward,
+    /// only against the order of the refs (`oneway=-1`)
+    Backward,
+}
+
+impl Oneway {
+    pub fn from_tags<'a>(tags: impl Iterator<Item = (&'a str, &'a str)>) -> Oneway {
+        let mut oneway = None;
+        let mut implied = false;
+
+        for (key, value) in tags {
+            match (key, value) {
+                ("oneway", "yes" | "true" | "1") => oneway = Some(Oneway::Forward),
+                ("oneway", "-1" | "reverse") => oneway = Some(Oneway::Backward),
+                ("oneway", "no" | "false" | "0") => oneway = Some(Oneway::No),
+                ("junction", "roundabout" | "circular") => implied = true,
+                ("highway", "motorway" | "motorway_link") => implied = true,
+                _ => {}
+            }
+        }
+
+        // an explicit oneway tag always wins over the implied one
+        match oneway {
+            Some(oneway) => oneway,
+            None if implied => Oneway::Forward,
+            None => Oneway::No,
+        }
+    }
+
+    fn forward(self) -> bool {
+        self != Oneway::Backward
+    }
+
+    fn backward(self) -> bool {
+        self != Oneway::Forward
+    }
+}
+
+fn process_way(
+    id_to_idx: &HashMap<i64, u32>,
+    idx_to_node: &mut [Node],
+    way: &osmpbf::Way,
+    profile: Profile,
+) {
+    let valid = OpenStreetMap::valid_way(way, profile);
 
     if !valid {
         return;
     }
 
+    let oneway = profile.oneway(way.tags());
+
+    let mut highway = Highway::Other;
+    let mut max_speed = 0;
+    for (key, value) in way.tags() {
+        match key {
+            "highway" => highway = Highway::from_tag(value),
+            "maxspeed" => max_speed = parse_max_speed(value).unwrap_or(0),
+            _ => {}
+        }
+    }
+
     let refs: Vec<_> = way
         .refs()
         .map(|real_id| *id_to_idx.get(&real_id).unwrap())
         .collect();
 
-    if refs.len() <= 1 {
-        return;
-    }
+    for pair in refs.windows(2) {
+        let (from_idx, to_idx) = (pair[0], pair[1]);
 
-    let first_idx = *refs.first().unwrap();
+        let from_loc = idx_to_node[from_idx as usize].location;
+        let to_loc = idx_to_node[to_idx as usize].location;
 
-    let first_node = idx_to_node.get_mut(first_idx as usize).unwrap();
+        let edge = |node| Edge {
+            node,
+            length: from_loc.dist_metres(to_loc) as f32,
+            highway: highway as u8,
+            max_speed,
+            way_id: way.id(),
+        };
 
-    first_node.connected.push(refs[1]);
+        if oneway.forward() {
+            idx_to_node[from_idx as usize].connected.push(edge(to_idx));
+        }
 
-    for i in 1..(refs.len() - 1) {
-        let prev_idx = refs[i - 1];
-        let on_idx = refs[i];
-        let next_idx = refs[i + 1];
-        let node = idx_to_node.get_mut(on_idx as usize).unwrap();
+        if oneway.backward() {
+            idx_to_node[to_idx as usize].connected.push(edge(from_idx));
+        }
+    }
+}
 
-        node.connected.push2(prev_idx, next_idx);
+/// Replaces the incoming edges of every node with the reverse of `connected`.
+fn link_incoming(idx_to_node: &mut [Node]) {
+    let mut incoming = vec![Vec::new(); idx_to_node.len()];
+
+    for (from_idx, node) in idx_to_node.iter().enumerate() {
+        for edge in node.connected.iterator() {
+            let to_idx = edge.node;
+            incoming[to_idx as usize].push(edge.with_node(from_idx as u32));
+        }
     }
 
-    let last_idx = *refs.last().unwrap();
+    for (node, incoming) in idx_to_node.iter_mut().zip(incoming) {
+        node.incoming = CompactVec::from_vec(incoming);
+    }
+}
 
-    let last_node = idx_to_node.get_mut(last_idx as usize).unwrap();
-    last_node.connected.push(refs[refs.len() - 2])
+/// A map as it is parsed or read, before anything is derived from it: only
+/// the `connected` edges of its nodes are filled in.
+pub struct RawMap {
+    pub nodes: Vec<Node>,
+    pub osm_ids: Vec<i64>,
+    pub profile: Profile,
 }
 
 pub struct OpenStreetMap {
     idx_to_node: Vec<Node>,
+    /// the OSM id of every node. Copies made for turn restrictions share the id
+    /// of the node they were copied from.
+    idx_to_osm: Vec<i64>,
+    /// all ids sorted by OSM id, for looking up the id of an OSM node
+    osm_order: Vec<u32>,
+    /// the nodes copied for turn restrictions
+    copies: Copies,
+    profile: Profile,
+    /// the nodes with outgoing edges, leaving out turn restriction copies
+    index: QuadTree,
 }
 
+/// The mean radius of the earth.
+pub const EARTH_RADIUS_METRES: f64 = 6_371_008.8;
+
+pub const METRES_PER_MILE: f64 = 1609.344;
+
+/// A longitude and latitude in degrees.
 #[derive(Debug, Copy, Clone)]
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct Location(pub f64, pub f64);
 
 impl Location {
+    /// the squared distance in degrees, as if they were flat. Only useful for
+    /// comparing points which are close together.
     #[inline]
     pub fn dist2(&self, other: Location) -> f64 {
         let dx = self.0 - other.0;
@@ -103,10 +220,29 @@ impl Location {
         dx * dx + dy * dy
     }
 
+    #[allow(dead_code)]
     pub fn dist(&self, other: Location) -> f64 {
         self.dist2(other).sqrt()
     }
 
+    /// the great circle distance, from the haversine formula
+    ///
+    /// https://en.wikipedia.org/wiki/Haversine_formula
+    pub fn dist_metres(&self, other: Location) -> f64 {
+        let (lat, other_lat) = (self.1.to_radians(), other.1.to_radians());
+        let half_dlat = (other_lat - lat) / 2.0;
+        let half_dlon = (other.0 - self.0).to_radians() / 2.0;
+
+        let a = half_dlat.sin().powi(2) + lat.cos() * other_lat.cos() * half_dlon.sin().powi(2);
+        2.0 * EARTH_RADIUS_METRES * a.sqrt().min(1.0).asin()
+    }
+
+    #[allow(dead_code)]
+    pub fn dist_miles(&self, other: Location) -> f64 {
+        self.dist_metres(other) / METRES_PER_MILE
+    }
+
+    #[allow(dead_code)]
     pub fn f32(&self) -> (f32, f32) {
         (self.0 as f32, self.1 as f32)
     }
@@ -125,7 +261,9 @@ impl Node {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct ClosestResult {
+    /// the distance in metres
     pub dist: f64,
     pub id: u32,
 }
@@ -133,7 +271,7 @@ pub struct ClosestResult {
 impl ClosestResult {
     #[allow(dead_code)]
     pub fn dist_miles(&self) -> f64 {
-        self.dist * 68.703
+        self.dist / METRES_PER_MILE
     }
 }
 
@@ -148,107 +286,464 @@ impl Location {
 }
 
 impl OpenStreetMap {
+    /// Writes the map in the format described in `map_file`: a header, one
+    /// record per node and a CRC-32 of everything before it.
+    #[allow(dead_code)]
     pub fn save(&self, name: &str) -> Result<(), io::Error> {
         let file = File::create(name)?;
-        let mut writer = BufWriter::new(file);
+        let mut writer = ChecksumWriter::new(BufWriter::new(file));
 
-        writer.write_u32::<BigEndian>(self.idx_to_node.len() as u32)?;
+        let header = MapFileHeader {
+            version: map_file::VERSION,
+            flags: map_file::FLAG_IDS | map_file::FLAG_TAGS | map_file::FLAG_DIRECTED,
+            profile: self.profile,
+            bounds: Some(self.get_bounds()),
+            node_count: self.idx_to_node.len() as u32,
+        };
+        header.write(&mut writer)?;
 
-        for node in &self.idx_to_node {
+        for (node, &osm_id) in self.idx_to_node.iter().zip(&self.idx_to_osm) {
             let Location(x, y) = node.location; // 8*2 bytes
             writer.write_f64::<BigEndian>(x)?;
             writer.write_f64::<BigEndian>(y)?;
-            let connected_len = node.connected.len(); // 4*connected_len bytes + 1 byte
+            writer.write_i64::<BigEndian>(osm_id)?; // 8 bytes
+            let connected_len = node.connected.len(); // 18*connected_len bytes + 1 byte
             writer.write_u8(connected_len)?;
-            for &i in node.connected.iterator() {
-                writer.write_u32::<BigEndian>(i)?;
+            for edge in node.connected.iterator() {
+                writer.write_u32::<BigEndian>(edge.node)?;
+                writer.write_f32::<BigEndian>(edge.length)?;
+                writer.write_u8(edge.highway)?;
+                writer.write_u8(edge.max_speed)?;
+                writer.write_i64::<BigEndian>(edge.way_id)?;
             }
         }
-        writer.flush()?;
+        let checksum = writer.checksum();
+        writer.finish()?;
+
+        let index = File::create(OpenStreetMap::index_file(name))?;
+        self.index.write(BufWriter::new(index), checksum)?;
 
         Ok(())
     }
 
+    /// the file the spatial index of the map file `name` is saved in
+    fn index_file(name: &str) -> String {
+        format!("{}.index", name)
+    }
+
     pub fn iterator(&self) -> Iter<'_, Node> {
         self.idx_to_node.iter()
     }
 
-    pub fn read_custom_file(name: &str) -> Result<OpenStreetMap, io::Error> {
-        let file = File::open(name)?;
-        let mut reader = BufReader::new(file);
-        let length = reader.read_u32::<BigEndian>()?;
+    /// Opens a map file, reading its header and leaving the reader at the first
+    /// node record. A file from before the header is read into memory whole to
+    /// tell which layout it has.
+    fn open_file(
+        name: &str,
+    ) -> Result<(MapFileHeader, ChecksumReader<Box<dyn Read>>), MapFileError> {
+        let mut file = BufReader::new(File::open(name)?);
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic)?;
+
+        if magic != map_file::MAGIC {
+            let mut bytes = magic.to_vec();
+            file.read_to_end(&mut bytes)?;
+            let (header, start) = MapFileHeader::read_legacy(&bytes)?;
+            let mut rest = Cursor::new(bytes);
+            rest.set_position(start as u64);
+            return Ok((header, ChecksumReader::new(Box::new(rest))));
+        }
+
+        let mut reader: ChecksumReader<Box<dyn Read>> =
+            ChecksumReader::new(Box::new(Cursor::new(magic).chain(file)));
+        let header = MapFileHeader::read(&mut reader)?;
+        Ok((header, reader))
+    }
+
+    /// Reads just the header of a map file.
+    #[allow(dead_code)]
+    pub fn read_header(name: &str) -> Result<MapFileHeader, MapFileError> {
+        OpenStreetMap::open_file(name).map(|(header, _)| header)
+    }
+
+    pub fn read_custom_file(name: &str) -> Result<OpenStreetMap, MapFileError> {
+        let (raw, checksum) = OpenStreetMap::read_raw(name)?;
+
+        // the index is rebuilt if it is missing, unreadable or was saved for
+        // another map
+        let locations = raw.nodes.iter().map(|node| node.location).collect();
+        let index = File::open(OpenStreetMap::index_file(name))
+            .ok()
+            .and_then(|file| QuadTree::read(BufReader::new(file), locations, checksum).ok())
+            .flatten();
+
+        Ok(OpenStreetMap::assemble(
+            raw.nodes,
+            raw.osm_ids,
+            raw.profile,
+            index,
+        ))
+    }
+
+    /// Reads the nodes of a map file and the checksum of its contents.
+    pub fn read_raw(name: &str) -> Result<(RawMap, u32), MapFileError> {
+        let (header, mut reader) = OpenStreetMap::open_file(name)?;
+
+        let length = header.node_count;
         let mut idx_to_node = Vec::with_capacity(length as usize);
+        let mut idx_to_osm = Vec::with_capacity(length as usize);
         for _ in 0..length {
             let x = reader.read_f64::<BigEndian>()?;
             let y = reader.read_f64::<BigEndian>()?;
             let location = Location(x, y);
+            // without ids every node is its own OSM node
+            let osm_id = if header.has(map_file::FLAG_IDS) {
+                reader.read_i64::<BigEndian>()?
+            } else {
+                idx_to_osm.len() as i64
+            };
+            idx_to_osm.push(osm_id);
             let connected_len = reader.read_u8()?;
             let mut vec = Vec::with_capacity(connected_len as usize);
             for _ in 0..connected_len {
-                let idx = reader.read_u32::<BigEndian>()?;
-                vec.push(idx);
+                let node = reader.read_u32::<BigEndian>()?;
+                let edge = if header.has(map_file::FLAG_TAGS) {
+                    Edge {
+                        node,
+                        length: reader.read_f32::<BigEndian>()?,
+                        highway: reader.read_u8()?,
+                        max_speed: reader.read_u8()?,
+                        way_id: reader.read_i64::<BigEndian>()?,
+                    }
+                } else {
+                    // the length is filled in once every location is known
+                    Edge {
+                        node,
+                        length: 0.0,
+                        highway: Highway::Other as u8,
+                        max_speed: 0,
+                        way_id: 0,
+                    }
+                };
+                vec.push(edge);
             }
             let node = Node {
                 connected: CompactVec::from_vec(vec),
+                incoming: CompactVec::empty(),
                 location,
             };
             idx_to_node.push(node);
         }
 
-        Ok(OpenStreetMap { idx_to_node })
+        let checksum = reader.checksum();
+        reader.finish(header.version > 0)?;
+
+        if !header.has(map_file::FLAG_TAGS) {
+            let locations: Vec<_> = idx_to_node.iter().map(|node| node.location).collect();
+            for (node, from_loc) in idx_to_node.iter_mut().zip(locations.iter()) {
+                let edges: Vec<_> = node
+                    .connected
+                    .iterator()
+                    .map(|edge| Edge {
+                        length: from_loc.dist_metres(locations[edge.node as usize]) as f32,
+                        ..*edge
+                    })
+                    .collect();
+                node.connected = CompactVec::from_vec(edges);
+            }
+        }
+
+        // an undirected file may list a road under one end only
+        if !header.has(map_file::FLAG_DIRECTED) {
+            let mut missing = vec![Vec::new(); idx_to_node.len()];
+            for (from, node) in idx_to_node.iter().enumerate() {
+                for edge in node.connected.iterator() {
+                    let back = &idx_to_node[edge.node as usize];
+                    if !back
+                        .connected
+                        .iterator()
+                        .any(|back| back.node == from as u32)
+                    {
+                        missing[edge.node as usize].push(edge.with_node(from as u32));
+                    }
+                }
+            }
+            for (node, missing) in idx_to_node.iter_mut().zip(missing) {
+                if !missing.is_empty() {
+                    let mut edges: Vec<_> = node.connected.iterator().cloned().collect();
+                    edges.extend(missing);
+                    node.connected = CompactVec::from_vec(edges);
+                }
+            }
+        }
+
+        let raw = RawMap {
+            nodes: idx_to_node,
+            osm_ids: idx_to_osm,
+            profile: header.profile,
+        };
+        Ok((raw, checksum))
     }
-    pub fn trim(&self) -> OpenStreetMap {
-        let mut chosen_from = HashSet::new();
-        let mut origin_map = HashMap::new();
-        for i in 0..self.idx_to_node.len() {
-            // avoids first
-            chosen_from.insert(i as u32);
+
+    /// A map from parsed or read nodes.
+    pub fn from_raw(raw: RawMap) -> OpenStreetMap {
+        OpenStreetMap::from_nodes(raw.nodes, raw.osm_ids, raw.profile)
+    }
+
+    /// Builds a map from nodes whose `connected` edges are filled in. The
+    /// incoming edges and the spatial index are derived from them.
+    fn from_nodes(idx_to_node: Vec<Node>, idx_to_osm: Vec<i64>, profile: Profile) -> OpenStreetMap {
+        OpenStreetMap::assemble(idx_to_node, idx_to_osm, profile, None)
+    }
+
+    fn assemble(
+        mut idx_to_node: Vec<Node>,
+        idx_to_osm: Vec<i64>,
+        profile: Profile,
+        index: Option<QuadTree>,
+    ) -> OpenStreetMap {
+        link_incoming(&mut idx_to_node);
+
+        // ties are broken by id so an OSM id finds the original node, not a copy
+        let mut osm_order: Vec<u32> = (0..idx_to_osm.len() as u32).collect();
+        osm_order.sort_unstable_by_key(|&id| (idx_to_osm[id as usize], id));
+
+        // within a run of equal OSM ids the first is the original
+        let copies = Copies::from_osm_order(&idx_to_osm, &osm_order);
+
+        let index = index.unwrap_or_else(|| {
+            let locations = idx_to_node.iter().map(|node| node.location).collect();
+            let snappable = (0..idx_to_node.len() as u32)
+                .filter(|&id| idx_to_node[id as usize].connected.len() > 0 && !copies.is_copy(id))
+                .collect();
+            QuadTree::new(locations, snappable)
+        });
+
+        OpenStreetMap {
+            idx_to_node,
+            idx_to_osm,
+            osm_order,
+            copies,
+            profile,
+            index,
         }
+    }
 
-        while let Some(id) = chosen_from.iter().next().cloned() {
-            let mut frontier_old = Vec::new();
-            let mut frontier_new = Vec::new();
-            let mut elems = Vec::new();
-            chosen_from.remove(&id);
-            frontier_old.push(id);
-            elems.push(id);
-            while !frontier_old.is_empty() {
-                for frontier_id in frontier_old {
-                    let node = self.get(frontier_id);
-                    node.connected.iterator().for_each(|&new| {
-                        if chosen_from.remove(&new) {
-                            frontier_new.push(new);
-                            elems.push(new);
-                        }
+    /// A map where node `i` is at `(i, 0)` with OSM id `100 + i`, and each pair
+    /// in `edges` is a residential edge.
+    #[cfg(test)]
+    pub fn from_edges(node_count: usize, edges: &[(u32, u32)]) -> OpenStreetMap {
+        let locations: Vec<_> = (0..node_count).map(|i| Location(i as f64, 0.0)).collect();
+        OpenStreetMap::from_locations(&locations, edges)
+    }
+
+    /// `node_count` nodes scattered over a few miles, each joined to the three
+    /// closest, some of them by one way roads. The same `seed` gives the same
+    /// map.
+    #[cfg(test)]
+    pub fn random_roads(node_count: usize, seed: u64) -> OpenStreetMap {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let rng = &mut StdRng::seed_from_u64(seed);
+        let locations: Vec<_> = (0..node_count)
+            .map(|_| Location(rng.gen_range(-94.0, -93.9), rng.gen_range(44.9, 45.0)))
+            .collect();
+
+        let mut edges = Vec::new();
+        for from in 0..node_count as u32 {
+            let here = locations[from as usize];
+            let mut by_dist: Vec<u32> = (0..node_count as u32).filter(|&to| to != from).collect();
+            by_dist.sort_by(|&a, &b| {
+                here.dist_metres(locations[a as usize])
+                    .partial_cmp(&here.dist_metres(locations[b as usize]))
+                    .unwrap()
+            });
+            for &to in by_dist.iter().take(3) {
+                edges.push((from, to));
+                if rng.gen_bool(0.7) {
+                    edges.push((to, from));
+                }
+            }
+        }
+        edges.sort_unstable();
+        edges.dedup();
+
+        OpenStreetMap::from_locations(&locations, &edges)
+    }
+
+    /// A map with a node at each of `locations`, joined by `edges` as long as
+    /// the distance between their ends.
+    #[cfg(test)]
+    pub fn from_locations(locations: &[Location], edges: &[(u32, u32)]) -> OpenStreetMap {
+        let nodes = (0..locations.len())
+            .map(|i| {
+                let connected: Vec<_> = edges
+                    .iter()
+                    .filter(|(from, _)| *from == i as u32)
+                    .map(|&(_, to)| Edge {
+                        node: to,
+                        length: locations[i].dist_metres(locations[to as usize]) as f32,
+                        highway: Highway::Residential as u8,
+                        max_speed: 0,
+                        way_id: 0,
                     })
+                    .collect();
+                Node {
+                    connected: CompactVec::from_vec(connected),
+                    incoming: CompactVec::empty(),
+                    location: locations[i],
                 }
-                frontier_old = frontier_new.drain(0..frontier_new.len()).collect();
+            })
+            .collect();
+        let osm_ids = (0..locations.len() as i64).map(|i| 100 + i).collect();
+        OpenStreetMap::from_nodes(nodes, osm_ids, Profile::Car)
+    }
+
+    /// A `width` by `height` grid of two way roads `spacing` degrees apart,
+    /// starting at (0, 0) and numbered by row.
+    #[cfg(test)]
+    pub fn grid(width: u32, height: u32, spacing: f64) -> OpenStreetMap {
+        let locations: Vec<_> = (0..width * height)
+            .map(|i| Location((i % width) as f64 * spacing, (i / width) as f64 * spacing))
+            .collect();
+        let mut edges = Vec::new();
+        for i in 0..width * height {
+            if i % width < width - 1 {
+                edges.extend([(i, i + 1), (i + 1, i)]);
+            }
+            if i / width < height - 1 {
+                edges.extend([(i, i + width), (i + width, i)]);
             }
-            origin_map.insert(id, elems);
         }
+        OpenStreetMap::from_locations(&locations, &edges)
+    }
 
-        let (_, id_list) = origin_map.into_iter().max_by_key(|(_, v)| v.len()).unwrap();
+    /// which nodes are copies made for turn restrictions
+    pub fn restriction_copies(&self) -> &Copies {
+        &self.copies
+    }
+
+    /// the OSM id of the node `id`
+    pub fn osm_id(&self, id: u32) -> i64 {
+        self.idx_to_osm[id as usize]
+    }
+
+    /// the id of the node with OSM id `osm_id`, if it is in the map
+    #[allow(dead_code)]
+    pub fn id_of_osm(&self, osm_id: i64) -> Option<u32> {
+        let idx = self
+            .osm_order
+            .partition_point(|&id| self.idx_to_osm[id as usize] < osm_id);
+
+        self.osm_order
+            .get(idx)
+            .cloned()
+            .filter(|&id| self.idx_to_osm[id as usize] == osm_id)
+    }
+
+    /// the profile the map was parsed with
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    /// Splits the graph into strongly connected components (Kosaraju's
+    /// algorithm). Each component is a list of node ids.
+    fn strongly_connected_components(&self) -> Vec<Vec<u32>> {
+        let node_count = self.node_count();
+
+        // first pass: order nodes by the time their forward DFS finishes
+        let mut visited = vec![false; node_count];
+        let mut finished = Vec::with_capacity(node_count);
+
+        for root in 0..node_count as u32 {
+            if visited[root as usize] {
+                continue;
+            }
+            visited[root as usize] = true;
+
+            let mut stack = vec![(root, self.next_to_id(root))];
+            while let Some((id, neighbors)) = stack.last_mut() {
+                match neighbors.next() {
+                    Some(next) => {
+                        if !visited[next as usize] {
+                            visited[next as usize] = true;
+                            stack.push((next, self.next_to_id(next)));
+                        }
+                    }
+                    None => {
+                        finished.push(*id);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+
+        // second pass: everything that can reach a root (in reverse finishing order)
+        // and has not been claimed yet is in its component
+        let mut claimed = vec![false; node_count];
+        let mut components = Vec::new();
+
+        for &root in finished.iter().rev() {
+            if claimed[root as usize] {
+                continue;
+            }
+            claimed[root as usize] = true;
+
+            let mut elems = vec![root];
+            let mut stack = vec![root];
+            while let Some(id) = stack.pop() {
+                for prev in self.prev_to_id(id) {
+                    if !claimed[prev as usize] {
+                        claimed[prev as usize] = true;
+                        elems.push(prev);
+                        stack.push(prev);
+                    }
+                }
+            }
+            components.push(elems);
+        }
+
+        components
+    }
+
+    /// Keeps only the largest strongly connected component. Now that edges are
+    /// directed, a component that is merely connected can still contain
+    /// pairs with no path between them.
+    #[allow(dead_code)]
+    pub fn trim(&self) -> OpenStreetMap {
+        let mut id_list = self
+            .strongly_connected_components()
+            .into_iter()
+            .max_by_key(|elems| elems.len())
+            .unwrap();
+
+        // keep the original relative order so nearby nodes stay nearby in memory
+        id_list.sort_unstable();
 
         println!("combining!");
-        let mut counter = 0;
 
         let old_id_to_new: HashMap<u32, u32> = id_list
             .iter()
-            .map(|&old_id| {
-                let counter_local = counter;
-                counter += 1;
-                (old_id, counter_local)
-            })
+            .enumerate()
+            .map(|(new_id, &old_id)| (old_id, new_id as u32))
             .collect();
 
         let mut new_nodes = Vec::new();
+        let mut new_osm_ids = Vec::new();
 
         for old_id in id_list {
+            new_osm_ids.push(self.osm_id(old_id));
+
             let node = self.get(old_id);
             let result_vec: Vec<_> = node
                 .connected
                 .iterator()
-                .filter_map(|x| old_id_to_new.get(x).cloned())
+                .filter_map(|edge| {
+                    let old_to = edge.node;
+                    old_id_to_new
+                        .get(&old_to)
+                        .map(|&new_to| edge.with_node(new_to))
+                })
                 .collect();
 
             assert_ne!(result_vec.len(), 0);
@@ -256,14 +751,13 @@ impl OpenStreetMap {
             let compact = CompactVec::from_vec(result_vec);
             let new_node = Node {
                 connected: compact,
+                incoming: CompactVec::empty(),
                 location: node.location,
             };
             new_nodes.push(new_node);
         }
 
-        OpenStreetMap {
-            idx_to_node: new_nodes,
-        }
+        OpenStreetMap::from_nodes(new_nodes, new_osm_ids, self.profile)
     }
     pub fn get(&self, id: u32) -> &Node {
         self.idx_to_node.get(id as usize).unwrap()
@@ -272,50 +766,70 @@ impl OpenStreetMap {
         self.idx_to_node.len()
     }
 
-    pub fn next_to_id(&self, from_id: u32) -> CompactVecIterator<'_, u32> {
+    pub fn edges_from(&self, from_id: u32) -> CompactVecIterator<'_, Edge> {
         self.get(from_id).connected.iterator()
     }
 
+    /// the incoming edges of `to_id`. `Edge::node` is the node each one starts
+    /// at.
+    pub fn edges_to(&self, to_id: u32) -> CompactVecIterator<'_, Edge> {
+        self.get(to_id).incoming.iterator()
+    }
+
+    pub fn edges(&self, id: u32, direction: Direction) -> CompactVecIterator<'_, Edge> {
+        match direction {
+            Direction::Forward => self.edges_from(id),
+            Direction::Backward => self.edges_to(id),
+        }
+    }
+
     pub fn random(&self) -> (u32, &Node) {
         let rng = &mut rand::thread_rng();
         let idx = rng.gen_range(0, self.idx_to_node.len());
         (idx as u32, &self.idx_to_node[idx])
     }
-    pub fn closest(&self, lat: f64, long: f64) -> Option<ClosestResult> {
-        let mut min_id = None;
-        let mut min_val = f64::MAX;
-        self.idx_to_node.iter().enumerate().for_each(|(id, node)| {
-            if node.connected.len() == 0 {
-                // if there are no direct connections
-                return;
-            }
-            let Location(nlat, nlong) = node.location;
-            let dlat = nlat - lat;
-            let dlong = nlong - long;
-            let num = dlat * dlat + dlong * dlong;
-            if num < min_val {
-                min_val = num;
-                min_id = Some(id)
-            }
-        });
-        min_id.map(|_| ClosestResult {
-            id: min_id.unwrap() as u32,
-            dist: min_val,
-        })
+    /// The closest node to a point which has outgoing edges. Copies of nodes
+    /// made for turn restrictions are never returned; the original is.
+    #[allow(dead_code)]
+    pub fn closest(&self, location: Location) -> Option<ClosestResult> {
+        self.index
+            .closest(location)
+            .map(|(id, dist)| ClosestResult { dist, id })
+    }
+
+    /// the `k` closest nodes to `location`, closest first, like `closest`
+    #[allow(dead_code)]
+    pub fn k_closest(&self, location: Location, k: usize) -> Vec<ClosestResult> {
+        self.index
+            .k_closest(location, k)
+            .into_iter()
+            .map(|(id, dist)| ClosestResult { dist, id })
+            .collect()
+    }
+
+    /// every node within `radius` metres of `location`, closest first, like
+    /// `closest`
+    #[allow(dead_code)]
+    pub fn within(&self, location: Location, radius: f64) -> Vec<ClosestResult> {
+        self.index
+            .within(location, radius)
+            .into_iter()
+            .map(|(id, dist)| ClosestResult { dist, id })
+            .collect()
     }
 
     #[inline]
-    fn valid_way(way: &osmpbf::Way) -> bool {
-        way.tags().into_iter().any(|(key, _)| key == "highway")
+    fn valid_way(way: &osmpbf::Way, profile: Profile) -> bool {
+        profile.valid_way(way.tags())
     }
 
-    pub fn parse_highway_nodes(name: &str) -> Result<HashSet<i64>, io::Error> {
+    pub fn parse_highway_nodes(name: &str, profile: Profile) -> Result<HashSet<i64>, io::Error> {
         let reader = ElementReader::from_path(name)?;
         let mut valid_nodes = HashSet::new();
 
         reader.for_each(|x| {
             if let osmpbf::Element::Way(way) = x {
-                if OpenStreetMap::valid_way(&way) {
+                if OpenStreetMap::valid_way(&way, profile) {
                     for r in way.refs() {
                         valid_nodes.insert(r);
                     }
@@ -326,10 +840,18 @@ impl OpenStreetMap {
         Ok(valid_nodes)
     }
 
-    pub fn parse(name: &str) -> Result<OpenStreetMap, io::Error> {
-        let valid = OpenStreetMap::parse_highway_nodes(name)?;
+    #[allow(dead_code)]
+    pub fn parse(name: &str, profile: Profile) -> Result<OpenStreetMap, io::Error> {
+        OpenStreetMap::parse_raw(name, profile).map(OpenStreetMap::from_raw)
+    }
+
+    /// Parses the nodes of the roads `profile` can use from a PBF file.
+    pub fn parse_raw(name: &str, profile: Profile) -> Result<RawMap, io::Error> {
+        let valid = OpenStreetMap::parse_highway_nodes(name, profile)?;
         let mut id_to_idx = HashMap::new();
         let mut idx_to_node = Vec::new();
+        let mut idx_to_osm = Vec::new();
+        let mut restrictions = Vec::new();
 
         let reader = ElementReader::from_path(name)?;
 
@@ -345,16 +867,333 @@ impl OpenStreetMap {
                     let to_insert = Node {
                         location,
                         connected: CompactVec::empty(),
+                        incoming: CompactVec::empty(),
                     };
                     idx_to_node.push(to_insert);
+                    idx_to_osm.push(id);
                 }
             } else if let osmpbf::Element::Way(way) = &element {
-                process_way(&mut id_to_idx, &mut idx_to_node, &way)
+                process_way(&id_to_idx, &mut idx_to_node, way, profile)
+            } else if let osmpbf::Element::Relation(relation) = &element {
+                restrictions.extend(TurnRestriction::from_relation(relation, profile));
             }
         })?;
 
+        turn_restriction::apply(&mut idx_to_node, &mut idx_to_osm, &id_to_idx, &restrictions);
+
         // prune
 
-        Ok(OpenStreetMap { idx_to_node })
+        Ok(RawMap {
+            nodes: idx_to_node,
+            osm_ids: idx_to_osm,
+            profile,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, fs::File, io::Write, path::PathBuf};
+
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    use crate::{
+        edge::Highway,
+        graph::{Direction, Graph},
+        map_file::MapFileError,
+        osm_parser::{Location, Oneway, OpenStreetMap},
+        profile::Profile,
+    };
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ai_osm_{}_{}.save", name, std::process::id()))
+    }
+
+    #[test]
+    fn oneway_tags() {
+        let oneway =
+            |tags: &[(&'static str, &'static str)]| Oneway::from_tags(tags.iter().cloned());
+
+        assert_eq!(Oneway::No, oneway(&[("highway", "residential")]));
+        assert_eq!(Oneway::Forward, oneway(&[("oneway", "yes")]));
+        assert_eq!(Oneway::Forward, oneway(&[("oneway", "true")]));
+        assert_eq!(Oneway::Forward, oneway(&[("oneway", "1")]));
+        assert_eq!(Oneway::Backward, oneway(&[("oneway", "-1")]));
+        assert_eq!(Oneway::Forward, oneway(&[("junction", "roundabout")]));
+        assert_eq!(Oneway::Forward, oneway(&[("highway", "motorway")]));
+        assert_eq!(
+            Oneway::No,
+            oneway(&[("highway", "motorway"), ("oneway", "no")])
+        );
+    }
+
+    #[test]
+    fn incoming_mirrors_connected() {
+        let map = OpenStreetMap::from_edges(3, &[(0, 1), (1, 2), (2, 1)]);
+
+        assert_eq!(Vec::<u32>::new(), map.prev_to_id(0).collect::<Vec<_>>());
+        assert_eq!(vec![0, 2], map.prev_to_id(1).collect::<Vec<_>>());
+        assert_eq!(vec![1], map.prev_to_id(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn trim_keeps_strongly_connected() {
+        // 0 -> 1 <-> 2 <-> 3, node 0 can never be reached
+        let map = OpenStreetMap::from_edges(4, &[(0, 1), (1, 2), (2, 1), (2, 3), (3, 2)]);
+        let trimmed = map.trim();
+
+        assert_eq!(3, trimmed.node_count());
+        assert_eq!(101, trimmed.osm_id(0));
+        assert_eq!(Some(2), trimmed.id_of_osm(103));
+        assert_eq!(None, trimmed.id_of_osm(100));
+        for id in 0..3 {
+            assert_ne!(0, trimmed.get(id).connected.len());
+            assert_ne!(0, trimmed.get(id).incoming.len());
+        }
+    }
+
+    #[test]
+    fn haversine() {
+        // a degree of longitude in Minnesota is far shorter than one of latitude
+        let east = Location(-93.0, 45.0).dist_metres(Location(-92.0, 45.0));
+        let north = Location(-93.0, 44.0).dist_metres(Location(-93.0, 45.0));
+        assert!((east - 78_626.3).abs() < 1.0);
+        assert!((north - 111_195.1).abs() < 1.0);
+
+        // downtown Minneapolis to downtown St. Paul
+        let minneapolis = Location(-93.265, 44.978);
+        let st_paul = Location(-93.094, 44.954);
+        assert!((minneapolis.dist_miles(st_paul) - 8.522).abs() < 0.001);
+    }
+
+    #[test]
+    fn closest() {
+        let map = OpenStreetMap::from_edges(4, &[(0, 1), (1, 2), (2, 1), (1, 0)]);
+
+        // node 3 has no edges, so it is never the closest
+        assert_eq!(2, map.closest(Location(2.9, 0.0)).unwrap().id);
+
+        let ids: Vec<_> = map
+            .k_closest(Location(0.6, 0.0), 2)
+            .into_iter()
+            .map(|result| result.id)
+            .collect();
+        assert_eq!(vec![1, 0], ids);
+
+        let ids: Vec<_> = map
+            .within(Location(0.0, 0.0), 150_000.0)
+            .into_iter()
+            .map(|result| result.id)
+            .collect();
+        assert_eq!(vec![0, 1], ids);
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let map = OpenStreetMap::from_edges(3, &[(0, 1), (1, 2), (2, 0)]);
+        let path = temp_file("round_trip");
+        map.save(path.to_str().unwrap()).unwrap();
+
+        let header = OpenStreetMap::read_header(path.to_str().unwrap()).unwrap();
+        assert_eq!(crate::map_file::VERSION, header.version);
+        assert_eq!(3, header.node_count);
+        assert_eq!(2.0, header.bounds.unwrap().to.x());
+
+        let index_path = OpenStreetMap::index_file(path.to_str().unwrap());
+        assert!(fs::metadata(&index_path).is_ok());
+
+        let read = OpenStreetMap::read_custom_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&index_path).unwrap();
+
+        assert_eq!(3, read.node_count());
+        assert_eq!(1, read.closest(Location(0.9, 0.2)).unwrap().id);
+        assert_eq!(Profile::Car, read.profile());
+        assert_eq!(102, read.osm_id(2));
+        assert_eq!(vec![0], read.next_to_id(2).collect::<Vec<_>>());
+        assert_eq!(vec![1], read.prev_to_id(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn stale_index_is_rebuilt() {
+        let map = OpenStreetMap::from_edges(3, &[(0, 1), (1, 2), (2, 0)]);
+        let path = temp_file("stale_index");
+        let name = path.to_str().unwrap();
+        map.save(name).unwrap();
+
+        // only node 0 has an edge, so only it is in this index
+        let other = OpenStreetMap::from_edges(3, &[(0, 1)]);
+        let other_path = temp_file("stale_index_other");
+        let other_name = other_path.to_str().unwrap();
+        other.save(other_name).unwrap();
+
+        let index = OpenStreetMap::index_file(name);
+        let other_index = OpenStreetMap::index_file(other_name);
+        fs::copy(&other_index, &index).unwrap();
+        let read = OpenStreetMap::read_custom_file(name).unwrap();
+        assert_eq!(2, read.closest(Location(2.1, 0.0)).unwrap().id);
+
+        let bytes = fs::read(&other_index).unwrap();
+        fs::write(&index, &bytes[..bytes.len() / 2]).unwrap();
+        let read = OpenStreetMap::read_custom_file(name).unwrap();
+        assert_eq!(2, read.closest(Location(2.1, 0.0)).unwrap().id);
+
+        for file in [name, other_name, &index, &other_index] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn save_corrupt() {
+        let map = OpenStreetMap::from_edges(3, &[(0, 1), (1, 2), (2, 0)]);
+        let path = temp_file("corrupt");
+        let name = path.to_str().unwrap();
+        map.save(name).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        let mut flipped = bytes.clone();
+        flipped[60] ^= 1;
+        fs::write(&path, &flipped).unwrap();
+        let err = OpenStreetMap::read_custom_file(name).err().unwrap();
+        assert!(matches!(err, MapFileError::ChecksumMismatch { .. }));
+
+        fs::write(&path, &bytes[..bytes.len() - 10]).unwrap();
+        let err = OpenStreetMap::read_custom_file(name).err().unwrap();
+        assert!(matches!(err, MapFileError::Truncated));
+
+        fs::write(&path, b"PK\x03\x04 not a map").unwrap();
+        let err = OpenStreetMap::read_custom_file(name).err().unwrap();
+        assert!(matches!(err, MapFileError::BadMagic));
+
+        let mut newer = bytes;
+        newer[5] = 99;
+        fs::write(&path, &newer).unwrap();
+        let err = OpenStreetMap::read_custom_file(name).err().unwrap();
+        assert!(matches!(err, MapFileError::UnsupportedVersion(99)));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(OpenStreetMap::index_file(name)).unwrap();
+    }
+
+    #[test]
+    fn read_version_0() {
+        let path = temp_file("version_0");
+        let mut file = File::create(&path).unwrap();
+
+        // node count, then x, y, neighbour count and neighbours. The road from 1
+        // to 2 is only listed under 1.
+        file.write_u32::<BigEndian>(3).unwrap();
+        for (x, neighbours) in [(0.0, vec![1]), (1.0, vec![0, 2]), (2.0, vec![])] {
+            file.write_f64::<BigEndian>(x).unwrap();
+            file.write_f64::<BigEndian>(0.0).unwrap();
+            file.write_u8(neighbours.len() as u8).unwrap();
+            for neighbour in neighbours {
+                file.write_u32::<BigEndian>(neighbour).unwrap();
+            }
+        }
+        file.flush().unwrap();
+
+        let name = path.to_str().unwrap();
+        let header = OpenStreetMap::read_header(name).unwrap();
+        let map = OpenStreetMap::read_custom_file(name).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!((0, 3), (header.version, header.node_count));
+        assert_eq!(Profile::Car, map.profile());
+        assert_eq!(2, map.osm_id(2));
+        assert_eq!(vec![1], map.next_to_id(2).collect::<Vec<_>>());
+        let edge = map.edges(0, Direction::Forward).next().unwrap();
+        let degree = Location(0.0, 0.0).dist_metres(Location(1.0, 0.0));
+        assert!((edge.length as f64 - degree).abs() < 1.0);
+    }
+
+    #[test]
+    fn read_with_profile() {
+        let path = temp_file("with_profile");
+        let mut file = File::create(&path).unwrap();
+
+        // profile, node count, then x, y, edge count and edges with their tags
+        file.write_u8(Profile::Bike as u8).unwrap();
+        file.write_u32::<BigEndian>(2).unwrap();
+        for (x, to) in [(0.0, 1), (1.0, 0)] {
+            file.write_f64::<BigEndian>(x).unwrap();
+            file.write_f64::<BigEndian>(0.0).unwrap();
+            file.write_u8(1).unwrap();
+            file.write_u32::<BigEndian>(to).unwrap();
+            file.write_f32::<BigEndian>(5.0).unwrap();
+            file.write_u8(Highway::Cycleway as u8).unwrap();
+            file.write_u8(0).unwrap();
+            file.write_i64::<BigEndian>(3).unwrap();
+        }
+        file.flush().unwrap();
+
+        let map = OpenStreetMap::read_custom_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(Profile::Bike, map.profile());
+        let edge = map.edges(1, Direction::Forward).next().unwrap();
+        assert_eq!(
+            (0, 5.0, Highway::Cycleway),
+            (edge.node, edge.length, edge.highway())
+        );
+    }
+
+    #[test]
+    fn read_with_osm_ids() {
+        let path = temp_file("with_osm_ids");
+        let mut file = File::create(&path).unwrap();
+
+        // profile, node count, then x, y, OSM id, edge count and edges
+        file.write_u8(Profile::Bike as u8).unwrap();
+        file.write_u32::<BigEndian>(2).unwrap();
+        for (x, osm_id, to) in [(0.0, 7, 1), (1.0, 9, 0)] {
+            file.write_f64::<BigEndian>(x).unwrap();
+            file.write_f64::<BigEndian>(0.0).unwrap();
+            file.write_i64::<BigEndian>(osm_id).unwrap();
+            file.write_u8(1).unwrap();
+            file.write_u32::<BigEndian>(to).unwrap();
+            file.write_f32::<BigEndian>(5.0).unwrap();
+            file.write_u8(Highway::Cycleway as u8).unwrap();
+            file.write_u8(0).unwrap();
+            file.write_i64::<BigEndian>(3).unwrap();
+        }
+        file.flush().unwrap();
+
+        let map = OpenStreetMap::read_custom_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(Profile::Bike, map.profile());
+        assert_eq!(Some(1), map.id_of_osm(9));
+        assert_eq!(vec![1], map.next_to_id(0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn read_with_tags() {
+        let path = temp_file("with_tags");
+        let mut file = File::create(&path).unwrap();
+
+        // node count, then x, y, edge count and edges, one way from 0 to 1
+        file.write_u32::<BigEndian>(2).unwrap();
+        for (x, edges) in [(0.0, 1), (1.0, 0)] {
+            file.write_f64::<BigEndian>(x).unwrap();
+            file.write_f64::<BigEndian>(0.0).unwrap();
+            file.write_u8(edges).unwrap();
+            for _ in 0..edges {
+                file.write_u32::<BigEndian>(1).unwrap();
+                file.write_f32::<BigEndian>(5.0).unwrap();
+                file.write_u8(Highway::Primary as u8).unwrap();
+                file.write_u8(50).unwrap();
+                file.write_i64::<BigEndian>(3).unwrap();
+            }
+        }
+        file.flush().unwrap();
+
+        let map = OpenStreetMap::read_custom_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(Profile::Car, map.profile());
+        assert_eq!(0, map.next_to_id(1).count());
+        let edge = map.edges(0, Direction::Forward).next().unwrap();
+        assert_eq!((50, 3), (edge.max_speed, edge.way_id));
     }
 }
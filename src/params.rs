@@ -1,18 +1,173 @@
-use crate::osm_parser::Node;
+use crate::{
+    edge::{Edge, Highway},
+    graph::Located,
+    osm_parser::OpenStreetMap,
+    profile::Profile,
+};
 
 pub trait Params<T>: std::marker::Sync {
     fn heuristic(&self, on: &T, goal: &T) -> f64;
-    fn neighbor_dist(&self, on: &T, next: &T) -> f64;
+    /// `heuristic` from the node numbered `on_id` to the one numbered
+    /// `goal_id`. This is what the searches call, so params which keep tables
+    /// by node can look them up.
+    fn heuristic_between(&self, on_id: u32, on: &T, goal_id: u32, goal: &T) -> f64 {
+        let _ = (on_id, goal_id);
+        self.heuristic(on, goal)
+    }
+    /// the cost of travelling `edge`, which goes from `on` to `next`
+    fn neighbor_dist(&self, on: &T, next: &T, edge: &Edge) -> f64;
 }
 
+/// Shortest distance in metres.
 pub struct SimpleParams;
 
-impl Params<Node> for SimpleParams {
-    fn heuristic(&self, on: &Node, goal: &Node) -> f64 {
-        on.location.dist(goal.location)
+impl<T: Located> Params<T> for SimpleParams {
+    fn heuristic(&self, on: &T, goal: &T) -> f64 {
+        on.location().dist_metres(goal.location())
+    }
+
+    fn neighbor_dist(&self, _on: &T, _next: &T, edge: &Edge) -> f64 {
+        edge.length as f64
+    }
+}
+
+/// The speed in km/h a car is assumed to drive on a way without a `maxspeed`
+/// tag.
+pub fn default_speed(highway: Highway) -> u8 {
+    match highway {
+        Highway::Motorway => 105,
+        Highway::MotorwayLink => 70,
+        Highway::Trunk => 90,
+        Highway::TrunkLink => 60,
+        Highway::Primary => 80,
+        Highway::PrimaryLink => 50,
+        Highway::Secondary => 70,
+        Highway::SecondaryLink => 45,
+        Highway::Tertiary => 55,
+        Highway::TertiaryLink => 40,
+        Highway::Unclassified => 50,
+        Highway::Residential => 40,
+        Highway::LivingStreet => 10,
+        Highway::Service => 20,
+        Highway::Track => 15,
+        Highway::Road => 40,
+        Highway::Pedestrian | Highway::Footway | Highway::Path => 5,
+        Highway::Cycleway => 15,
+        Highway::Steps => 3,
+        Highway::Other => 30,
     }
+}
+
+/// Fastest travel time in seconds.
+///
+/// A car drives at the `maxspeed` of an edge, or `default_speed` of its highway
+/// class if it has none. Bikes and pedestrians travel at a constant speed, but
+/// never faster than the limit.
+pub struct TravelTimeParams {
+    profile: Profile,
+    /// the fastest speed of any edge in the map, in m/s. Dividing a straight
+    /// line distance by it can never overestimate the travel time, so the
+    /// heuristic stays admissible.
+    max_speed: f64,
+}
+
+impl TravelTimeParams {
+    #[allow(dead_code)]
+    pub fn new(map: &OpenStreetMap) -> TravelTimeParams {
+        let profile = map.profile();
+        let max_speed = map
+            .iterator()
+            .flat_map(|node| node.connected.iterator())
+            .map(|edge| TravelTimeParams::profile_speed(profile, edge))
+            .fold(0.0, f64::max);
+
+        TravelTimeParams {
+            profile,
+            max_speed: max_speed.max(1.0),
+        }
+    }
+
+    fn profile_speed(profile: Profile, edge: &Edge) -> f64 {
+        let limit = match edge.max_speed {
+            0 => default_speed(edge.highway()),
+            max_speed => max_speed,
+        };
+        let km_h = match profile {
+            Profile::Car => limit,
+            Profile::Bike => limit.min(15),
+            Profile::Foot => limit.min(5),
+        };
+        km_h as f64 / 3.6
+    }
+
+    /// the speed along `edge` in m/s
+    pub fn speed(&self, edge: &Edge) -> f64 {
+        TravelTimeParams::profile_speed(self.profile, edge)
+    }
+}
+
+impl<T: Located> Params<T> for TravelTimeParams {
+    fn heuristic(&self, on: &T, goal: &T) -> f64 {
+        on.location().dist_metres(goal.location()) / self.max_speed
+    }
+
+    fn neighbor_dist(&self, _on: &T, _next: &T, edge: &Edge) -> f64 {
+        edge.length as f64 / self.speed(edge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compact_array::CompactVec,
+        edge::{Edge, Highway},
+        osm_parser::{Location, Node},
+        params::{Params, TravelTimeParams},
+        profile::Profile,
+    };
+
+    fn node(x: f64) -> Node {
+        Node {
+            connected: CompactVec::empty(),
+            incoming: CompactVec::empty(),
+            location: Location(x, 0.0),
+        }
+    }
+
+    #[test]
+    fn travel_time() {
+        let params = TravelTimeParams {
+            profile: Profile::Car,
+            max_speed: 30.0,
+        };
+        let (on, next) = (node(0.0), node(0.01));
+        let length = on.location.dist_metres(next.location) as f32;
+
+        let tagged = Edge {
+            node: 1,
+            length,
+            highway: Highway::Residential as u8,
+            max_speed: 72,
+            way_id: 0,
+        };
+        let untagged = Edge {
+            max_speed: 0,
+            ..tagged
+        };
+
+        // 72 km/h is 20 m/s, a residential road defaults to 40 km/h
+        let tagged_time = params.neighbor_dist(&on, &next, &tagged);
+        let untagged_time = params.neighbor_dist(&on, &next, &untagged);
+        assert!((tagged_time - length as f64 / 20.0).abs() < 1e-6);
+        assert!((untagged_time - length as f64 * 3.6 / 40.0).abs() < 1e-6);
+
+        assert!(params.heuristic(&on, &next) <= tagged_time);
 
-    fn neighbor_dist(&self, on: &Node, next: &Node) -> f64 {
-        on.location.dist(next.location)
+        let walking = TravelTimeParams {
+            profile: Profile::Foot,
+            max_speed: 5.0 / 3.6,
+        };
+        let walking_time = walking.neighbor_dist(&on, &next, &tagged);
+        assert!((walking_time - length as f64 * 3.6 / 5.0).abs() < 1e-6);
     }
 }
@@ -0,0 +1,242 @@
+use std::{
+    error::Error,
+    fmt,
+    fmt::{Display, Formatter},
+};
+
+use crate::{
+    a_star,
+    a_star::Path,
+    graph::Graph,
+    osm_parser::{Location, OpenStreetMap},
+};
+
+/// Google's precision, 5 decimal places of a degree, about a metre.
+#[allow(dead_code)]
+pub const PRECISION_5: u32 = 5;
+/// The precision OSRM and Valhalla can use, about 10cm.
+#[allow(dead_code)]
+pub const PRECISION_6: u32 = 6;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PolylineError {
+    /// a character outside the range the format uses, at this byte
+    BadCharacter(usize),
+    /// the polyline ends part way through a number, or with only a latitude
+    Truncated,
+    /// two of the snapped points are not connected by any road
+    NoRoute { from: u32, to: u32 },
+    /// the map has no nodes to snap to
+    EmptyMap,
+}
+
+impl Display for PolylineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PolylineError::BadCharacter(at) => write!(f, "bad polyline character at {}", at),
+            PolylineError::Truncated => write!(f, "polyline is truncated"),
+            PolylineError::NoRoute { from, to } => {
+                write!(f, "no route from node {} to node {}", from, to)
+            }
+            PolylineError::EmptyMap => write!(f, "no nodes to snap the polyline to"),
+        }
+    }
+}
+
+impl Error for PolylineError {}
+
+fn encode_number(number: i64, into: &mut String) {
+    // the sign goes in the lowest bit
+    let signed = if number < 0 {
+        !(number << 1)
+    } else {
+        number << 1
+    };
+    let mut value = signed as u64;
+    while value >= 0x20 {
+        into.push((((value & 0x1f) | 0x20) as u8 + 63) as char);
+        value >>= 5;
+    }
+    into.push((value as u8 + 63) as char);
+}
+
+/// `locations` in Google's encoded polyline format with `precision` decimal
+/// places. Each point is latitude then longitude, the difference from the
+/// point before in 5 bit chunks.
+#[allow(dead_code)]
+pub fn encode(locations: &[Location], precision: u32) -> String {
+    let factor = 10f64.powi(precision as i32);
+    let mut encoded = String::new();
+    let (mut lat, mut lon) = (0, 0);
+    for location in locations {
+        let (next_lat, next_lon) = (
+            (location.y() * factor).round() as i64,
+            (location.x() * factor).round() as i64,
+        );
+        encode_number(next_lat - lat, &mut encoded);
+        encode_number(next_lon - lon, &mut encoded);
+        lat = next_lat;
+        lon = next_lon;
+    }
+    encoded
+}
+
+/// The points of a polyline encoded with `precision` decimal places.
+#[allow(dead_code)]
+pub fn decode(encoded: &str, precision: u32) -> Result<Vec<Location>, PolylineError> {
+    let factor = 10f64.powi(precision as i32);
+    let mut bytes = encoded.bytes().enumerate();
+    let mut number = || -> Result<Option<i64>, PolylineError> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let Some((at, byte)) = bytes.next() else {
+                return match shift {
+                    0 => Ok(None),
+                    _ => Err(PolylineError::Truncated),
+                };
+            };
+            if !(63..127).contains(&byte) || shift > 60 {
+                return Err(PolylineError::BadCharacter(at));
+            }
+            let chunk = (byte - 63) as u64;
+            value |= (chunk & 0x1f) << shift;
+            shift += 5;
+            if chunk < 0x20 {
+                let number = (value >> 1) as i64;
+                return Ok(Some(if value & 1 == 1 { !number } else { number }));
+            }
+        }
+    };
+
+    let mut locations = Vec::new();
+    let (mut lat, mut lon) = (0, 0);
+    while let Some(dlat) = number()? {
+        let dlon = number()?.ok_or(PolylineError::Truncated)?;
+        lat += dlat;
+        lon += dlon;
+        locations.push(Location(lon as f64 / factor, lat as f64 / factor));
+    }
+    Ok(locations)
+}
+
+/// The nodes of `path` as a polyline.
+#[allow(dead_code)]
+pub fn encode_path<G: Graph>(path: &Path<G>, precision: u32) -> String {
+    let map = path.parent_map;
+    let locations: Vec<_> = path.ids.iter().map(|&id| map.location(id)).collect();
+    encode(&locations, precision)
+}
+
+/// A polyline snapped back onto `map`: each point goes to its closest node,
+/// and nodes which are not next to each other, where the polyline was
+/// simplified or came from another map, are joined by the shortest path.
+#[allow(dead_code)]
+pub fn decode_path<'a>(
+    map: &'a OpenStreetMap,
+    encoded: &str,
+    precision: u32,
+) -> Result<Path<'a>, PolylineError> {
+    let mut ids: Vec<u32> = Vec::new();
+    for location in decode(encoded, precision)? {
+        let id = map.closest(location).ok_or(PolylineError::EmptyMap)?.id;
+        let Some(&last) = ids.last() else {
+            ids.push(id);
+            continue;
+        };
+        if last == id {
+            continue;
+        }
+        if map.next_to_id(last).any(|next| next == id) {
+            ids.push(id);
+        } else {
+            let between =
+                a_star::path(map, last, id).ok_or(PolylineError::NoRoute { from: last, to: id })?;
+            ids.extend(&between.ids[1..]);
+        }
+    }
+
+    Ok(Path {
+        ids,
+        parent_map: map,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        a_star,
+        osm_parser::{Location, OpenStreetMap},
+        polyline::{
+            decode, decode_path, encode, encode_path, PolylineError, PRECISION_5, PRECISION_6,
+        },
+    };
+
+    fn close(a: Location, b: Location, precision: u32) -> bool {
+        let tolerance = 0.5 / 10f64.powi(precision as i32) + 1e-12;
+        (a.x() - b.x()).abs() <= tolerance && (a.y() - b.y()).abs() <= tolerance
+    }
+
+    #[test]
+    fn googles_example() {
+        let points = [
+            Location(-120.2, 38.5),
+            Location(-120.95, 40.7),
+            Location(-126.453, 43.252),
+        ];
+        let encoded = encode(&points, PRECISION_5);
+        assert_eq!("_p~iF~ps|U_ulLnnqC_mqNvxq`@", encoded);
+
+        let decoded = decode(&encoded, PRECISION_5).unwrap();
+        assert_eq!(3, decoded.len());
+        for (a, b) in points.iter().zip(&decoded) {
+            assert!(close(*a, *b, PRECISION_5));
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let points = [
+            Location(-93.2650108, 44.9777531),
+            Location(-93.2650109, 44.9777532),
+            Location(0.0, 0.0),
+            Location(179.9999994, -89.9999996),
+        ];
+        for precision in [PRECISION_5, PRECISION_6] {
+            let decoded = decode(&encode(&points, precision), precision).unwrap();
+            assert_eq!(points.len(), decoded.len());
+            for (a, b) in points.iter().zip(&decoded) {
+                assert!(close(*a, *b, precision));
+            }
+        }
+
+        assert!(decode("", PRECISION_5).unwrap().is_empty());
+        assert_eq!(
+            Some(PolylineError::Truncated),
+            decode("_p~iF", PRECISION_5).err()
+        );
+        assert_eq!(
+            Some(PolylineError::Truncated),
+            decode("_p~iF~ps|", PRECISION_5).err()
+        );
+        assert_eq!(
+            Some(PolylineError::BadCharacter(2)),
+            decode("_p iF~ps|U", PRECISION_5).err()
+        );
+    }
+
+    #[test]
+    fn snaps_onto_nodes() {
+        let map = OpenStreetMap::grid(4, 4, 0.001);
+
+        let path = a_star::path(&map, 0, 15).unwrap();
+        for precision in [PRECISION_5, PRECISION_6] {
+            let decoded = decode_path(&map, &encode_path(&path, precision), precision).unwrap();
+            assert_eq!(path.ids, decoded.ids);
+        }
+
+        // only the corners, a little off, are filled in along the roads
+        let corners = [Location(0.00002, -0.00001), Location(0.00301, 0.00003)];
+        let decoded = decode_path(&map, &encode(&corners, PRECISION_6), PRECISION_6).unwrap();
+        assert_eq!(vec![0, 1, 2, 3], decoded.ids);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{edge::Highway, osm_parser::Oneway};
+
+/// The kind of traveller a map is built for. It decides which ways end up in
+/// the graph and whether one-way restrictions apply.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Profile {
+    Car,
+    Bike,
+    Foot,
+}
+
+impl Profile {
+    pub fn from_u8(value: u8) -> Option<Profile> {
+        match value {
+            0 => Some(Profile::Car),
+            1 => Some(Profile::Bike),
+            2 => Some(Profile::Foot),
+            _ => None,
+        }
+    }
+
+    /// Whether ways of this class are usable when no access tag says otherwise.
+    fn allows_class(self, highway: Highway) -> bool {
+        match self {
+            Profile::Car => !matches!(
+                highway,
+                Highway::Track
+                    | Highway::Pedestrian
+                    | Highway::Footway
+                    | Highway::Cycleway
+                    | Highway::Path
+                    | Highway::Steps
+                    | Highway::Other
+            ),
+            Profile::Bike => !matches!(
+                highway,
+                Highway::Motorway
+                    | Highway::MotorwayLink
+                    | Highway::Pedestrian
+                    | Highway::Footway
+                    | Highway::Steps
+                    | Highway::Other
+            ),
+            Profile::Foot => !matches!(
+                highway,
+                Highway::Motorway | Highway::MotorwayLink | Highway::Other
+            ),
+        }
+    }
+
+    /// The access keys that apply to this profile, most specific first.
+    ///
+    /// https://wiki.openstreetmap.org/wiki/Key:access
+    fn access_keys(self) -> &'static [&'static str] {
+        match self {
+            Profile::Car => &["motorcar", "motor_vehicle", "vehicle", "access"],
+            Profile::Bike => &["bicycle", "vehicle", "access"],
+            Profile::Foot => &["foot", "access"],
+        }
+    }
+
+    pub fn valid_way<'a>(self, tags: impl Iterator<Item = (&'a str, &'a str)>) -> bool {
+        let tags: Vec<_> = tags.collect();
+
+        let highway = match tags.iter().find(|(key, _)| *key == "highway") {
+            Some((_, value)) => Highway::from_tag(value),
+            None => return false,
+        };
+
+        let allowed = self.allows_class(highway);
+
+        // the most specific access tag decides
+        for &access_key in self.access_keys() {
+            let value = tags
+                .iter()
+                .find(|(key, _)| *key == access_key)
+                .map(|(_, value)| *value);
+
+            match value {
+                Some("no" | "private") => return false,
+                // a general `access=yes` does not let cars onto a footway, but `bicycle=yes` does
+                // let bikes onto one
+                Some("yes" | "designated" | "permissive" | "destination" | "customers") => {
+                    return allowed || access_key != "access"
+                }
+                _ => {}
+            }
+        }
+
+        allowed
+    }
+
+    pub fn oneway<'a>(self, tags: impl Iterator<Item = (&'a str, &'a str)>) -> Oneway {
+        match self {
+            Profile::Car => Oneway::from_tags(tags),
+            Profile::Bike => {
+                let tags: Vec<_> = tags.collect();
+                let contraflow = tags
+                    .iter()
+                    .any(|&(key, value)| key == "oneway:bicycle" && value == "no");
+                if contraflow {
+                    Oneway::No
+                } else {
+                    Oneway::from_tags(tags.into_iter())
+                }
+            }
+            // pedestrians may walk both ways down a one-way street
+            Profile::Foot => Oneway::No,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{osm_parser::Oneway, profile::Profile};
+
+    fn valid(profile: Profile, tags: &[(&'static str, &'static str)]) -> bool {
+        profile.valid_way(tags.iter().cloned())
+    }
+
+    #[test]
+    fn classes() {
+        let motorway = [("highway", "motorway")];
+        let footway = [("highway", "footway")];
+        let cycleway = [("highway", "cycleway")];
+
+        assert!(valid(Profile::Car, &motorway));
+        assert!(!valid(Profile::Bike, &motorway));
+        assert!(!valid(Profile::Foot, &motorway));
+
+        assert!(!valid(Profile::Car, &footway));
+        assert!(!valid(Profile::Bike, &footway));
+        assert!(valid(Profile::Foot, &footway));
+
+        assert!(!valid(Profile::Car, &cycleway));
+        assert!(valid(Profile::Bike, &cycleway));
+
+        assert!(!valid(Profile::Foot, &[("building", "yes")]));
+    }
+
+    #[test]
+    fn access_tags() {
+        let private = [("highway", "residential"), ("access", "private")];
+        assert!(!valid(Profile::Car, &private));
+        assert!(!valid(Profile::Foot, &private));
+
+        let no_cars = [("highway", "residential"), ("motor_vehicle", "no")];
+        assert!(!valid(Profile::Car, &no_cars));
+        assert!(valid(Profile::Bike, &no_cars));
+
+        let bikes_on_footway = [("highway", "footway"), ("bicycle", "yes")];
+        assert!(valid(Profile::Bike, &bikes_on_footway));
+        assert!(!valid(Profile::Car, &bikes_on_footway));
+
+        let open_footway = [("highway", "footway"), ("access", "yes")];
+        assert!(!valid(Profile::Car, &open_footway));
+
+        let foot_override = [("highway", "service"), ("access", "no"), ("foot", "yes")];
+        assert!(valid(Profile::Foot, &foot_override));
+        assert!(!valid(Profile::Car, &foot_override));
+    }
+
+    #[test]
+    fn oneway() {
+        let oneway = [("highway", "residential"), ("oneway", "yes")];
+        let contraflow = [
+            ("highway", "residential"),
+            ("oneway", "yes"),
+            ("oneway:bicycle", "no"),
+        ];
+
+        assert_eq!(Oneway::Forward, Profile::Car.oneway(oneway.iter().cloned()));
+        assert_eq!(
+            Oneway::Forward,
+            Profile::Bike.oneway(oneway.iter().cloned())
+        );
+        assert_eq!(Oneway::No, Profile::Foot.oneway(oneway.iter().cloned()));
+        assert_eq!(Oneway::No, Profile::Bike.oneway(contraflow.iter().cloned()));
+        assert_eq!(
+            Oneway::Forward,
+            Profile::Car.oneway(contraflow.iter().cloned())
+        );
+    }
+}
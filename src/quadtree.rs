@@ -0,0 +1,627 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    f64::consts::{FRAC_PI_2, PI},
+    io,
+    io::{Read, Write},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    map_file::{ChecksumReader, ChecksumWriter, MapFileError},
+    osm_parser::{Location, EARTH_RADIUS_METRES},
+};
+
+/// The first bytes of an index file written by `QuadTree::write`.
+pub const MAGIC: [u8; 4] = *b"RQTI";
+
+/// Version 2 records the checksum of the map file the index belongs to.
+pub const VERSION: u16 = 2;
+
+/// A quad with at most this many points is not split any further.
+const LEAF_SIZE: usize = 16;
+
+/// Stops many points at the same location from splitting forever.
+const MAX_DEPTH: usize = 32;
+
+/// A region of the tree. The items in it are `ids[start..end]`, whether it is a
+/// leaf or not.
+#[derive(Debug, Copy, Clone)]
+struct Quad {
+    /// While the tree is built this is the region the quad splits. Afterwards
+    /// it is the box around everything in it, which is what searches prune by.
+    min: (f64, f64),
+    max: (f64, f64),
+    /// the index of the first of its four children, or 0 for a leaf
+    children: u32,
+    start: u32,
+    end: u32,
+}
+
+impl Quad {
+    fn is_leaf(&self) -> bool {
+        self.children == 0
+    }
+
+    /// A lower bound of the distance in metres from `location` to anything in
+    /// the quad.
+    ///
+    /// On the unit sphere the quad is a patch between two meridians and two
+    /// parallels. Each coordinate of a point of the patch is a product of
+    /// independent sines and cosines, so the box around the patch comes from
+    /// their ranges. No point of the patch is closer (in a straight line
+    /// through the earth) than that box.
+    fn min_dist(&self, location: Location) -> f64 {
+        if self.min.0 > self.max.0 {
+            // an empty quad
+            return f64::INFINITY;
+        }
+
+        let (min_lon, max_lon) = (self.min.0.to_radians(), self.max.0.to_radians());
+        let (min_lat, max_lat) = (self.min.1.to_radians(), self.max.1.to_radians());
+        let contains = |min: f64, max: f64, angle: f64| min <= angle && angle <= max;
+        let range = |a: f64, b: f64| (a.min(b), a.max(b));
+
+        let (mut cos_lat_min, mut cos_lat_max) = range(min_lat.cos(), max_lat.cos());
+        if contains(min_lat, max_lat, 0.0) {
+            cos_lat_max = 1.0;
+        }
+        cos_lat_min = cos_lat_min.max(0.0);
+
+        let (mut cos_lon_min, mut cos_lon_max) = range(min_lon.cos(), max_lon.cos());
+        if contains(min_lon, max_lon, 0.0) {
+            cos_lon_max = 1.0;
+        }
+        if contains(min_lon, max_lon, PI) || contains(min_lon, max_lon, -PI) {
+            cos_lon_min = -1.0;
+        }
+
+        let (mut sin_lon_min, mut sin_lon_max) = range(min_lon.sin(), max_lon.sin());
+        if contains(min_lon, max_lon, FRAC_PI_2) {
+            sin_lon_max = 1.0;
+        }
+        if contains(min_lon, max_lon, -FRAC_PI_2) {
+            sin_lon_min = -1.0;
+        }
+
+        let product = |(a_min, a_max): (f64, f64), (b_min, b_max): (f64, f64)| {
+            let products = [a_min * b_min, a_min * b_max, a_max * b_min, a_max * b_max];
+            let min = products.iter().cloned().fold(f64::MAX, f64::min);
+            let max = products.iter().cloned().fold(f64::MIN, f64::max);
+            (min, max)
+        };
+
+        let cos_lat = (cos_lat_min, cos_lat_max);
+        let bounds = [
+            product(cos_lat, (cos_lon_min, cos_lon_max)),
+            product(cos_lat, (sin_lon_min, sin_lon_max)),
+            (min_lat.sin(), max_lat.sin()),
+        ];
+
+        let point = unit_vector(location);
+        let chord2: f64 = point
+            .iter()
+            .zip(bounds.iter())
+            .map(|(&value, &(min, max))| {
+                let gap = (min - value).max(value - max).max(0.0);
+                gap * gap
+            })
+            .sum();
+
+        chord_metres(chord2)
+    }
+
+    fn quadrant(&self, location: Location) -> usize {
+        let Location(x, y) = location;
+        let mid_x = (self.min.0 + self.max.0) / 2.0;
+        let mid_y = (self.min.1 + self.max.1) / 2.0;
+        (x >= mid_x) as usize + 2 * (y >= mid_y) as usize
+    }
+}
+
+/// the point on the unit sphere at `location`
+fn unit_vector(location: Location) -> [f64; 3] {
+    let Location(lon, lat) = location;
+    let (lon, lat) = (lon.to_radians(), lat.to_radians());
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+/// the great circle distance between two points a chord of length
+/// `sqrt(chord2)` apart on the unit sphere
+fn chord_metres(chord2: f64) -> f64 {
+    2.0 * EARTH_RADIUS_METRES * (chord2.sqrt() / 2.0).min(1.0).asin()
+}
+
+/// Something waiting in a search queue, closest first.
+struct Candidate {
+    dist: f64,
+    idx: u32,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.dist.partial_cmp(&self.dist).unwrap()
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist.eq(&other.dist)
+    }
+}
+
+impl Eq for Candidate {}
+
+/// A point region quadtree over node locations, stored as two flat arrays.
+///
+/// https://en.wikipedia.org/wiki/Quadtree
+///
+/// Distances are great circle distances in metres, like
+/// `Location::dist_metres`.
+pub struct QuadTree {
+    quads: Vec<Quad>,
+    ids: Vec<u32>,
+    locations: Vec<Location>,
+}
+
+impl QuadTree {
+    /// Builds a tree over the nodes `ids`. `locations` has the location of
+    /// every node, including the ones left out.
+    pub fn new(locations: Vec<Location>, ids: Vec<u32>) -> QuadTree {
+        QuadTree::with_extents(locations, ids, |_, location| (location, location))
+    }
+
+    /// Builds a tree over items which are not points. Each item is placed by
+    /// its entry in `keys`, and `extent` gives the corners of a box around the
+    /// whole item from its id and key. Search it with `k_closest_by` and
+    /// `within_by`.
+    pub fn with_extents(
+        keys: Vec<Location>,
+        mut ids: Vec<u32>,
+        extent: impl Fn(u32, Location) -> (Location, Location),
+    ) -> QuadTree {
+        let mut min = (f64::MAX, f64::MAX);
+        let mut max = (f64::MIN, f64::MIN);
+        for &id in &ids {
+            let Location(x, y) = keys[id as usize];
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+
+        let root = Quad {
+            min,
+            max,
+            children: 0,
+            start: 0,
+            end: ids.len() as u32,
+        };
+
+        let mut quads = vec![root];
+        QuadTree::split(&mut quads, &mut ids, &keys, 0, 0);
+
+        // the regions were only needed to split; searches prune by the box
+        // around what is actually in each quad. Children come after their
+        // parent, so going backwards visits them first.
+        for idx in (0..quads.len()).rev() {
+            let quad = quads[idx];
+            let boxes: Vec<_> = if quad.is_leaf() {
+                ids[quad.start as usize..quad.end as usize]
+                    .iter()
+                    .map(|&id| {
+                        let (Location(min_x, min_y), Location(max_x, max_y)) =
+                            extent(id, keys[id as usize]);
+                        ((min_x, min_y), (max_x, max_y))
+                    })
+                    .collect()
+            } else {
+                (quad.children..quad.children + 4)
+                    .map(|child| (quads[child as usize].min, quads[child as usize].max))
+                    .collect()
+            };
+
+            let quad = &mut quads[idx];
+            quad.min = (f64::MAX, f64::MAX);
+            quad.max = (f64::MIN, f64::MIN);
+            for (min, max) in boxes {
+                quad.min = (quad.min.0.min(min.0), quad.min.1.min(min.1));
+                quad.max = (quad.max.0.max(max.0), quad.max.1.max(max.1));
+            }
+        }
+
+        QuadTree {
+            quads,
+            ids,
+            locations: keys,
+        }
+    }
+
+    fn split(
+        quads: &mut Vec<Quad>,
+        ids: &mut [u32],
+        locations: &[Location],
+        idx: usize,
+        depth: usize,
+    ) {
+        let quad = quads[idx];
+        if (quad.end - quad.start) as usize <= LEAF_SIZE || depth == MAX_DEPTH {
+            return;
+        }
+
+        let points = &mut ids[quad.start as usize..quad.end as usize];
+        points.sort_unstable_by_key(|&id| quad.quadrant(locations[id as usize]));
+
+        let children = quads.len();
+        quads[idx].children = children as u32;
+
+        let mid = (
+            (quad.min.0 + quad.max.0) / 2.0,
+            (quad.min.1 + quad.max.1) / 2.0,
+        );
+        let mut start = quad.start;
+        for quadrant in 0..4 {
+            let count = points
+                .iter()
+                .filter(|&&id| quad.quadrant(locations[id as usize]) == quadrant)
+                .count() as u32;
+
+            let (min_x, max_x) = match quadrant % 2 {
+                0 => (quad.min.0, mid.0),
+                _ => (mid.0, quad.max.0),
+            };
+            let (min_y, max_y) = match quadrant / 2 {
+                0 => (quad.min.1, mid.1),
+                _ => (mid.1, quad.max.1),
+            };
+
+            quads.push(Quad {
+                min: (min_x, min_y),
+                max: (max_x, max_y),
+                children: 0,
+                start,
+                end: start + count,
+            });
+            start += count;
+        }
+
+        for child in children..children + 4 {
+            QuadTree::split(quads, ids, locations, child, depth + 1);
+        }
+    }
+
+    /// the number of nodes in the tree
+    #[allow(dead_code)]
+    pub fn indexed_count(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// the closest node to `location` and its distance
+    pub fn closest(&self, location: Location) -> Option<(u32, f64)> {
+        self.k_closest(location, 1).into_iter().next()
+    }
+
+    /// the `k` closest nodes to `location`, closest first
+    pub fn k_closest(&self, location: Location, k: usize) -> Vec<(u32, f64)> {
+        self.k_closest_by(location, k, |id| {
+            self.locations[id as usize].dist_metres(location)
+        })
+    }
+
+    /// The `k` closest items, closest first, where `dist` is the distance in
+    /// metres from `location` to an item. It must never be less than the
+    /// distance to the extent of the item.
+    pub fn k_closest_by(
+        &self,
+        location: Location,
+        k: usize,
+        dist: impl Fn(u32) -> f64,
+    ) -> Vec<(u32, f64)> {
+        if k == 0 || self.ids.is_empty() {
+            return Vec::new();
+        }
+
+        // the k best so far, with the furthest on top
+        let mut best: BinaryHeap<Reverse<Candidate>> = BinaryHeap::new();
+        let mut queue = BinaryHeap::new();
+        queue.push(Candidate {
+            dist: self.quads[0].min_dist(location),
+            idx: 0,
+        });
+
+        while let Some(Candidate {
+            dist: quad_dist,
+            idx,
+        }) = queue.pop()
+        {
+            if best.len() == k && quad_dist >= best.peek().unwrap().0.dist {
+                break;
+            }
+
+            let quad = &self.quads[idx as usize];
+            if !quad.is_leaf() {
+                for child in quad.children..quad.children + 4 {
+                    queue.push(Candidate {
+                        dist: self.quads[child as usize].min_dist(location),
+                        idx: child,
+                    });
+                }
+                continue;
+            }
+
+            for &id in &self.ids[quad.start as usize..quad.end as usize] {
+                let dist = dist(id);
+                if best.len() < k {
+                    best.push(Reverse(Candidate { dist, idx: id }));
+                } else if dist < best.peek().unwrap().0.dist {
+                    best.pop();
+                    best.push(Reverse(Candidate { dist, idx: id }));
+                }
+            }
+        }
+
+        best.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(candidate)| (candidate.idx, candidate.dist))
+            .collect()
+    }
+
+    /// every node at most `radius` metres from `location`, closest first
+    pub fn within(&self, location: Location, radius: f64) -> Vec<(u32, f64)> {
+        self.within_by(location, radius, |id| {
+            self.locations[id as usize].dist_metres(location)
+        })
+    }
+
+    /// every item at most `radius` from `location`, closest first, with
+    /// `dist` as in `k_closest_by`
+    pub fn within_by(
+        &self,
+        location: Location,
+        radius: f64,
+        dist: impl Fn(u32) -> f64,
+    ) -> Vec<(u32, f64)> {
+        let mut found = Vec::new();
+
+        if self.ids.is_empty() {
+            return found;
+        }
+
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            let quad = &self.quads[idx as usize];
+            if quad.min_dist(location) > radius {
+                continue;
+            }
+
+            if !quad.is_leaf() {
+                stack.extend(quad.children..quad.children + 4);
+                continue;
+            }
+
+            for &id in &self.ids[quad.start as usize..quad.end as usize] {
+                let dist = dist(id);
+                if dist <= radius {
+                    found.push((id, dist));
+                }
+            }
+        }
+
+        found.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        found
+    }
+
+    /// Writes the tree, ending with a CRC-32 like a map file. The locations
+    /// are not written; they come from the map, whose checksum is
+    /// `map_checksum`.
+    pub fn write(&self, writer: impl Write, map_checksum: u32) -> io::Result<()> {
+        let mut writer = ChecksumWriter::new(writer);
+
+        writer.write_all(&MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
+        writer.write_u32::<BigEndian>(self.locations.len() as u32)?;
+        writer.write_u32::<BigEndian>(map_checksum)?;
+        writer.write_u32::<BigEndian>(self.quads.len() as u32)?;
+        writer.write_u32::<BigEndian>(self.ids.len() as u32)?;
+
+        for quad in &self.quads {
+            for value in [quad.min.0, quad.min.1, quad.max.0, quad.max.1] {
+                writer.write_f64::<BigEndian>(value)?;
+            }
+            writer.write_u32::<BigEndian>(quad.children)?;
+            writer.write_u32::<BigEndian>(quad.start)?;
+            writer.write_u32::<BigEndian>(quad.end)?;
+        }
+
+        for &id in &self.ids {
+            writer.write_u32::<BigEndian>(id)?;
+        }
+
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Reads a tree written by `write` for a map with these node `locations`
+    /// and checksum. A tree written for another map is `None`.
+    pub fn read(
+        reader: impl Read,
+        locations: Vec<Location>,
+        map_checksum: u32,
+    ) -> Result<Option<QuadTree>, MapFileError> {
+        let mut reader = ChecksumReader::new(reader);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(MapFileError::BadMagic);
+        }
+
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(MapFileError::UnsupportedVersion(version));
+        }
+
+        let node_count = reader.read_u32::<BigEndian>()?;
+        if node_count as usize != locations.len() || reader.read_u32::<BigEndian>()? != map_checksum
+        {
+            return Ok(None);
+        }
+
+        let quad_count = reader.read_u32::<BigEndian>()?;
+        let id_count = reader.read_u32::<BigEndian>()?;
+
+        // the counts are only trusted as far as the map, so a corrupt file
+        // cannot ask for a huge allocation before it runs out
+        let mut quads = Vec::with_capacity((quad_count as usize).min(locations.len() + 1));
+        for _ in 0..quad_count {
+            let mut bounds = [0.0; 4];
+            for value in bounds.iter_mut() {
+                *value = reader.read_f64::<BigEndian>()?;
+            }
+            let [min_x, min_y, max_x, max_y] = bounds;
+            let quad = Quad {
+                min: (min_x, min_y),
+                max: (max_x, max_y),
+                children: reader.read_u32::<BigEndian>()?,
+                start: reader.read_u32::<BigEndian>()?,
+                end: reader.read_u32::<BigEndian>()?,
+            };
+
+            let children_valid = quad.is_leaf()
+                || quad
+                    .children
+                    .checked_add(4)
+                    .is_some_and(|end| end <= quad_count);
+            if !children_valid || quad.start > quad.end || quad.end > id_count {
+                return Err(MapFileError::Corrupt);
+            }
+            quads.push(quad);
+        }
+
+        let mut ids = Vec::with_capacity((id_count as usize).min(locations.len()));
+        for _ in 0..id_count {
+            let id = reader.read_u32::<BigEndian>()?;
+            if id as usize >= locations.len() {
+                return Err(MapFileError::Corrupt);
+            }
+            ids.push(id);
+        }
+
+        reader.finish(true)?;
+
+        if quads.is_empty() {
+            return Err(MapFileError::Corrupt);
+        }
+
+        Ok(Some(QuadTree {
+            quads,
+            ids,
+            locations,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{map_file::MapFileError, osm_parser::Location, quadtree::QuadTree};
+
+    fn random_tree(count: usize, seed: u64) -> (QuadTree, Vec<Location>) {
+        let rng = &mut StdRng::seed_from_u64(seed);
+        let mut locations: Vec<_> = (0..count)
+            .map(|_| Location(rng.gen_range(-94.0, -93.0), rng.gen_range(44.0, 45.0)))
+            .collect();
+        // duplicates must not split forever
+        locations.extend(vec![Location(-93.5, 44.5); 40]);
+
+        let ids = (0..locations.len() as u32).collect();
+        (QuadTree::new(locations.clone(), ids), locations)
+    }
+
+    fn brute_force(locations: &[Location], location: Location) -> Vec<(u32, f64)> {
+        let mut all: Vec<_> = locations
+            .iter()
+            .enumerate()
+            .map(|(id, other)| (id as u32, other.dist_metres(location)))
+            .collect();
+        all.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        all
+    }
+
+    #[test]
+    fn queries_match_brute_force() {
+        let (tree, locations) = random_tree(2000, 10);
+        let rng = &mut StdRng::seed_from_u64(11);
+
+        for _ in 0..50 {
+            let location = Location(rng.gen_range(-94.2, -92.8), rng.gen_range(43.8, 45.2));
+            let expected = brute_force(&locations, location);
+
+            let (_, dist) = tree.closest(location).unwrap();
+            assert_eq!(expected[0].1, dist);
+
+            let k_dists: Vec<_> = tree
+                .k_closest(location, 10)
+                .into_iter()
+                .map(|(_, dist)| dist)
+                .collect();
+            let expected_dists: Vec<_> = expected[..10].iter().map(|&(_, dist)| dist).collect();
+            assert_eq!(expected_dists, k_dists);
+
+            let mut within: Vec<_> = tree
+                .within(location, 4000.0)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            let mut expected_within: Vec<_> = expected
+                .iter()
+                .take_while(|&&(_, dist)| dist <= 4000.0)
+                .map(|&(id, _)| id)
+                .collect();
+            within.sort_unstable();
+            expected_within.sort_unstable();
+            assert_eq!(expected_within, within);
+        }
+    }
+
+    #[test]
+    fn write_read() {
+        let (tree, locations) = random_tree(500, 12);
+        let mut bytes = Vec::new();
+        tree.write(&mut bytes, 7).unwrap();
+
+        let read = QuadTree::read(&bytes[..], locations.clone(), 7)
+            .unwrap()
+            .unwrap();
+        assert_eq!(tree.indexed_count(), read.indexed_count());
+        let location = Location(-93.3, 44.2);
+        assert_eq!(tree.k_closest(location, 5), read.k_closest(location, 5));
+
+        // an index for another map is ignored
+        assert!(QuadTree::read(&bytes[..], locations[1..].to_vec(), 7)
+            .unwrap()
+            .is_none());
+        assert!(QuadTree::read(&bytes[..], locations.clone(), 8)
+            .unwrap()
+            .is_none());
+
+        // children of the root so far past the end that counting them
+        // overflows
+        let mut corrupt = bytes.clone();
+        corrupt[54..58].copy_from_slice(&(u32::MAX - 1).to_be_bytes());
+        assert!(matches!(
+            QuadTree::read(&corrupt[..], locations.clone(), 7),
+            Err(MapFileError::Corrupt)
+        ));
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(QuadTree::read(&bytes[..], locations, 7).is_err());
+    }
+}
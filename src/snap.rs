@@ -0,0 +1,350 @@
+use crate::{
+    a_star,
+    a_star::Path,
+    edge::Edge,
+    graph::{Direction, Graph},
+    osm_parser::Location,
+    params::Params,
+    quadtree::QuadTree,
+};
+
+/// A point projected onto a road segment.
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub struct Snap {
+    /// the segment goes from `from` to `to` along `edge`
+    pub from: u32,
+    pub to: u32,
+    pub edge: Edge,
+    /// the edge from `to` back to `from`, unless the road is one way
+    pub reverse: Option<Edge>,
+    /// the closest point on the segment (the foot point)
+    pub location: Location,
+    /// how far along the segment `location` is, from 0 at `from` to 1 at `to`
+    pub fraction: f64,
+    /// the distance in metres from the point that was snapped to `location`
+    pub dist: f64,
+}
+
+impl Snap {
+    fn cost<G: Graph>(
+        map: &G,
+        from: u32,
+        to: u32,
+        edge: &Edge,
+        params: &impl Params<G::Node>,
+    ) -> f64 {
+        params.neighbor_dist(map.get(from), map.get(to), edge)
+    }
+
+    /// The nodes a route starting here can get to without passing another
+    /// node, and what it costs to get there. That is where the edges lead,
+    /// which is a turn restriction copy of `to` or `from` if the road has one.
+    pub fn departures<G: Graph>(&self, map: &G, params: &impl Params<G::Node>) -> Vec<(u32, f64)> {
+        let mut departures = vec![(
+            self.edge.node,
+            (1.0 - self.fraction) * Snap::cost(map, self.from, self.to, &self.edge, params),
+        )];
+        if let Some(reverse) = &self.reverse {
+            let cost = Snap::cost(map, self.to, self.from, reverse, params);
+            departures.push((reverse.node, self.fraction * cost));
+        }
+        departures
+    }
+
+    /// The nodes a route ending here comes from, and what is left to pay from
+    /// each of them: `from`, `to` and any of their turn restriction copies
+    /// still allowed onto the road.
+    pub fn arrivals<G: Graph>(&self, map: &G, params: &impl Params<G::Node>) -> Vec<(u32, f64)> {
+        let onto = |from: u32, to: u32| {
+            map.with_copies(from)
+                .filter(move |&id| map.next_to_id(id).any(|next| next == to))
+        };
+
+        let cost = self.fraction * Snap::cost(map, self.from, self.to, &self.edge, params);
+        let mut arrivals: Vec<_> = onto(self.from, self.edge.node)
+            .map(|id| (id, cost))
+            .collect();
+        if let Some(reverse) = &self.reverse {
+            let cost = (1.0 - self.fraction) * Snap::cost(map, self.to, self.from, reverse, params);
+            arrivals.extend(onto(self.to, reverse.node).map(|id| (id, cost)));
+        }
+        arrivals
+    }
+
+    fn same_segment(&self, other: &Snap) -> bool {
+        self.from == other.from && self.to == other.to
+    }
+}
+
+/// The point on the segment `a`-`b` closest to `p`, and how far along it is.
+/// Segments are short, so they are treated as flat, with degrees of longitude
+/// shrunk to their length at `p`.
+fn project(p: Location, a: Location, b: Location) -> (Location, f64) {
+    let scale = p.y().to_radians().cos();
+    let (dx, dy) = ((b.x() - a.x()) * scale, b.y() - a.y());
+    let (px, py) = ((p.x() - a.x()) * scale, p.y() - a.y());
+
+    let len2 = dx * dx + dy * dy;
+    let fraction = if len2 == 0.0 {
+        0.0
+    } else {
+        ((px * dx + py * dy) / len2).clamp(0.0, 1.0)
+    };
+
+    let foot = Location(
+        a.x() + fraction * (b.x() - a.x()),
+        a.y() + fraction * (b.y() - a.y()),
+    );
+    (foot, fraction)
+}
+
+/// A spatial index over the road segments of a map, for snapping points onto
+/// the closest road rather than the closest node.
+///
+/// A road which can be driven both ways is one segment. Segments join the
+/// original nodes, never their turn restriction copies, which are in the
+/// same places.
+pub struct SegmentIndex<'a, G: Graph> {
+    map: &'a G,
+    segments: Vec<(u32, u32)>,
+    tree: QuadTree,
+}
+
+impl<'a, G: Graph> SegmentIndex<'a, G> {
+    #[allow(dead_code)]
+    pub fn new(map: &'a G) -> SegmentIndex<'a, G> {
+        let mut segments = Vec::new();
+        for from in 0..map.node_count() as u32 {
+            if map.original(from) != from {
+                continue;
+            }
+            for edge in map.edges(from, Direction::Forward) {
+                let to = map.original(edge.node);
+                let two_way = map.next_to_id(to).any(|next| map.original(next) == from);
+                // the other direction adds it
+                if two_way && to < from {
+                    continue;
+                }
+                segments.push((from, to));
+            }
+        }
+
+        let midpoints = segments
+            .iter()
+            .map(|&(from, to)| {
+                let (a, b) = (map.location(from), map.location(to));
+                Location((a.x() + b.x()) / 2.0, (a.y() + b.y()) / 2.0)
+            })
+            .collect();
+        let ids = (0..segments.len() as u32).collect();
+
+        let tree = QuadTree::with_extents(midpoints, ids, |id, _| {
+            let (from, to) = segments[id as usize];
+            let (a, b) = (map.location(from), map.location(to));
+            (
+                Location(a.x().min(b.x()), a.y().min(b.y())),
+                Location(a.x().max(b.x()), a.y().max(b.y())),
+            )
+        });
+
+        SegmentIndex {
+            map,
+            segments,
+            tree,
+        }
+    }
+
+    fn dist(&self, segment: u32, location: Location) -> f64 {
+        let (from, to) = self.segments[segment as usize];
+        let (foot, _) = project(location, self.map.location(from), self.map.location(to));
+        foot.dist_metres(location)
+    }
+
+    fn snap_to(&self, segment: u32, location: Location) -> Snap {
+        let map = self.map;
+        let (from, to) = self.segments[segment as usize];
+        let (foot, fraction) = project(location, map.location(from), map.location(to));
+
+        let edge = *map
+            .edges(from, Direction::Forward)
+            .find(|edge| map.original(edge.node) == to)
+            .unwrap();
+        let reverse = map
+            .edges(to, Direction::Forward)
+            .find(|edge| map.original(edge.node) == from)
+            .cloned();
+
+        Snap {
+            from,
+            to,
+            edge,
+            reverse,
+            location: foot,
+            fraction,
+            dist: foot.dist_metres(location),
+        }
+    }
+
+    /// the closest point on any road to `location`
+    #[allow(dead_code)]
+    pub fn snap(&self, location: Location) -> Option<Snap> {
+        self.tree
+            .k_closest_by(location, 1, |segment| self.dist(segment, location))
+            .first()
+            .map(|&(segment, _)| self.snap_to(segment, location))
+    }
+
+    /// every road within `radius` metres of `location`, closest first
+    #[allow(dead_code)]
+    pub fn snap_within(&self, location: Location, radius: f64) -> Vec<Snap> {
+        self.tree
+            .within_by(location, radius, |segment| self.dist(segment, location))
+            .into_iter()
+            .map(|(segment, _)| self.snap_to(segment, location))
+            .collect()
+    }
+}
+
+/// A route between two snapped points. `path` is the nodes in between, which
+/// is empty when both points are on the same segment.
+#[allow(dead_code)]
+pub struct Route<'a, G: Graph> {
+    pub start: Snap,
+    pub path: Path<'a, G>,
+    pub goal: Snap,
+    pub cost: f64,
+}
+
+impl<'a, G: Graph> Route<'a, G> {
+    /// the snapped start, each node of the path and the snapped goal
+    #[allow(dead_code)]
+    pub fn locations(&self) -> Vec<Location> {
+        let map = self.path.parent_map;
+        let mut locations = vec![self.start.location];
+        locations.extend(self.path.ids.iter().map(|&id| map.location(id)));
+        locations.push(self.goal.location);
+        locations
+    }
+}
+
+/// The cost of going straight from `start` to `goal` along the segment they
+/// are both on, without reaching a node. `None` on different segments, or if
+/// it would mean going the wrong way down a one way road.
+pub fn direct<G: Graph>(
+    map: &G,
+    start: &Snap,
+    goal: &Snap,
+    params: &impl Params<G::Node>,
+) -> Option<f64> {
+    if !start.same_segment(goal) {
+        return None;
+    }
+
+    let forward = Snap::cost(map, start.from, start.to, &start.edge, params);
+    let backward = start
+        .reverse
+        .map(|reverse| Snap::cost(map, start.to, start.from, &reverse, params));
+
+    let along = goal.fraction - start.fraction;
+    match backward {
+        _ if along >= 0.0 => Some(along * forward),
+        Some(backward) => Some(-along * backward),
+        None => None,
+    }
+}
+
+/// The cheapest route from exactly `start` to exactly `goal`.
+#[allow(dead_code)]
+pub fn route<'a, G: Graph>(
+    map: &'a G,
+    start: Snap,
+    goal: Snap,
+    params: &impl Params<G::Node>,
+) -> Option<Route<'a, G>> {
+    let found = a_star::path_between(
+        map,
+        &start.departures(map, params),
+        &goal.arrivals(map, params),
+        params,
+    );
+
+    // on the same segment the route may not need to reach a node at all
+    let direct = direct(map, &start, &goal, params);
+
+    let (path, cost) = match (found, direct) {
+        (Some((path, cost)), Some(direct)) if cost <= direct => (path, cost),
+        (_, Some(direct)) => (
+            Path {
+                ids: Vec::new(),
+                parent_map: map,
+            },
+            direct,
+        ),
+        (found, None) => found?,
+    };
+
+    Some(Route {
+        start,
+        path,
+        goal,
+        cost,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        osm_parser::{Location, OpenStreetMap},
+        params::SimpleParams,
+        snap::{route, SegmentIndex},
+    };
+
+    /// the length of a degree along the equator
+    fn degree() -> f64 {
+        Location(0.0, 0.0).dist_metres(Location(1.0, 0.0))
+    }
+
+    #[test]
+    fn snap_onto_segment() {
+        // 0 - 1 - 2 along the x axis, 2 -> 3 one way
+        let map = OpenStreetMap::from_edges(4, &[(0, 1), (1, 0), (1, 2), (2, 1), (2, 3)]);
+        let index = SegmentIndex::new(&map);
+
+        let snap = index.snap(Location(1.25, 0.1)).unwrap();
+        assert_eq!((1, 2), (snap.from, snap.to));
+        assert!((snap.fraction - 0.25).abs() < 1e-9);
+        assert!((snap.location.x() - 1.25).abs() < 1e-9);
+        assert!((snap.dist - 0.1 * degree()).abs() < 1e-6);
+        assert!(snap.reverse.is_some());
+
+        let oneway = index.snap(Location(2.5, -0.3)).unwrap();
+        assert_eq!((2, 3), (oneway.from, oneway.to));
+        assert!(oneway.reverse.is_none());
+
+        assert_eq!(
+            3,
+            index.snap_within(Location(1.5, 0.0), 0.6 * degree()).len()
+        );
+    }
+
+    #[test]
+    fn route_between_snaps() {
+        let map = OpenStreetMap::from_edges(4, &[(0, 1), (1, 0), (1, 2), (2, 1), (2, 3)]);
+        let index = SegmentIndex::new(&map);
+        let snap = |x| index.snap(Location(x, 0.0)).unwrap();
+
+        // every edge is a degree long
+        let found = route(&map, snap(0.5), snap(1.75), &SimpleParams).unwrap();
+        assert_eq!(vec![1], found.path.ids);
+        assert!((found.cost / degree() - 1.25).abs() < 1e-6);
+        assert_eq!(3, found.locations().len());
+
+        let backwards = route(&map, snap(1.75), snap(1.25), &SimpleParams).unwrap();
+        assert!(backwards.path.ids.is_empty());
+        assert!((backwards.cost / degree() - 0.5).abs() < 1e-6);
+
+        // 2 -> 3 is one way, so going back has to go round, which it cannot
+        assert!(route(&map, snap(2.75), snap(2.25), &SimpleParams).is_none());
+    }
+}
@@ -0,0 +1,465 @@
+use crate::{graph::Graph, matrix, matrix::CostMatrix, params::Params, via, via::ViaRoute};
+
+/// Up to this many stops whose place is not fixed, the order is worked out
+/// exactly. The table it takes doubles with every stop.
+const EXACT_LIMIT: usize = 12;
+
+/// Where a tour has to start and finish. Stops are given by their index in
+/// the list of stops.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Ends {
+    /// start and finish at any stop
+    Open,
+    Start(usize),
+    End(usize),
+    StartEnd(usize, usize),
+    /// start at the depot and come back to it
+    RoundTrip(usize),
+}
+
+impl Ends {
+    fn first(self) -> Option<usize> {
+        match self {
+            Ends::Start(first) | Ends::StartEnd(first, _) | Ends::RoundTrip(first) => Some(first),
+            Ends::Open | Ends::End(_) => None,
+        }
+    }
+
+    fn last(self) -> Option<usize> {
+        match self {
+            Ends::End(last) | Ends::StartEnd(_, last) => Some(last),
+            _ => None,
+        }
+    }
+}
+
+/// An order of the stops and what it costs, with the way back to the start
+/// for a round trip.
+struct Order<'m> {
+    costs: &'m CostMatrix,
+    ends: Ends,
+    stops: Vec<usize>,
+}
+
+impl<'m> Order<'m> {
+    fn link(&self, from: Option<usize>, to: Option<usize>) -> f64 {
+        match (from, to) {
+            (Some(from), Some(to)) => self.costs.get(from, to),
+            _ => 0.0,
+        }
+    }
+
+    fn before(&self, position: usize) -> Option<usize> {
+        position.checked_sub(1).map(|position| self.stops[position])
+    }
+
+    fn after(&self, position: usize) -> Option<usize> {
+        match self.stops.get(position + 1) {
+            Some(&stop) => Some(stop),
+            None if matches!(self.ends, Ends::RoundTrip(_)) => Some(self.stops[0]),
+            None => None,
+        }
+    }
+
+    fn cost(&self) -> f64 {
+        (0..self.stops.len())
+            .map(|position| self.link(Some(self.stops[position]), self.after(position)))
+            .sum()
+    }
+
+    /// the positions of the stops which may be moved
+    fn movable(&self) -> (usize, usize) {
+        let from = self.ends.first().is_some() as usize;
+        let to = self.stops.len() - self.ends.last().is_some() as usize;
+        (from, to)
+    }
+
+    /// Reverses a stretch of the tour wherever that makes it cheaper. With
+    /// one way streets the stretch itself can cost more backwards, so that is
+    /// counted too.
+    fn two_opt(&mut self) -> bool {
+        let (from, to) = self.movable();
+        let mut improved = false;
+
+        for i in from..to {
+            let (mut forward, mut backward) = (0.0, 0.0);
+            for j in i + 1..to {
+                let (a, b) = (self.stops[j - 1], self.stops[j]);
+                forward += self.costs.get(a, b);
+                backward += self.costs.get(b, a);
+
+                let (before, after) = (self.before(i), self.after(j));
+                let (first, last) = (Some(self.stops[i]), Some(self.stops[j]));
+                let old = self.link(before, first) + forward + self.link(last, after);
+                let new = self.link(before, last) + backward + self.link(first, after);
+                if new < old - 1e-9 {
+                    self.stops[i..=j].reverse();
+                    improved = true;
+                    // the sums are for the old order
+                    forward = (i + 1..=j)
+                        .map(|k| self.costs.get(self.stops[k - 1], self.stops[k]))
+                        .sum();
+                    backward = (i + 1..=j)
+                        .map(|k| self.costs.get(self.stops[k], self.stops[k - 1]))
+                        .sum();
+                }
+            }
+        }
+
+        improved
+    }
+
+    /// Moves a run of up to three stops to wherever else it is cheapest.
+    fn or_opt(&mut self) -> bool {
+        let (from, to) = self.movable();
+        let mut improved = false;
+
+        for length in 1..=3 {
+            let mut i = from;
+            while i + length <= to {
+                let (first, last) = (self.stops[i], self.stops[i + length - 1]);
+                let (before, after) = (self.before(i), self.after(i + length - 1));
+                let removed = self.link(before, Some(first)) + self.link(Some(last), after)
+                    - self.link(before, after);
+
+                let mut rest = self.stops.clone();
+                let run: Vec<_> = rest.drain(i..i + length).collect();
+                let rest_order = Order {
+                    costs: self.costs,
+                    ends: self.ends,
+                    stops: rest,
+                };
+
+                // between positions `at - 1` and `at` of what is left
+                let best = (from..=to - length)
+                    .filter(|&at| at != i)
+                    .map(|at| {
+                        let (before, after) = (
+                            rest_order.before(at),
+                            rest_order.stops.get(at).cloned().or_else(|| {
+                                // the end of the list, or back round to the depot
+                                at.checked_sub(1).and_then(|last| rest_order.after(last))
+                            }),
+                        );
+                        let added = rest_order.link(before, Some(first))
+                            + rest_order.link(Some(last), after)
+                            - rest_order.link(before, after);
+                        (at, added)
+                    })
+                    .min_by(|a, b| a.1.total_cmp(&b.1));
+
+                match best {
+                    Some((at, added)) if added < removed - 1e-9 => {
+                        let mut stops = rest_order.stops;
+                        stops.splice(at..at, run);
+                        self.stops = stops;
+                        improved = true;
+                    }
+                    _ => i += 1,
+                }
+            }
+        }
+
+        improved
+    }
+}
+
+/// Always on to the closest stop not visited yet.
+fn nearest_neighbour(costs: &CostMatrix, ends: Ends, start: usize) -> Vec<usize> {
+    let count = costs.sources.len();
+    let mut visited = vec![false; count];
+    if let Some(last) = ends.last() {
+        visited[last] = true;
+    }
+
+    let mut stops = vec![start];
+    visited[start] = true;
+    while let Some(next) = (0..count).filter(|&stop| !visited[stop]).min_by(|&a, &b| {
+        let on = *stops.last().unwrap();
+        costs.get(on, a).total_cmp(&costs.get(on, b))
+    }) {
+        visited[next] = true;
+        stops.push(next);
+    }
+
+    if let Some(last) = ends.last().filter(|&last| last != start) {
+        stops.push(last);
+    }
+    stops
+}
+
+/// The cheapest order by Held-Karp dynamic programming over the stops which
+/// are not fixed in place.
+fn exact(costs: &CostMatrix, ends: Ends) -> Vec<usize> {
+    let count = costs.sources.len();
+    let (first, last) = (ends.first(), ends.last());
+    let free: Vec<usize> = (0..count)
+        .filter(|&stop| Some(stop) != first && Some(stop) != last)
+        .collect();
+
+    let link = |from: Option<usize>, to: usize| from.map_or(0.0, |from| costs.get(from, to));
+    let close = |on: usize| {
+        last.map_or(0.0, |last| costs.get(on, last))
+            + match ends {
+                Ends::RoundTrip(depot) => costs.get(on, depot),
+                _ => 0.0,
+            }
+    };
+
+    let n = free.len();
+    let mut result: Vec<usize> = first.into_iter().collect();
+    if n > 0 {
+        // best[set][end]: the cheapest way through `set` finishing at `end`
+        let mut best = vec![vec![f64::INFINITY; n]; 1 << n];
+        let mut parent = vec![vec![usize::MAX; n]; 1 << n];
+        for end in 0..n {
+            best[1 << end][end] = link(first, free[end]);
+        }
+        for set in 1..1usize << n {
+            for end in (0..n).filter(|&end| set & (1 << end) != 0) {
+                let cost = best[set][end];
+                if !cost.is_finite() {
+                    continue;
+                }
+                for next in (0..n).filter(|&next| set & (1 << next) == 0) {
+                    let through = cost + costs.get(free[end], free[next]);
+                    let grown = set | (1 << next);
+                    if through < best[grown][next] {
+                        best[grown][next] = through;
+                        parent[grown][next] = end;
+                    }
+                }
+            }
+        }
+
+        let full = (1 << n) - 1;
+        let mut end = (0..n)
+            .min_by(|&a, &b| {
+                let cost = |end: usize| best[full][end] + close(free[end]);
+                cost(a).total_cmp(&cost(b))
+            })
+            .unwrap();
+
+        let mut set = full;
+        let mut middle = Vec::with_capacity(n);
+        while end != usize::MAX {
+            middle.push(free[end]);
+            let prev = parent[set][end];
+            set &= !(1 << end);
+            end = prev;
+        }
+        middle.reverse();
+        result.extend(middle);
+    }
+    result.extend(last.filter(|&last| Some(last) != first));
+    result
+}
+
+/// A good order to visit every stop of `costs` in, within `ends`: exactly for
+/// a handful of stops, otherwise by building a tour nearest neighbour first
+/// and improving it with 2-opt and Or-opt until neither helps.
+pub fn order(costs: &CostMatrix, ends: Ends) -> Vec<usize> {
+    let count = costs.sources.len();
+    if count == 0 {
+        return Vec::new();
+    }
+    let fixed = ends.first().is_some() as usize + ends.last().is_some() as usize;
+    if count <= EXACT_LIMIT + fixed {
+        return exact(costs, ends);
+    }
+
+    let starts: Vec<usize> = match ends.first() {
+        Some(first) => vec![first],
+        None => (0..count)
+            .filter(|&stop| Some(stop) != ends.last())
+            .collect(),
+    };
+    let mut best = starts
+        .into_iter()
+        .map(|start| Order {
+            costs,
+            ends,
+            stops: nearest_neighbour(costs, ends, start),
+        })
+        .min_by(|a, b| a.cost().total_cmp(&b.cost()))
+        .unwrap();
+
+    while best.two_opt() || best.or_opt() {}
+    best.stops
+}
+
+/// A tour of `stops` on `map`.
+#[allow(dead_code)]
+pub struct Tour<'a, G: Graph> {
+    /// the stops in the order to visit them, as indices into the stops given
+    pub order: Vec<usize>,
+    pub cost: f64,
+    /// the whole way round, with the depot again at the end of a round trip
+    pub route: ViaRoute<'a, G>,
+}
+
+/// The cheapest order to visit `stops` in found by `order`, with the costs
+/// between them from many to many searches, and the route through them.
+/// `None` if some stop cannot be reached.
+#[allow(dead_code)]
+pub fn solve<'a, G: Graph>(
+    map: &'a G,
+    stops: &[u32],
+    ends: Ends,
+    params: &impl Params<G::Node>,
+) -> Option<Tour<'a, G>> {
+    let costs = matrix::many_to_many(map, stops, stops, params);
+    let order = order(&costs, ends);
+    let cost = Order {
+        costs: &costs,
+        ends,
+        stops: order.clone(),
+    }
+    .cost();
+    if !cost.is_finite() {
+        return None;
+    }
+
+    let mut waypoints: Vec<u32> = order.iter().map(|&stop| stops[stop]).collect();
+    if let Ends::RoundTrip(depot) = ends {
+        waypoints.push(stops[depot]);
+    }
+    let route = via::route(map, &waypoints, params)?;
+
+    Some(Tour { order, cost, route })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{
+        matrix,
+        matrix::CostMatrix,
+        osm_parser::OpenStreetMap,
+        params::SimpleParams,
+        tsp::{exact, order, solve, Ends, Order},
+    };
+
+    fn random_costs(count: usize, seed: u64) -> CostMatrix {
+        let rng = &mut StdRng::seed_from_u64(seed);
+        let stops: Vec<u32> = (0..count as u32).collect();
+        let rows = (0..count)
+            .map(|from| {
+                (0..count)
+                    .map(|to| match from == to {
+                        true => 0.0,
+                        false => rng.gen_range(1.0, 100.0),
+                    })
+                    .collect()
+            })
+            .collect();
+        CostMatrix::from_rows(&stops, &stops, rows)
+    }
+
+    fn cost(costs: &CostMatrix, ends: Ends, stops: &[usize]) -> f64 {
+        Order {
+            costs,
+            ends,
+            stops: stops.to_vec(),
+        }
+        .cost()
+    }
+
+    /// the cheapest order by trying them all
+    fn brute_force(costs: &CostMatrix, ends: Ends) -> f64 {
+        fn permute(stops: &mut Vec<usize>, k: usize, each: &mut impl FnMut(&[usize])) {
+            if k == stops.len() {
+                each(stops);
+            }
+            for i in k..stops.len() {
+                stops.swap(k, i);
+                permute(stops, k + 1, each);
+                stops.swap(k, i);
+            }
+        }
+
+        let mut best = f64::INFINITY;
+        let mut stops: Vec<usize> = (0..costs.sources.len()).collect();
+        permute(&mut stops, 0, &mut |stops| {
+            let fits = ends.first().is_none_or(|first| stops[0] == first)
+                && ends
+                    .last()
+                    .is_none_or(|last| *stops.last().unwrap() == last);
+            if fits {
+                best = best.min(cost(costs, ends, stops));
+            }
+        });
+        best
+    }
+
+    fn all_ends() -> [Ends; 5] {
+        [
+            Ends::Open,
+            Ends::Start(2),
+            Ends::End(1),
+            Ends::StartEnd(3, 0),
+            Ends::RoundTrip(4),
+        ]
+    }
+
+    #[test]
+    fn exact_matches_brute_force() {
+        for seed in 0..3 {
+            let costs = random_costs(7, seed);
+            for ends in all_ends() {
+                let stops = exact(&costs, ends);
+                assert_eq!(7, stops.len());
+                assert_eq!(ends.first().unwrap_or(stops[0]), stops[0]);
+                assert_eq!(ends.last().unwrap_or(stops[6]), stops[6]);
+                assert!((cost(&costs, ends, &stops) - brute_force(&costs, ends)).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn heuristic_is_close() {
+        let costs = random_costs(30, 7);
+        for ends in all_ends() {
+            let stops = order(&costs, ends);
+            let mut sorted = stops.clone();
+            sorted.sort_unstable();
+            assert_eq!((0..30).collect::<Vec<_>>(), sorted);
+            assert_eq!(ends.first().unwrap_or(stops[0]), stops[0]);
+            assert_eq!(ends.last().unwrap_or(stops[29]), stops[29]);
+
+            // the improvements left nothing to improve
+            let mut improved = Order {
+                costs: &costs,
+                ends,
+                stops: stops.clone(),
+            };
+            assert!(!improved.two_opt() && !improved.or_opt());
+        }
+
+        // a few more than are worked out exactly, on roads
+        let map = OpenStreetMap::random_roads(120, 23).trim();
+        let stops: Vec<u32> = (0..15).map(|i| i * 7).collect();
+        let costs = matrix::many_to_many(&map, &stops, &stops, &SimpleParams);
+        for ends in all_ends() {
+            let best = cost(&costs, ends, &exact(&costs, ends));
+            let found = cost(&costs, ends, &order(&costs, ends));
+            assert!(found <= best * 1.1);
+        }
+    }
+
+    #[test]
+    fn tour_on_a_map() {
+        let map = OpenStreetMap::random_roads(80, 22).trim();
+        let stops = [0, 9, 17, 30, 44, 52];
+
+        let tour = solve(&map, &stops, Ends::RoundTrip(0), &SimpleParams).unwrap();
+        assert_eq!(0, tour.order[0]);
+        assert_eq!(7, tour.route.waypoints.len());
+        assert_eq!(0, *tour.route.path.ids.last().unwrap());
+
+        let total: f64 = tour.route.costs.iter().sum();
+        assert!((total - tour.cost).abs() < 0.01);
+    }
+}
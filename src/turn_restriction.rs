@@ -0,0 +1,516 @@
+use std::collections::{HashMap, HashSet};
+
+use osmpbf::RelMemberType;
+
+use crate::{compact_array::CompactVec, edge::Edge, osm_parser::Node, profile::Profile};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RestrictionKind {
+    /// `no_left_turn`, `no_u_turn`, ...: this turn is forbidden
+    No,
+    /// `only_straight_on`, `only_right_turn`, ...: this is the only turn
+    /// allowed
+    Only,
+}
+
+/// A `type=restriction` relation with a via node.
+///
+/// https://wiki.openstreetmap.org/wiki/Relation:restriction
+#[derive(Debug, Copy, Clone)]
+pub struct TurnRestriction {
+    pub from_way: i64,
+    /// the OSM id of the node where the turn happens
+    pub via: i64,
+    pub to_way: i64,
+    pub kind: RestrictionKind,
+}
+
+impl TurnRestriction {
+    /// Reads a restriction relation. Other relations, restrictions which do not
+    /// apply to `profile` and restrictions with a via way (not supported) give
+    /// `None`.
+    pub fn from_relation(relation: &osmpbf::Relation, profile: Profile) -> Option<TurnRestriction> {
+        let kind = restriction_kind(relation.tags(), profile)?;
+
+        let mut from_way = None;
+        let mut via = None;
+        let mut to_way = None;
+
+        for member in relation.members() {
+            match (member.role().ok()?, member.member_type) {
+                ("from", RelMemberType::Way) => from_way = Some(member.member_id),
+                ("via", RelMemberType::Node) => via = Some(member.member_id),
+                ("to", RelMemberType::Way) => to_way = Some(member.member_id),
+                ("via", _) => return None,
+                _ => {}
+            }
+        }
+
+        Some(TurnRestriction {
+            from_way: from_way?,
+            via: via?,
+            to_way: to_way?,
+            kind,
+        })
+    }
+}
+
+/// The kind of restriction a relation with these tags is for `profile`, if it
+/// is a restriction that applies at all.
+pub fn restriction_kind<'a>(
+    tags: impl Iterator<Item = (&'a str, &'a str)>,
+    profile: Profile,
+) -> Option<RestrictionKind> {
+    let (mode_key, except_modes): (_, &[_]) = match profile {
+        Profile::Car => ("restriction:motorcar", &["motorcar", "motor_vehicle"]),
+        Profile::Bike => ("restriction:bicycle", &["bicycle"]),
+        // turn restrictions are for vehicles
+        Profile::Foot => return None,
+    };
+
+    let mut is_restriction = false;
+    let mut general = None;
+    let mut specific = None;
+    let mut except = None;
+
+    for (key, value) in tags {
+        match key {
+            "type" => is_restriction = value == "restriction",
+            "restriction" => general = Some(value),
+            "except" => except = Some(value),
+            key if key == mode_key => specific = Some(value),
+            _ => {}
+        }
+    }
+
+    if !is_restriction {
+        return None;
+    }
+
+    if let Some(except) = except {
+        if except
+            .split(';')
+            .any(|mode| except_modes.contains(&mode.trim()))
+        {
+            return None;
+        }
+    }
+
+    let value = specific.or(general)?;
+    if value.starts_with("no_") {
+        Some(RestrictionKind::No)
+    } else if value.starts_with("only_") {
+        Some(RestrictionKind::Only)
+    } else {
+        None
+    }
+}
+
+/// Whether turning from `from_way` onto `to_way` is allowed, given the
+/// restrictions at the via node.
+fn turn_allowed(restrictions: &[TurnRestriction], from_way: i64, to_way: i64) -> bool {
+    let mut only = None;
+
+    for restriction in restrictions.iter().filter(|r| r.from_way == from_way) {
+        match restriction.kind {
+            RestrictionKind::No if restriction.to_way == to_way => return false,
+            RestrictionKind::No => {}
+            RestrictionKind::Only => {
+                let matched = only.unwrap_or(false) || restriction.to_way == to_way;
+                only = Some(matched);
+            }
+        }
+    }
+
+    only.unwrap_or(true)
+}
+
+/// Which nodes are copies made by `apply`, and of which node. A search for a
+/// node has to treat its copies as the same place, since a route arriving on
+/// a restricted way ends at a copy.
+///
+/// The pairs are kept in any slice, so a mapped file can use its own.
+#[derive(Debug, Default)]
+pub struct Copies<T = Vec<[u32; 2]>> {
+    /// [copy, original], sorted
+    originals: T,
+    /// [original, copy], sorted
+    copies: T,
+}
+
+impl Copies {
+    /// `pairs` of [copy, original] in any order.
+    pub fn new(mut originals: Vec<[u32; 2]>) -> Copies {
+        originals.sort_unstable();
+        let mut copies: Vec<_> = originals
+            .iter()
+            .map(|&[copy, original]| [original, copy])
+            .collect();
+        copies.sort_unstable();
+        Copies { originals, copies }
+    }
+
+    /// Every node after the first in a run of `osm_order`, the ids sorted by
+    /// OSM id and then id, is a copy of the first.
+    pub fn from_osm_order(osm_ids: &[i64], osm_order: &[u32]) -> Copies {
+        let mut pairs = Vec::new();
+        let mut original = None;
+        for pair in osm_order.windows(2) {
+            if osm_ids[pair[0] as usize] == osm_ids[pair[1] as usize] {
+                let first = *original.get_or_insert(pair[0]);
+                pairs.push([pair[1], first]);
+            } else {
+                original = None;
+            }
+        }
+        Copies::new(pairs)
+    }
+
+    /// Finds the copies from the OSM ids alone, for graphs that keep no order.
+    pub fn from_osm_ids(osm_ids: &[i64]) -> Copies {
+        let mut osm_order: Vec<u32> = (0..osm_ids.len() as u32).collect();
+        osm_order.sort_unstable_by_key(|&id| (osm_ids[id as usize], id));
+        Copies::from_osm_order(osm_ids, &osm_order)
+    }
+}
+
+impl<'a> Copies<&'a [[u32; 2]]> {
+    /// Pairs already sorted, as `originals` and `copies` return them.
+    pub fn from_sorted(originals: &'a [[u32; 2]], copies: &'a [[u32; 2]]) -> Self {
+        Copies { originals, copies }
+    }
+}
+
+impl<T: AsRef<[[u32; 2]]>> Copies<T> {
+    /// the [copy, original] pairs, sorted by copy
+    pub fn originals(&self) -> &[[u32; 2]] {
+        self.originals.as_ref()
+    }
+
+    /// the [original, copy] pairs, sorted by original
+    pub fn copies(&self) -> &[[u32; 2]] {
+        self.copies.as_ref()
+    }
+
+    pub fn is_copy(&self, id: u32) -> bool {
+        self.originals()
+            .binary_search_by_key(&id, |&[copy, _]| copy)
+            .is_ok()
+    }
+
+    /// the node `id` is a copy of, or `id` itself
+    pub fn original(&self, id: u32) -> u32 {
+        let originals = self.originals();
+        match originals.binary_search_by_key(&id, |&[copy, _]| copy) {
+            Ok(at) => originals[at][1],
+            Err(_) => id,
+        }
+    }
+
+    /// the copies of `id`
+    pub fn of(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        let copies = self.copies();
+        let start = copies.partition_point(|&[original, _]| original < id);
+        copies[start..]
+            .iter()
+            .take_while(move |&&[original, _]| original == id)
+            .map(|&[_, copy]| copy)
+    }
+}
+
+/// Builds the restrictions into the graph itself so any search obeys them.
+///
+/// For every way a restriction starts from, the via node gets a copy which
+/// only has the outgoing edges allowed when arriving on that way. Edges of
+/// that way into the via node are then pointed at the copy. The copies are
+/// appended after the existing nodes and share the location and OSM id of the
+/// via node.
+pub fn apply(
+    idx_to_node: &mut Vec<Node>,
+    idx_to_osm: &mut Vec<i64>,
+    id_to_idx: &HashMap<i64, u32>,
+    restrictions: &[TurnRestriction],
+) {
+    let mut by_via: HashMap<u32, Vec<TurnRestriction>> = HashMap::new();
+    for restriction in restrictions {
+        if let Some(&via) = id_to_idx.get(&restriction.via) {
+            by_via.entry(via).or_default().push(*restriction);
+        }
+    }
+
+    let mut redirect = HashMap::new();
+
+    for (&via, restrictions) in &by_via {
+        let from_ways: HashSet<_> = restrictions.iter().map(|r| r.from_way).collect();
+
+        for from_way in from_ways {
+            let node = &idx_to_node[via as usize];
+            let allowed: Vec<Edge> = node
+                .connected
+                .iterator()
+                .filter(|edge| turn_allowed(restrictions, from_way, edge.way_id))
+                .cloned()
+                .collect();
+
+            if allowed.len() == node.connected.len() as usize {
+                continue;
+            }
+
+            let copy = Node {
+                connected: CompactVec::from_vec(allowed),
+                incoming: CompactVec::empty(),
+                location: node.location,
+            };
+
+            redirect.insert((via, from_way), idx_to_node.len() as u32);
+            idx_to_node.push(copy);
+            idx_to_osm.push(idx_to_osm[via as usize]);
+        }
+    }
+
+    if redirect.is_empty() {
+        return;
+    }
+
+    // copies were cloned from nodes which may point at a via node too, so they
+    // are redirected as well
+    for node in idx_to_node.iter_mut() {
+        let target = |edge: &Edge| redirect.get(&(edge.node, edge.way_id));
+
+        if node.connected.iterator().all(|edge| target(edge).is_none()) {
+            continue;
+        }
+
+        let edges: Vec<_> = node
+            .connected
+            .iterator()
+            .map(|edge| match target(edge) {
+                Some(&copy) => edge.with_node(copy),
+                None => *edge,
+            })
+            .collect();
+        node.connected = CompactVec::from_vec(edges);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        a_star,
+        bidirectional::bi_astar::a_star_bi,
+        compact_array::CompactVec,
+        contraction::hierarchy::ContractionHierarchy,
+        dijkstra,
+        edge::{Edge, Highway},
+        graph::{Direction, Graph},
+        mapped::MappedMap,
+        matrix,
+        osm_parser::{Location, Node, OpenStreetMap, RawMap},
+        params::SimpleParams,
+        profile::Profile,
+        snap::{self, SegmentIndex},
+        turn_restriction::{apply, restriction_kind, RestrictionKind, TurnRestriction},
+    };
+
+    fn edge(node: u32, way_id: i64) -> Edge {
+        Edge {
+            node,
+            length: 1.0,
+            highway: Highway::Residential as u8,
+            max_speed: 0,
+            way_id,
+        }
+    }
+
+    /// `x` and `y` in millionths of a degree, so every edge is longer than the
+    /// straight line and the heuristic stays a lower bound
+    fn node(x: f64, y: f64, edges: Vec<Edge>) -> Node {
+        Node {
+            connected: CompactVec::from_vec(edges),
+            incoming: CompactVec::empty(),
+            location: Location(x * 1e-6, y * 1e-6),
+        }
+    }
+
+    /// 0 (west) - 1 - 2 (east) on ways 10 and 20, and 1 - 3 (north) on way 30
+    fn junction() -> (Vec<Node>, Vec<i64>, HashMap<i64, u32>) {
+        let nodes = vec![
+            node(-1.0, 0.0, vec![edge(1, 10)]),
+            node(0.0, 0.0, vec![edge(0, 10), edge(2, 20), edge(3, 30)]),
+            node(1.0, 0.0, vec![edge(1, 20)]),
+            node(0.0, 1.0, vec![edge(1, 30)]),
+        ];
+        let idx_to_osm = (100..104).collect();
+        let id_to_idx = (0..4).map(|i| (100 + i as i64, i)).collect();
+        (nodes, idx_to_osm, id_to_idx)
+    }
+
+    /// `junction` with no turn from 10 onto 30 at 101
+    fn restricted() -> OpenStreetMap {
+        let (mut nodes, mut osm_ids, id_to_idx) = junction();
+        let restriction = TurnRestriction {
+            from_way: 10,
+            via: 101,
+            to_way: 30,
+            kind: RestrictionKind::No,
+        };
+        apply(&mut nodes, &mut osm_ids, &id_to_idx, &[restriction]);
+        OpenStreetMap::from_raw(RawMap {
+            nodes,
+            osm_ids,
+            profile: Profile::Car,
+        })
+    }
+
+    fn targets(node: &Node) -> Vec<u32> {
+        node.connected.iterator().map(|edge| edge.node).collect()
+    }
+
+    #[test]
+    fn kind() {
+        let tags = |tags: &[(&'static str, &'static str)], profile| {
+            restriction_kind(tags.iter().cloned(), profile)
+        };
+
+        let no_left = [("type", "restriction"), ("restriction", "no_left_turn")];
+        assert_eq!(Some(RestrictionKind::No), tags(&no_left, Profile::Car));
+        assert_eq!(Some(RestrictionKind::No), tags(&no_left, Profile::Bike));
+        assert_eq!(None, tags(&no_left, Profile::Foot));
+
+        let only = [("type", "restriction"), ("restriction", "only_straight_on")];
+        assert_eq!(Some(RestrictionKind::Only), tags(&only, Profile::Car));
+
+        let except = [
+            ("type", "restriction"),
+            ("restriction", "no_right_turn"),
+            ("except", "psv;bicycle"),
+        ];
+        assert_eq!(Some(RestrictionKind::No), tags(&except, Profile::Car));
+        assert_eq!(None, tags(&except, Profile::Bike));
+
+        let trucks = [("type", "restriction"), ("restriction:hgv", "no_left_turn")];
+        assert_eq!(None, tags(&trucks, Profile::Car));
+
+        let route = [("type", "route"), ("restriction", "no_left_turn")];
+        assert_eq!(None, tags(&route, Profile::Car));
+    }
+
+    #[test]
+    fn no_turn() {
+        let (mut nodes, mut idx_to_osm, id_to_idx) = junction();
+        let restriction = TurnRestriction {
+            from_way: 10,
+            via: 101,
+            to_way: 30,
+            kind: RestrictionKind::No,
+        };
+
+        apply(&mut nodes, &mut idx_to_osm, &id_to_idx, &[restriction]);
+
+        assert_eq!(5, nodes.len());
+        assert_eq!(101, idx_to_osm[4]);
+        // arriving from the west goes through the copy, which cannot turn north
+        assert_eq!(vec![4], targets(&nodes[0]));
+        assert_eq!(vec![0, 2], targets(&nodes[4]));
+        // arriving from elsewhere is unaffected
+        assert_eq!(vec![1], targets(&nodes[2]));
+        assert_eq!(vec![0, 2, 3], targets(&nodes[1]));
+    }
+
+    #[test]
+    fn only_turn() {
+        let (mut nodes, mut idx_to_osm, id_to_idx) = junction();
+        let restriction = TurnRestriction {
+            from_way: 30,
+            via: 101,
+            to_way: 20,
+            kind: RestrictionKind::Only,
+        };
+
+        apply(&mut nodes, &mut idx_to_osm, &id_to_idx, &[restriction]);
+
+        assert_eq!(vec![4], targets(&nodes[3]));
+        assert_eq!(vec![2], targets(&nodes[4]));
+    }
+
+    #[test]
+    fn routes_end_at_copies() {
+        let map = restricted();
+        let close = |cost: f64| (cost - 1.0).abs() < 1e-6;
+        assert_eq!((1, 1), (map.original(4), map.original(1)));
+
+        // arriving from the west ends at the copy, which is still the junction
+        let path = a_star::path(&map, 0, 1).unwrap();
+        assert_eq!(vec![0, 4], path.ids);
+        assert_eq!(
+            vec![0, 4],
+            a_star_bi(&map, 0, 1, &SimpleParams).unwrap().ids
+        );
+
+        let forward = dijkstra::tree(&map, 0, Direction::Forward, &SimpleParams, None);
+        assert!(close(forward.dist_to(1).unwrap()));
+        assert_eq!(vec![0, 4], forward.path_to(1).unwrap().ids);
+        let backward = dijkstra::tree(&map, 1, Direction::Backward, &SimpleParams, None);
+        assert!(close(backward.dist_to(0).unwrap()));
+
+        assert!(close(
+            matrix::many_to_many(&map, &[0], &[1], &SimpleParams).get(0, 0)
+        ));
+
+        let hierarchy = ContractionHierarchy::new(&map, &SimpleParams);
+        let (path, cost) = hierarchy.path(&map, 0, 1).unwrap();
+        assert_eq!(vec![0, 4], path.ids);
+        assert!(close(cost));
+        assert!(close(hierarchy.matrix(&map, &[0], &[1]).get(0, 0)));
+
+        // the turn north is still forbidden, so that goes round by the east
+        let path = a_star::path(&map, 0, 3).unwrap();
+        assert_eq!(vec![0, 4, 2, 1, 3], path.ids);
+    }
+
+    #[test]
+    fn snap_beside_junction() {
+        let map = restricted();
+        let index = SegmentIndex::new(&map);
+
+        // half way along the road from the west, which is two way even though
+        // going east it ends at the copy
+        let start = index.snap(Location(-0.5e-6, 0.1e-6)).unwrap();
+        assert_eq!((0, 1), (start.from, start.to));
+        assert!(start.reverse.is_some());
+
+        // half way up the road north, which the start cannot turn into at the
+        // junction, so it turns round in the east
+        let goal = index.snap(Location(0.1e-6, 0.5e-6)).unwrap();
+        assert_eq!((1, 3), (goal.from, goal.to));
+        let route = snap::route(&map, start, goal, &SimpleParams).unwrap();
+        assert_eq!(vec![4, 2, 1], route.path.ids);
+        assert!((route.cost - 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn mapped_keeps_copies() {
+        let map = restricted();
+        let path =
+            std::env::temp_dir().join(format!("ai_osm_restricted_{}.csr", std::process::id()));
+        let name = path.to_str().unwrap();
+        map.save_mapped(name).unwrap();
+
+        let mapped = MappedMap::open(name).unwrap();
+        assert_eq!((1, 1), (mapped.original(4), mapped.original(1)));
+        assert_eq!(vec![1, 4], mapped.with_copies(1).collect::<Vec<_>>());
+        assert_eq!(vec![0, 4], a_star::path(&mapped, 0, 1).unwrap().ids);
+        // the copy is in the same place, but never the closest node
+        assert_eq!(
+            Some(1),
+            mapped
+                .closest(Location(0.1e-6, 0.0))
+                .map(|result| result.id)
+        );
+
+        drop(mapped);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{
+    a_star,
+    a_star::Path,
+    graph::Graph,
+    osm_parser::{Location, OpenStreetMap},
+    params::Params,
+};
+
+/// One path through a list of stops.
+#[allow(dead_code)]
+pub struct ViaRoute<'a, G: Graph> {
+    pub path: Path<'a, G>,
+    /// the nodes the route stops at, in order
+    pub waypoints: Vec<u32>,
+    /// where each waypoint is in `path.ids`
+    pub stops: Vec<usize>,
+    /// the length in metres of each leg, from one waypoint to the next
+    pub legs: Vec<f64>,
+    /// the cost of each leg with the params the route was found with
+    pub costs: Vec<f64>,
+}
+
+impl<'a, G: Graph> ViaRoute<'a, G> {
+    /// the nodes of leg `leg`, both its waypoints included
+    #[allow(dead_code)]
+    pub fn leg(&self, leg: usize) -> &[u32] {
+        &self.path.ids[self.stops[leg]..=self.stops[leg + 1]]
+    }
+}
+
+/// The cheapest route from the first waypoint to the last through all the
+/// others in order, or `None` if any leg has no path.
+#[allow(dead_code)]
+pub fn route<'a, G: Graph>(
+    map: &'a G,
+    waypoints: &[u32],
+    params: &impl Params<G::Node>,
+) -> Option<ViaRoute<'a, G>> {
+    let (&first, rest) = waypoints.split_first()?;
+
+    let mut ids = vec![first];
+    let mut stops = vec![0];
+    let mut legs = Vec::with_capacity(rest.len());
+    let mut costs = Vec::with_capacity(rest.len());
+
+    let mut from = first;
+    for &to in rest {
+        let (leg, cost) = a_star::path_between(map, &[(from, 0.0)], &[(to, 0.0)], params)?;
+        legs.push(leg.length_metres());
+        costs.push(cost);
+        // the first node is the end of the leg before
+        ids.extend(&leg.ids[1..]);
+        stops.push(ids.len() - 1);
+        from = to;
+    }
+
+    Some(ViaRoute {
+        path: Path {
+            ids,
+            parent_map: map,
+        },
+        waypoints: waypoints.to_vec(),
+        stops,
+        legs,
+        costs,
+    })
+}
+
+/// `route` through the nodes closest to `locations`.
+#[allow(dead_code)]
+pub fn route_through<'a>(
+    map: &'a OpenStreetMap,
+    locations: &[Location],
+    params: &impl Params<<OpenStreetMap as Graph>::Node>,
+) -> Option<ViaRoute<'a, OpenStreetMap>> {
+    let waypoints = locations
+        .iter()
+        .map(|&location| map.closest(location).map(|closest| closest.id))
+        .collect::<Option<Vec<_>>>()?;
+    route(map, &waypoints, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        a_star,
+        osm_parser::{Location, OpenStreetMap},
+        params::SimpleParams,
+        via::{route, route_through},
+    };
+
+    #[test]
+    fn legs_add_up() {
+        // every node can reach every other once trimmed
+        let map = OpenStreetMap::random_roads(60, 21).trim();
+        let last = map.node_count() as u32 - 1;
+        let waypoints = [3, last / 2, 17, 17, last];
+
+        let found = route(&map, &waypoints, &SimpleParams).unwrap();
+        assert_eq!(4, found.legs.len());
+        assert_eq!(0.0, found.legs[2]);
+
+        for (i, pair) in waypoints.windows(2).enumerate() {
+            assert_eq!(pair[0], found.path.ids[found.stops[i]]);
+            assert_eq!(pair[1], found.path.ids[found.stops[i + 1]]);
+
+            let leg = a_star::path(&map, pair[0], pair[1]).unwrap();
+            assert!((leg.length_metres() - found.legs[i]).abs() < 1e-6);
+            assert_eq!(leg.ids, found.leg(i));
+        }
+
+        let total: f64 = found.legs.iter().sum();
+        assert!((found.path.length_metres() - total).abs() < 1e-6);
+    }
+
+    #[test]
+    fn snapped_waypoints() {
+        // 0 <-> 1 <-> 2 <-> 3 along the equator, 3 -> 4 one way
+        let map =
+            OpenStreetMap::from_edges(5, &[(0, 1), (1, 0), (1, 2), (2, 1), (2, 3), (3, 2), (3, 4)]);
+
+        let found = route_through(
+            &map,
+            &[Location(0.1, 0.0), Location(3.2, 0.1), Location(1.9, 0.0)],
+            &SimpleParams,
+        )
+        .unwrap();
+        assert_eq!(vec![0, 3, 2], found.waypoints);
+        assert_eq!(vec![0, 1, 2, 3, 2], found.path.ids);
+        assert_eq!(vec![0, 3, 4], found.stops);
+
+        // there is no way back from the end of the one way street
+        assert!(route(&map, &[0, 4, 0], &SimpleParams).is_none());
+    }
+}
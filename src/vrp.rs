@@ -0,0 +1,433 @@
+use crate::{graph::Graph, matrix, matrix::CostMatrix, params::Params, via, via::ViaRoute};
+
+/// A delivery to make. Times are in seconds, counted from the same moment as
+/// the fleet's `open` and `close`.
+#[derive(Debug, Copy, Clone)]
+pub struct Stop {
+    pub node: u32,
+    /// how much of a vehicle's capacity it takes up
+    pub demand: u32,
+    /// the earliest service can start. A vehicle which is early waits.
+    pub earliest: f64,
+    /// the latest service can start
+    pub latest: f64,
+    /// how long the stop takes
+    pub service: f64,
+}
+
+/// Identical vehicles based at one depot.
+#[derive(Debug, Copy, Clone)]
+pub struct Fleet {
+    pub depot: u32,
+    pub vehicles: usize,
+    pub capacity: u32,
+    /// when the vehicles can leave the depot
+    pub open: f64,
+    /// when they have to be back by
+    pub close: f64,
+}
+
+/// The route of one vehicle.
+#[allow(dead_code)]
+pub struct VehicleRoute<'a, G: Graph> {
+    /// the stops in the order they are served, as indices into the stops given
+    pub stops: Vec<usize>,
+    /// when the vehicle gets to each stop, before any waiting
+    pub arrivals: Vec<f64>,
+    /// when it is back at the depot
+    pub back: f64,
+    pub load: u32,
+    /// from the depot through the stops and back
+    pub route: ViaRoute<'a, G>,
+}
+
+#[allow(dead_code)]
+pub struct Plan<'a, G: Graph> {
+    pub routes: Vec<VehicleRoute<'a, G>>,
+    /// the stops no vehicle could serve
+    pub unassigned: Vec<usize>,
+    /// the total driving time of all the routes
+    pub cost: f64,
+}
+
+/// The costs between the depot, number 0, and the stops, numbered from 1.
+struct Problem<'p> {
+    costs: CostMatrix,
+    stops: &'p [Stop],
+    fleet: &'p Fleet,
+}
+
+impl<'p> Problem<'p> {
+    fn stop(&self, at: usize) -> &Stop {
+        &self.stops[at - 1]
+    }
+
+    fn load(&self, route: &[usize]) -> u32 {
+        route.iter().map(|&at| self.stop(at).demand).sum()
+    }
+
+    fn travel(&self, route: &[usize]) -> f64 {
+        let mut on = 0;
+        let mut total = 0.0;
+        for &at in route.iter().chain(std::iter::once(&0)) {
+            total += self.costs.get(on, at);
+            on = at;
+        }
+        total
+    }
+
+    /// when `route` gets to each of its stops and back to the depot, or
+    /// `None` if it misses a window
+    fn schedule(&self, route: &[usize]) -> Option<(Vec<f64>, f64)> {
+        let mut arrivals = Vec::with_capacity(route.len());
+        let (mut on, mut time) = (0, self.fleet.open);
+        for &at in route {
+            let stop = self.stop(at);
+            let arrival = time + self.costs.get(on, at);
+            if arrival > stop.latest {
+                return None;
+            }
+            arrivals.push(arrival);
+            time = arrival.max(stop.earliest) + stop.service;
+            on = at;
+        }
+
+        let back = time + self.costs.get(on, 0);
+        (back <= self.fleet.close).then_some((arrivals, back))
+    }
+
+    fn feasible(&self, route: &[usize]) -> bool {
+        self.load(route) <= self.fleet.capacity && self.schedule(route).is_some()
+    }
+
+    /// Clarke and Wright's savings: every stop starts on a route of its own,
+    /// then the end of one route is joined to the start of another wherever
+    /// that saves the most driving and keeps to the capacity and windows.
+    fn savings(&self) -> (Vec<Vec<usize>>, Vec<usize>) {
+        let count = self.stops.len();
+        let (mut routes, unassigned): (Vec<Vec<usize>>, Vec<Vec<usize>>) = (1..=count)
+            .map(|at| vec![at])
+            .partition(|route| self.feasible(route));
+
+        let mut savings = Vec::new();
+        for from in 1..=count {
+            for to in (1..=count).filter(|&to| to != from) {
+                let saving =
+                    self.costs.get(from, 0) + self.costs.get(0, to) - self.costs.get(from, to);
+                if saving > 0.0 {
+                    savings.push((saving, from, to));
+                }
+            }
+        }
+        savings.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        for (_, from, to) in savings {
+            let ending = routes.iter().position(|route| route.last() == Some(&from));
+            let starting = routes.iter().position(|route| route.first() == Some(&to));
+            let (Some(ending), Some(starting)) = (ending, starting) else {
+                continue;
+            };
+            if ending == starting {
+                continue;
+            }
+
+            let mut joined = routes[ending].clone();
+            joined.extend(&routes[starting]);
+            if self.feasible(&joined) {
+                routes[ending] = joined;
+                routes.swap_remove(starting);
+            }
+        }
+
+        (routes, unassigned.concat())
+    }
+
+    /// Moves one stop to another place on its own route or another one, where
+    /// that drives less or empties a route.
+    fn relocate(&self, routes: &mut Vec<Vec<usize>>) -> bool {
+        for from in 0..routes.len() {
+            for i in 0..routes[from].len() {
+                let mut shorter = routes[from].clone();
+                let stop = shorter.remove(i);
+                let saved = self.travel(&routes[from]) - self.travel(&shorter);
+
+                for to in 0..routes.len() {
+                    let base = match to == from {
+                        true => &shorter,
+                        false => &routes[to],
+                    };
+                    for at in 0..=base.len() {
+                        if to == from && at == i {
+                            continue;
+                        }
+                        let mut longer = base.clone();
+                        longer.insert(at, stop);
+                        let added = self.travel(&longer) - self.travel(base);
+                        let empties = shorter.is_empty() && to != from;
+                        if (empties || added < saved - 1e-9) && self.feasible(&longer) {
+                            if to == from {
+                                routes[from] = longer;
+                            } else {
+                                routes[from] = shorter;
+                                routes[to] = longer;
+                                if routes[from].is_empty() {
+                                    routes.swap_remove(from);
+                                }
+                            }
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Puts `stop` wherever on `routes` adds the least driving and keeps to the
+    /// capacity and windows. False if it fits nowhere.
+    fn insert(&self, routes: &mut [Vec<usize>], stop: usize) -> bool {
+        let mut best: Option<(f64, usize, usize)> = None;
+        for (index, route) in routes.iter().enumerate() {
+            let before = self.travel(route);
+            for at in 0..=route.len() {
+                let mut longer = route.clone();
+                longer.insert(at, stop);
+                let added = self.travel(&longer) - before;
+                if best.is_none_or(|(least, _, _)| added < least) && self.feasible(&longer) {
+                    best = Some((added, index, at));
+                }
+            }
+        }
+
+        let Some((_, index, at)) = best else {
+            return false;
+        };
+        routes[index].insert(at, stop);
+        true
+    }
+
+    /// Swaps two stops on different routes where that drives less.
+    fn exchange(&self, routes: &mut [Vec<usize>]) -> bool {
+        for a in 0..routes.len() {
+            for b in a + 1..routes.len() {
+                let before = self.travel(&routes[a]) + self.travel(&routes[b]);
+                for i in 0..routes[a].len() {
+                    for j in 0..routes[b].len() {
+                        let (mut first, mut second) = (routes[a].clone(), routes[b].clone());
+                        std::mem::swap(&mut first[i], &mut second[j]);
+                        let after = self.travel(&first) + self.travel(&second);
+                        if after < before - 1e-9 && self.feasible(&first) && self.feasible(&second)
+                        {
+                            routes[a] = first;
+                            routes[b] = second;
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Routes for `fleet` which serve as many of `stops` as it can, driving as
+/// little as it can.
+///
+/// Costs come from many to many searches with `params` and are taken as
+/// seconds, so `params` should be travel times, like `TravelTimeParams`. The
+/// routes are built by savings and improved by relocating and exchanging
+/// stops until neither helps. If that leaves more routes than vehicles, the
+/// ones serving the least demand are dropped and their stops are inserted
+/// into the others wherever they fit, improving those again after each round.
+/// The stops which fit nowhere are unassigned.
+#[allow(dead_code)]
+pub fn solve<'a, G: Graph>(
+    map: &'a G,
+    stops: &[Stop],
+    fleet: &Fleet,
+    params: &impl Params<G::Node>,
+) -> Plan<'a, G> {
+    let nodes: Vec<u32> = std::iter::once(fleet.depot)
+        .chain(stops.iter().map(|stop| stop.node))
+        .collect();
+    let problem = Problem {
+        costs: matrix::many_to_many(map, &nodes, &nodes, params),
+        stops,
+        fleet,
+    };
+
+    let (mut routes, mut unassigned) = problem.savings();
+    while problem.relocate(&mut routes) || problem.exchange(&mut routes) {}
+
+    routes.sort_by_key(|route| std::cmp::Reverse(problem.load(route)));
+    let mut dropped: Vec<usize> = routes
+        .drain(fleet.vehicles.min(routes.len())..)
+        .flatten()
+        .collect();
+    // improving the routes can make room for a stop which did not fit before
+    while !dropped.is_empty() {
+        let before = dropped.len();
+        dropped.retain(|&stop| !problem.insert(&mut routes, stop));
+        if dropped.len() == before {
+            break;
+        }
+        while problem.relocate(&mut routes) || problem.exchange(&mut routes) {}
+    }
+    unassigned.extend(dropped);
+
+    let mut plan = Plan {
+        routes: Vec::with_capacity(routes.len()),
+        unassigned: unassigned.into_iter().map(|at| at - 1).collect(),
+        cost: 0.0,
+    };
+    for route in routes {
+        let waypoints: Vec<u32> = std::iter::once(fleet.depot)
+            .chain(route.iter().map(|&at| problem.stop(at).node))
+            .chain(std::iter::once(fleet.depot))
+            .collect();
+        let (arrivals, back) = problem.schedule(&route).unwrap();
+        // every stop was feasible, so the depot reaches it and it the depot
+        let path = via::route(map, &waypoints, params).unwrap();
+
+        plan.cost += problem.travel(&route);
+        plan.routes.push(VehicleRoute {
+            load: problem.load(&route),
+            stops: route.into_iter().map(|at| at - 1).collect(),
+            arrivals,
+            back,
+            route: path,
+        });
+    }
+    plan.unassigned.sort_unstable();
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{
+        matrix,
+        osm_parser::OpenStreetMap,
+        params::TravelTimeParams,
+        vrp::{solve, Fleet, Plan, Problem, Stop},
+    };
+
+    fn random_stops(map: &OpenStreetMap, count: usize, seed: u64) -> Vec<Stop> {
+        let rng = &mut StdRng::seed_from_u64(seed);
+        (0..count)
+            .map(|_| {
+                let earliest = rng.gen_range(0.0, 3600.0);
+                Stop {
+                    node: rng.gen_range(0, map.node_count() as u32),
+                    demand: rng.gen_range(1, 4),
+                    earliest,
+                    latest: earliest + rng.gen_range(3600.0, 7200.0),
+                    service: 60.0,
+                }
+            })
+            .collect()
+    }
+
+    /// every stop is served once or unassigned, within capacity and windows
+    fn check(plan: &Plan<OpenStreetMap>, stops: &[Stop], fleet: &Fleet) {
+        assert!(plan.routes.len() <= fleet.vehicles);
+
+        let mut seen = plan.unassigned.clone();
+        let mut cost = 0.0;
+        for vehicle in &plan.routes {
+            seen.extend(&vehicle.stops);
+            let load: u32 = vehicle.stops.iter().map(|&stop| stops[stop].demand).sum();
+            assert_eq!(load, vehicle.load);
+            assert!(load <= fleet.capacity);
+            assert!(vehicle.back <= fleet.close);
+
+            let ids = &vehicle.route.path.ids;
+            assert_eq!(fleet.depot, ids[0]);
+            assert_eq!(fleet.depot, *ids.last().unwrap());
+
+            let mut time = fleet.open;
+            for (leg, &stop) in vehicle.stops.iter().enumerate() {
+                let stop = &stops[stop];
+                assert_eq!(stop.node, vehicle.route.waypoints[leg + 1]);
+                let arrival = time + vehicle.route.costs[leg];
+                assert!((arrival - vehicle.arrivals[leg]).abs() < 1e-6);
+                assert!(arrival <= stop.latest);
+                time = arrival.max(stop.earliest) + stop.service;
+            }
+            cost += vehicle.route.costs.iter().sum::<f64>();
+        }
+        assert!((cost - plan.cost).abs() < 1e-6);
+
+        seen.sort_unstable();
+        assert_eq!((0..stops.len()).collect::<Vec<_>>(), seen);
+    }
+
+    #[test]
+    fn plans_are_feasible() {
+        let map = OpenStreetMap::random_roads(100, 24).trim();
+        let params = TravelTimeParams::new(&map);
+        let mut stops = random_stops(&map, 24, 25);
+        // a window which has closed before anyone can get there
+        stops[5].earliest = 0.0;
+        stops[5].latest = -1.0;
+
+        let fleet = Fleet {
+            depot: 0,
+            vehicles: 4,
+            capacity: 12,
+            open: 0.0,
+            close: 8.0 * 3600.0,
+        };
+        let plan = solve(&map, &stops, &fleet, &params);
+        check(&plan, &stops, &fleet);
+        assert_eq!(vec![5], plan.unassigned);
+
+        // one small vehicle has to leave stops behind
+        let fleet = Fleet {
+            vehicles: 1,
+            capacity: 10,
+            ..fleet
+        };
+        let plan = solve(&map, &stops, &fleet, &params);
+        check(&plan, &stops, &fleet);
+        assert!(plan.unassigned.len() > 1);
+
+        // and every stop it leaves behind would not fit anywhere on its route
+        let nodes: Vec<u32> = std::iter::once(fleet.depot)
+            .chain(stops.iter().map(|stop| stop.node))
+            .collect();
+        let problem = Problem {
+            costs: matrix::many_to_many(&map, &nodes, &nodes, &params),
+            stops: &stops,
+            fleet: &fleet,
+        };
+        let route: Vec<usize> = plan.routes[0].stops.iter().map(|&stop| stop + 1).collect();
+        for &stop in &plan.unassigned {
+            for at in 0..=route.len() {
+                let mut longer = route.clone();
+                longer.insert(at, stop + 1);
+                assert!(!problem.feasible(&longer));
+            }
+        }
+    }
+
+    /// Needs the trimmed Minnesota map which `main` reads.
+    #[test]
+    #[ignore]
+    fn minnesota() {
+        let map = OpenStreetMap::read_custom_file("map.save").unwrap();
+        let params = TravelTimeParams::new(&map);
+        let stops = random_stops(&map, 40, 26);
+        let fleet = Fleet {
+            depot: map.node_count() as u32 / 2,
+            vehicles: 10,
+            capacity: 20,
+            open: 0.0,
+            close: 24.0 * 3600.0,
+        };
+
+        let plan = solve(&map, &stops, &fleet, &params);
+        check(&plan, &stops, &fleet);
+    }
+}